@@ -0,0 +1,292 @@
+//! Command-line client for the MedBlock contract.
+//!
+//! Everything goes through the shared typed client so arguments always match
+//! what the contract expects, and deposits are estimated the same way the
+//! contract charges for storage. Calls are signed with the key near-cli saved
+//! for the account, including reads, so the contract checks who is reading.
+//!
+//!     med-block --contract med.testnet --account bob.testnet list
+//!     med-block --network local --contract med.test.near --account bob.test.near add --fhir bundle.json
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use med_block_client::MedBlockClient;
+use med_block_types::fhir;
+use med_block_types::med_record::NewRecord;
+use med_block_types::validation::validate;
+use med_block_types::AccountId;
+
+// Records fetched per view call when reading everything
+const PAGE_SIZE: u32 = 50;
+// Deploying code takes most of a transaction's gas
+const UPGRADE_GAS: u64 = 300_000_000_000_000;
+// Patients converted per call, each rewriting all of a patient's records
+const MIGRATE_BATCH: usize = 10;
+
+#[derive(Parser)]
+#[command(name = "med-block", about = "Manage patient records on the MedBlock contract")]
+struct Cli {
+    #[arg(long, value_enum, default_value_t = Network::Testnet, env = "MED_BLOCK_NETWORK")]
+    network: Network,
+    /// RPC endpoint, defaults to the network's public one or a local sandbox
+    #[arg(long, env = "MED_BLOCK_NODE_URL")]
+    node_url: Option<String>,
+    #[arg(long, env = "MED_BLOCK_CONTRACT")]
+    contract: AccountId,
+    /// Account that signs calls and reads as the viewer
+    #[arg(long, env = "MED_BLOCK_ACCOUNT")]
+    account: AccountId,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Network {
+    Testnet,
+    Mainnet,
+    /// A sandbox node such as the one workspaces starts
+    Local,
+}
+
+impl Network {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Network::Testnet => "testnet",
+            Network::Mainnet => "mainnet",
+            Network::Local => "localnet",
+        }
+    }
+
+    fn node_url(&self) -> &'static str {
+        match self {
+            Network::Testnet => "https://rpc.testnet.near.org",
+            Network::Mainnet => "https://rpc.mainnet.near.org",
+            Network::Local => "http://localhost:3030",
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Add a record, or every record in a FHIR bundle
+    Add(AddArgs),
+    /// List a patient's records, the signer's own by default
+    List {
+        #[arg(long)]
+        patient: Option<AccountId>,
+        #[arg(long, default_value_t = 0)]
+        start: u32,
+        #[arg(long, default_value_t = 10)]
+        limit: u32,
+    },
+    /// Delete records by id, all or none
+    Delete {
+        #[arg(required = true)]
+        ids: Vec<u64>,
+    },
+    /// Export a patient's records as a FHIR R4 bundle
+    Export {
+        #[arg(long)]
+        patient: Option<AccountId>,
+        /// File to write, stdout when left out
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Let an account read some or all of the signer's records
+    Grant {
+        grantee: AccountId,
+        /// Record ids to share, all of them when left out
+        #[arg(long, value_delimiter = ',')]
+        records: Option<Vec<u64>>,
+    },
+    /// Take away an account's access to the signer's records
+    Revoke { grantee: AccountId },
+    /// Deploy new contract code as the owner, or stage it for the approvers once they are set
+    Upgrade { wasm: PathBuf },
+    /// Convert every patient the first release stored to the current layout, as the
+    /// owner, once `migrate` has run
+    MigratePatients,
+}
+
+#[derive(clap::Args, Clone)]
+struct AddArgs {
+    /// FHIR R4 bundle to import instead of a single record
+    #[arg(long, conflicts_with_all = ["diagnosis", "hospital", "medicine", "admitted", "released", "allergies"])]
+    fhir: Option<PathBuf>,
+    #[arg(long, required_unless_present = "fhir")]
+    diagnosis: Option<String>,
+    #[arg(long, required_unless_present = "fhir")]
+    hospital: Option<String>,
+    #[arg(long, required_unless_present = "fhir")]
+    medicine: Option<String>,
+    /// Admission date as dd/mm/yyyy
+    #[arg(long, required_unless_present = "fhir")]
+    admitted: Option<String>,
+    /// Release date as dd/mm/yyyy
+    #[arg(long, required_unless_present = "fhir")]
+    released: Option<String>,
+    #[arg(long, default_value = "None")]
+    allergies: String,
+    #[arg(long, default_value_t = 0)]
+    price: u64,
+}
+
+impl AddArgs {
+    fn into_records(self) -> Result<Vec<NewRecord>> {
+        let Some(path) = self.fhir else {
+            return Ok(vec![NewRecord {
+                diagnosis: self.diagnosis.unwrap_or_default(),
+                hospital_name: self.hospital.unwrap_or_default(),
+                medicine_administered: self.medicine.unwrap_or_default(),
+                date_of_admission: self.admitted.unwrap_or_default(),
+                date_of_release: self.released.unwrap_or_default(),
+                allergies_recorded: self.allergies,
+                price: self.price,
+            }]);
+        };
+
+        let text = std::fs::read_to_string(&path).with_context(|| format!("could not read {}", path.display()))?;
+        let import = fhir::import(&serde_json::from_str(&text)?).map_err(anyhow::Error::msg)?;
+        for error in &import.errors {
+            eprintln!("entry {}: {}", error.entry, error.message);
+        }
+        if !import.errors.is_empty() {
+            bail!("{} entries could not be imported, nothing was submitted", import.errors.len());
+        }
+        Ok(import.records)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let node_url = cli.node_url.clone().unwrap_or_else(|| cli.network.node_url().to_string());
+    let client = MedBlockClient::new(&node_url, cli.contract.clone());
+    // Records are only read through calls, since a view can't tell who is asking
+    let signed = || client_with_key(&node_url, &cli);
+
+    match cli.command {
+        Command::Add(ref args) => {
+            let records = args.clone().into_records()?;
+            // Catch what the contract would refuse before paying for the call
+            let stored = client.get_record_count(cli.account.clone()).await?;
+            validate(&records, stored, &client.get_record_limits().await?).map_err(|error| anyhow::anyhow!(error.panic_message()))?;
+            let new_patient = stored == 0;
+            let deposit = fhir::required_deposit(&cli.account, &records, new_patient);
+            let ids = signed()?.add_records(records, deposit).await?;
+            println!("{}", serde_json::to_string(&ids)?);
+        }
+        Command::List { ref patient, start, limit } => {
+            let patient = patient.clone().unwrap_or_else(|| cli.account.clone());
+            let records = signed()?.read_shared_records(patient, start, limit).await?;
+            println!("{}", serde_json::to_string_pretty(&records)?);
+        }
+        Command::Delete { ref ids } => {
+            signed()?.delete_records(ids.clone()).await?;
+        }
+        Command::Export { ref patient, ref out } => {
+            let patient = patient.clone().unwrap_or_else(|| cli.account.clone());
+            let signer = signed()?;
+            let mut records = vec![];
+            loop {
+                let page = signer.read_shared_records(patient.clone(), records.len() as u32, PAGE_SIZE).await?;
+                let done = page.len() < PAGE_SIZE as usize;
+                records.extend(page);
+                if done {
+                    break;
+                }
+            }
+            let bundle = serde_json::to_string_pretty(&fhir::bundle(&patient, &records))?;
+            match out {
+                Some(path) => std::fs::write(path, bundle + "\n")?,
+                None => println!("{}", bundle),
+            }
+        }
+        Command::Grant { ref grantee, ref records } => {
+            let deposit = grant_deposit(&cli.account, grantee, records.as_deref());
+            signed()?.grant_access(grantee.clone(), records.clone(), deposit).await?;
+        }
+        Command::Revoke { ref grantee } => {
+            signed()?.revoke_access(grantee.clone()).await?;
+        }
+        Command::Upgrade { ref wasm } => {
+            let code = std::fs::read(wasm).with_context(|| format!("could not read {}", wasm.display()))?;
+            let signer = signed()?.with_gas(UPGRADE_GAS);
+            if let Some(config) = client.get_upgrade_config().await? {
+                // Approvers agree to the hash printed here, then one of them applies it
+                let deposit = upgrade_deposit(code.len(), &cli.account, &config.approvers);
+                let proposal = signer.propose_upgrade(code.into(), deposit).await?;
+                println!("{}", serde_json::to_string_pretty(&proposal)?);
+            } else {
+                signer.upgrade(code.into()).await?;
+            }
+        }
+        Command::MigratePatients => {
+            let signer = signed()?.with_gas(UPGRADE_GAS);
+            let patient_ids = client.patient_ids().await?;
+            let mut migrated = 0;
+            for batch in patient_ids.chunks(MIGRATE_BATCH) {
+                migrated += signer.migrate_patients(batch.to_vec()).await?;
+            }
+            println!("Converted {} of {} patients", migrated, patient_ids.len());
+        }
+    }
+    Ok(())
+}
+
+fn client_with_key(node_url: &str, cli: &Cli) -> Result<MedBlockClient> {
+    MedBlockClient::new(node_url, cli.contract.clone())
+        .with_credentials(cli.network.id(), &cli.account)
+        .with_context(|| format!("no key for {} on {}, log in with near-cli first", cli.account, cli.network.id()))
+}
+
+// Enough for a new grants entry holding this grant, anything unused is refunded
+fn grant_deposit(patient: &AccountId, grantee: &AccountId, records: Option<&[u64]>) -> u128 {
+    let entry = 40 + 1 + 4 + patient.as_str().len() as u128 + 4;
+    let grant = 4 + grantee.as_str().len() as u128 + 1 + records.map(|ids| 4 + 8 * ids.len() as u128).unwrap_or(0);
+    (entry + grant) * fhir::STORAGE_BYTE_COST
+}
+
+// Exactly what staging code costs, the contract charges no more: the code's own
+// entry, then the proposal in the contract's state with room for every approval and
+// the time it unlocks
+fn upgrade_deposit(code_len: usize, proposer: &AccountId, approvers: &[AccountId]) -> u128 {
+    let code = 40 + 1 + 4 + code_len as u128;
+    let account = |id: &AccountId| 4 + id.as_str().len() as u128;
+    let approvals = 4 + approvers.iter().map(account).sum::<u128>();
+    let proposal = 32 + account(proposer) + approvals + 1 + 8 + 16;
+    (code + proposal) * fhir::STORAGE_BYTE_COST
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_record_comes_from_flags() {
+        let cli = Cli::parse_from([
+            "med-block", "--contract", "med.testnet", "--account", "bob.testnet", "add",
+            "--diagnosis", "Malaria", "--hospital", "CGH", "--medicine", "Coartem",
+            "--admitted", "01/05/2022", "--released", "03/05/2022", "--price", "1000",
+        ]);
+        let Command::Add(args) = cli.command else { panic!("expected add") };
+        let records = args.into_records().unwrap();
+
+        assert_eq!(1, records.len());
+        assert_eq!("None", records[0].allergies_recorded);
+        assert_eq!(1000, records[0].price);
+    }
+
+    #[test]
+    fn grant_lists_records() {
+        let cli = Cli::parse_from([
+            "med-block", "--contract", "med.testnet", "--account", "bob.testnet", "grant", "doctor.testnet",
+            "--records", "1,4",
+        ]);
+        let Command::Grant { records, .. } = cli.command else { panic!("expected grant") };
+        assert_eq!(Some(vec![1, 4]), records);
+    }
+}
@@ -0,0 +1,619 @@
+//! Typed async client for the MedBlock contract.
+//!
+//! Every public contract method has a method here taking and returning the
+//! types from `med_block_types`, so callers never build JSON by hand. Views
+//! need no keys; calls are signed with the key given to the client, usually
+//! one near-cli saved under `~/.near-credentials`.
+//!
+//! ```ignore
+//! let client = MedBlockClient::new("https://rpc.testnet.near.org", "med.testnet".parse()?)
+//!     .with_credentials("testnet", &"bob.testnet".parse()?)?;
+//! let ids = client.add_records(records, deposit).await?;
+//! ```
+//!
+//! Methods that read the caller's own data, such as `read_record` or
+//! `read_claims`, look at who called them and so are sent as calls, not views.
+//! Payable methods take the deposit in yoctoNEAR as their last argument.
+//! `ft_on_transfer` and the `on_*` callbacks are left out as only token contracts
+//! and the contract itself call them.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use near_crypto::{InMemorySigner, Signer};
+use near_jsonrpc_client::{methods, JsonRpcClient};
+use near_jsonrpc_primitives::types::query::{QueryResponseKind, RpcQueryError};
+use near_primitives::transaction::{Action, FunctionCallAction, SignedTransaction, Transaction};
+use near_primitives::types::{BlockReference, Finality, FunctionArgs};
+use near_primitives::views::{FinalExecutionStatus, QueryRequest};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use med_block_types::access::{Grant, RecordSummary};
+use med_block_types::aggregate::Dimension;
+use med_block_types::appointment::Appointment;
+use med_block_types::billing::Bill;
+use med_block_types::certificate::{Certificate, CertificateApproval, CertificateVerification, NFTContractMetadata, Token, TokenId};
+use med_block_types::fungible_token::{AcceptedToken, StorageCredit};
+use med_block_types::insurance::{Claim, Insurer, Policy};
+use med_block_types::med_record::{MedRecord, NewRecord};
+use med_block_types::provider::Provider;
+use med_block_types::recovery::{RecoveryConfig, RecoveryRequest};
+use med_block_types::referral::Referral;
+use med_block_types::research::{Consent, DeidentifiedRecord, Institution, Program};
+use med_block_types::stats::RecordStatsView;
+use med_block_types::upgrade::{UpgradeConfig, UpgradeProposal};
+use med_block_types::validation::{RecordLimits, ValidationError};
+use med_block_types::{AccountId, Base58CryptoHash, Base64VecU8, U128, U64};
+
+pub use near_crypto;
+
+/// Gas attached to calls unless set with [`MedBlockClient::with_gas`], 100 TGas.
+pub const DEFAULT_GAS: u64 = 100_000_000_000_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("calls need a signer, set one with with_signer or with_credentials")]
+    NoSigner,
+    #[error("could not read credentials: {0}")]
+    Credentials(#[from] std::io::Error),
+    #[error("request failed: {0}")]
+    Rpc(String),
+    #[error("{method} failed: {message}")]
+    Failed { method: String, message: String },
+    #[error("unexpected JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// Why the contract turned a record away, when that is why the call failed.
+    pub fn validation(&self) -> Option<ValidationError> {
+        match self {
+            Error::Failed { message, .. } => ValidationError::from_panic(message),
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct MedBlockClient {
+    rpc: JsonRpcClient,
+    contract_id: near_primitives::types::AccountId,
+    signer: Option<InMemorySigner>,
+    gas: u64,
+}
+
+impl MedBlockClient {
+    pub fn new(node_url: &str, contract_id: AccountId) -> Self {
+        Self {
+            rpc: JsonRpcClient::connect(node_url),
+            contract_id: to_near_account(&contract_id),
+            signer: None,
+            gas: DEFAULT_GAS,
+        }
+    }
+
+    /// Sign calls with this key.
+    pub fn with_signer(mut self, signer: InMemorySigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Sign calls with the key near-cli saved for an account on a network.
+    pub fn with_credentials(self, network_id: &str, account_id: &AccountId) -> Result<Self> {
+        let path = credentials_path(network_id, account_id);
+        Ok(self.with_signer(InMemorySigner::from_file(&path)?))
+    }
+
+    /// Attach this much gas to calls.
+    pub fn with_gas(mut self, gas: u64) -> Self {
+        self.gas = gas;
+        self
+    }
+
+    /// The account calls are signed by, if there is a signer.
+    pub fn signer_id(&self) -> Option<AccountId> {
+        self.signer.as_ref().map(|signer| signer.account_id.as_str().parse().unwrap())
+    }
+
+    /// Call a view method and parse its JSON result.
+    pub async fn view<T: DeserializeOwned>(&self, method_name: &str, args: Value) -> Result<T> {
+        let request = methods::query::RpcQueryRequest {
+            block_reference: BlockReference::Finality(Finality::Final),
+            request: QueryRequest::CallFunction {
+                account_id: self.contract_id.clone(),
+                method_name: method_name.to_string(),
+                args: FunctionArgs::from(args.to_string().into_bytes()),
+            },
+        };
+        let response = self.rpc.call(request).await.map_err(|error| match error.handler_error() {
+            Some(RpcQueryError::ContractExecutionError { vm_error, .. }) => failed(method_name, vm_error.clone()),
+            _ => Error::Rpc(error.to_string()),
+        })?;
+
+        match response.kind {
+            QueryResponseKind::CallResult(result) => parse_result(&result.result),
+            _ => Err(Error::Rpc(format!("{} returned no result", method_name))),
+        }
+    }
+
+    /// Sign and send a call, waiting for it to finish, and parse its JSON result.
+    pub async fn call<T: DeserializeOwned>(&self, method_name: &str, args: Value, deposit: u128) -> Result<T> {
+        let signer = self.signer.as_ref().ok_or(Error::NoSigner)?;
+
+        let access_key = self.rpc.call(methods::query::RpcQueryRequest {
+            block_reference: BlockReference::Finality(Finality::Final),
+            request: QueryRequest::ViewAccessKey {
+                account_id: signer.account_id.clone(),
+                public_key: signer.public_key(),
+            },
+        }).await.map_err(|error| Error::Rpc(error.to_string()))?;
+        let QueryResponseKind::AccessKey(key) = access_key.kind else {
+            return Err(Error::Rpc(format!("no access key for {}", signer.account_id)));
+        };
+
+        let transaction = Transaction {
+            signer_id: signer.account_id.clone(),
+            public_key: signer.public_key(),
+            nonce: key.nonce + 1,
+            receiver_id: self.contract_id.clone(),
+            block_hash: access_key.block_hash,
+            actions: vec![Action::FunctionCall(FunctionCallAction {
+                method_name: method_name.to_string(),
+                args: args.to_string().into_bytes(),
+                gas: self.gas,
+                deposit,
+            })],
+        };
+        let (hash, _) = transaction.get_hash_and_size();
+        let signed_transaction = SignedTransaction::new(signer.sign(hash.as_ref()), transaction);
+
+        let outcome = self.rpc
+            .call(methods::broadcast_tx_commit::RpcBroadcastTxCommitRequest { signed_transaction })
+            .await
+            .map_err(|error| Error::Rpc(error.to_string()))?;
+        match outcome.status {
+            FinalExecutionStatus::SuccessValue(bytes) => parse_result(&bytes),
+            FinalExecutionStatus::Failure(error) => Err(failed(method_name, error.to_string())),
+            status => Err(failed(method_name, format!("{:?}", status))),
+        }
+    }
+
+    /// Deploy a fresh contract's state, called once by the contract account.
+    pub async fn init(&self, owner_id: AccountId) -> Result<()> {
+        self.call("new", args([("owner_id", serde_json::to_value(owner_id)?)]), 0).await
+    }
+
+    /// Every account with records, read straight from the contract's state for
+    /// `migrate_patients`. Public RPC nodes refuse to return state past a size limit.
+    pub async fn patient_ids(&self) -> Result<Vec<AccountId>> {
+        let request = methods::query::RpcQueryRequest {
+            block_reference: BlockReference::Finality(Finality::Final),
+            request: QueryRequest::ViewState {
+                account_id: self.contract_id.clone(),
+                prefix: PATIENTS_PREFIX.to_vec().into(),
+                include_proof: false,
+            },
+        };
+        let response = self.rpc.call(request).await.map_err(|error| Error::Rpc(error.to_string()))?;
+        match response.kind {
+            QueryResponseKind::ViewState(state) => Ok(state.values.iter().filter_map(|item| patient_id(&item.key)).collect()),
+            _ => Err(Error::Rpc(String::from("view_state returned no state"))),
+        }
+    }
+}
+
+// Patients are kept under this prefix followed by the Borsh encoded account id
+const PATIENTS_PREFIX: &[u8] = b"c";
+
+fn patient_id(key: &[u8]) -> Option<AccountId> {
+    let id = key.strip_prefix(PATIENTS_PREFIX)?;
+    let len = u32::from_le_bytes(id.get(..4)?.try_into().ok()?) as usize;
+    let bytes = id.get(4..)?;
+    if bytes.len() != len {
+        return None;
+    }
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+// Methods whose arguments and results map one to one onto the contract's.
+// `view` methods are free, `call` methods are signed and `payable` ones also
+// take the deposit to attach.
+macro_rules! methods {
+    ($($(#[doc = $doc:literal])* $kind:ident $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        impl MedBlockClient {
+            $(methods!(@method $(#[doc = $doc])* $kind $name($($arg: $ty),*) -> $ret);)*
+        }
+    };
+    (@method $(#[doc = $doc:literal])* view $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty) => {
+        $(#[doc = $doc])*
+        #[allow(clippy::too_many_arguments)]
+        pub async fn $name(&self, $($arg: $ty),*) -> Result<$ret> {
+            self.view(stringify!($name), args([$((stringify!($arg), serde_json::to_value($arg)?)),*])).await
+        }
+    };
+    (@method $(#[doc = $doc:literal])* call $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty) => {
+        $(#[doc = $doc])*
+        #[allow(clippy::too_many_arguments)]
+        pub async fn $name(&self, $($arg: $ty),*) -> Result<$ret> {
+            self.call(stringify!($name), args([$((stringify!($arg), serde_json::to_value($arg)?)),*]), 0).await
+        }
+    };
+    (@method $(#[doc = $doc:literal])* payable $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty) => {
+        $(#[doc = $doc])*
+        #[allow(clippy::too_many_arguments)]
+        pub async fn $name(&self, $($arg: $ty,)* deposit: u128) -> Result<$ret> {
+            self.call(stringify!($name), args([$((stringify!($arg), serde_json::to_value($arg)?)),*]), deposit).await
+        }
+    };
+}
+
+// Records
+methods! {
+    /// Get the contract's owner.
+    view get_owner() -> AccountId;
+    /// Add a record for the caller.
+    payable add_record(diagnosis: String, hospital_name: String, medicine_administered: String,
+        date_of_admission: String, date_of_release: String, allergies_recorded: String, price: u64) -> ();
+    /// Add several records for the caller at once, returning their ids.
+    payable add_records(records: Vec<NewRecord>) -> Vec<u64>;
+    /// Read a page of the caller's records.
+    call read_record(start: u32, limit: u32) -> Option<Vec<MedRecord>>;
+    /// Export a page of the caller's records as a FHIR R4 bundle.
+    call export_fhir(start: u32, limit: u32) -> Value;
+    /// Get the Merkle root of a patient's records.
+    view get_records_root(patient_id: AccountId) -> Option<Base58CryptoHash>;
+    /// Delete one of the caller's records.
+    call delete_record(id: u64) -> Option<MedRecord>;
+    /// Delete several of the caller's records, all or none.
+    call delete_records(ids: Vec<u64>) -> Vec<MedRecord>;
+    /// Set how much a single call may store, called by the owner.
+    call set_record_limits(limits: RecordLimits) -> ();
+    /// Get how much a single call may store.
+    view get_record_limits() -> RecordLimits;
+}
+
+// Access
+methods! {
+    /// Let an account read some or all of the caller's records.
+    payable grant_access(grantee_id: AccountId, record_ids: Option<Vec<u64>>) -> Grant;
+    /// Take away an account's access to the caller's records.
+    call revoke_access(grantee_id: AccountId) -> Option<Grant>;
+    /// Get the grants a patient has given.
+    view get_grants(patient_id: AccountId) -> Vec<Grant>;
+    /// Read a page of a patient's records as the patient or an account they granted.
+    call read_shared_records(patient_id: AccountId, start: u32, limit: u32) -> Vec<MedRecord>;
+    /// Read one of a patient's records as the patient or an account they granted.
+    call read_shared_record(patient_id: AccountId, record_id: u64) -> Option<MedRecord>;
+    /// Publish or withdraw a summary of the caller's records.
+    payable set_public_summary(enabled: bool) -> ();
+    /// View a page of a patient's public summary.
+    view view_public_summary(patient_id: AccountId, start: u32, limit: u32) -> Vec<RecordSummary>;
+    /// Export a page of a patient's public summary as a FHIR R4 bundle.
+    view view_public_fhir(patient_id: AccountId, start: u32, limit: u32) -> Value;
+    /// Whether a patient has made a summary public.
+    view has_public_summary(patient_id: AccountId) -> bool;
+}
+
+// Statistics
+methods! {
+    /// Get how many records a patient has.
+    view get_record_count(patient_id: AccountId) -> u64;
+    /// View the totals for a patient's records without the amounts billed, once they have made a summary public.
+    view view_record_stats(patient_id: AccountId) -> RecordStatsView;
+    /// Read the totals for a patient's records as the patient or an account they granted.
+    call read_record_stats(patient_id: AccountId) -> RecordStatsView;
+    /// Set the smallest group a visit count is shown for, called by the owner.
+    call set_min_group_size(min_group_size: u64) -> ();
+    /// Get the smallest group a visit count is shown for.
+    view get_min_group_size() -> u64;
+    /// Get a page of visit counts across all patients for one dimension, leaving out
+    /// groups too small to show.
+    view get_visit_counts(dimension: Dimension, start: u32, limit: u32) -> BTreeMap<String, u64>;
+    /// Get the visit count for one group.
+    view get_visit_count(dimension: Dimension, group: String) -> Option<u64>;
+}
+
+// Appointments
+methods! {
+    /// Publish an appointment slot, called by a verified provider.
+    payable publish_slot(starts_at: U64, ends_at: U64, no_show_deposit: U128) -> u64;
+    /// Withdraw an unbooked slot.
+    call withdraw_slot(appointment_id: u64) -> Appointment;
+    /// Book a slot, attaching its no-show deposit.
+    payable book_appointment(appointment_id: u64) -> Appointment;
+    /// Cancel a booking.
+    call cancel_appointment(appointment_id: u64) -> Appointment;
+    /// Mark a booked patient as not having shown up.
+    call mark_no_show(appointment_id: u64) -> Appointment;
+    /// Call off a booking as its hospital, refunding the patient.
+    call cancel_booking(appointment_id: u64) -> Appointment;
+    /// Take back the deposit for a booking the hospital never closed.
+    call reclaim_deposit(appointment_id: u64) -> Appointment;
+    /// Complete an appointment, adding its record for the patient.
+    payable complete_appointment(appointment_id: u64, diagnosis: String, medicine_administered: String,
+        date_of_admission: String, date_of_release: String, allergies_recorded: String, price: u64) -> u64;
+    /// Get an appointment.
+    view get_appointment(appointment_id: u64) -> Option<Appointment>;
+    /// Get a page of a hospital's open slots.
+    view get_open_slots(hospital_id: AccountId, start: u32, limit: u32) -> Vec<Appointment>;
+    /// Read a page of the caller's appointments.
+    call read_appointments(start: u32, limit: u32) -> Vec<Appointment>;
+}
+
+// Billing
+methods! {
+    /// Issue a bill for a patient's record, called by the hospital.
+    payable issue_bill(patient_id: AccountId, record_id: u64, amount: U128, token_id: Option<AccountId>) -> Bill;
+    /// Pay the bill issued on one of the caller's records.
+    payable pay_bill(record_id: u64) -> Bill;
+    /// Confirm a bill, called by the patient.
+    call confirm_bill(record_id: u64) -> Bill;
+    /// Refund a bill, called by the hospital.
+    call refund_bill(patient_id: AccountId, record_id: u64) -> Bill;
+    /// Dispute a bill.
+    call dispute_bill(patient_id: AccountId, record_id: u64, reason: String) -> Bill;
+    /// Settle a disputed bill, called by the owner.
+    call resolve_dispute(patient_id: AccountId, record_id: u64, refund: bool) -> Bill;
+    /// Get a bill.
+    view get_bill(patient_id: AccountId, record_id: u64) -> Option<Bill>;
+}
+
+// Certificates
+methods! {
+    /// Let a provider mint one certificate for one of the caller's records, optionally
+    /// showing verifiers the record's diagnosis.
+    payable approve_certificate(issuer_id: AccountId, record_id: u64, disclose_diagnosis: bool) -> ();
+    /// The provider a patient approved to certify a record.
+    view get_certificate_approval(patient_id: AccountId, record_id: u64) -> Option<CertificateApproval>;
+    /// Mint a certificate for one of a patient's records, called by a verified provider the
+    /// patient approved that wrote the record or was granted it.
+    payable mint_certificate(patient_id: AccountId, record_id: u64,
+        description: Option<String>, starts_at: Option<U64>, expires_at: Option<U64>) -> Token;
+    /// Revoke a certificate.
+    call revoke_certificate(token_id: TokenId) -> Certificate;
+    /// Burn one of the caller's certificates.
+    call burn_certificate(token_id: TokenId) -> ();
+    /// Check whether a certificate is valid now.
+    view verify_certificate(token_id: TokenId) -> Option<CertificateVerification>;
+    /// NEP-177 contract metadata.
+    view nft_metadata() -> NFTContractMetadata;
+    /// NEP-171 token lookup.
+    view nft_token(token_id: TokenId) -> Option<Token>;
+    /// NEP-181 tokens owned by an account.
+    view nft_tokens_for_owner(account_id: AccountId, from_index: Option<U64>, limit: Option<u64>) -> Vec<Token>;
+    /// NEP-181 number of tokens owned by an account.
+    view nft_supply_for_owner(account_id: AccountId) -> U64;
+    /// NEP-181 number of tokens.
+    view nft_total_supply() -> U64;
+    /// NEP-171 transfer, always refused as certificates are non-transferable.
+    payable nft_transfer(receiver_id: AccountId, token_id: TokenId, approval_id: Option<u64>, memo: Option<String>) -> ();
+    /// NEP-171 transfer and call, always refused as certificates are non-transferable.
+    payable nft_transfer_call(receiver_id: AccountId, token_id: TokenId, approval_id: Option<u64>,
+        memo: Option<String>, msg: String) -> bool;
+}
+
+// Fungible tokens
+methods! {
+    /// Accept a token for storage, called by the owner.
+    payable whitelist_token(token_id: AccountId, storage_byte_price: U128) -> ();
+    /// Stop accepting a token, called by the owner.
+    call remove_token(token_id: AccountId) -> Option<AcceptedToken>;
+    /// Get an accepted token.
+    view get_token(token_id: AccountId) -> Option<AcceptedToken>;
+    /// Get an account's storage credit.
+    view get_storage_credit(account_id: AccountId) -> StorageCredit;
+    /// Withdraw the caller's unused storage credit in the token it was bought with.
+    call withdraw_storage_credit() -> U128;
+}
+
+// Insurance
+methods! {
+    /// Register an insurer, called by the owner.
+    payable register_insurer(insurer_id: AccountId, name: String) -> ();
+    /// Get an insurer.
+    view get_insurer(insurer_id: AccountId) -> Option<Insurer>;
+    /// Link the caller's policy with an insurer.
+    payable link_policy(insurer_id: AccountId, policy_number: String) -> ();
+    /// Unlink the caller's policy.
+    call unlink_policy() -> Option<Policy>;
+    /// Get a patient's policy.
+    view get_policy(patient_id: AccountId) -> Option<Policy>;
+    /// Submit a claim for one of a patient's records, called by the verified provider on it.
+    payable submit_claim(patient_id: AccountId, record_id: u64) -> u64;
+    /// Approve a claim in full, called by the insurer.
+    call approve_claim(claim_id: u64) -> Claim;
+    /// Approve part of a claim, called by the insurer.
+    call partially_approve_claim(claim_id: u64, approved_amount: f64, reason: String) -> Claim;
+    /// Reject a claim, called by the insurer.
+    call reject_claim(claim_id: u64, reason: String) -> Claim;
+    /// Get a claim the caller is party to.
+    call get_claim(claim_id: u64) -> Option<Claim>;
+    /// Read a page of the claims the caller is party to.
+    call read_claims(start: u32, limit: u32) -> Vec<Claim>;
+}
+
+// Providers
+methods! {
+    /// Verify a provider, called by the owner.
+    payable verify_provider(provider_id: AccountId, name: String) -> ();
+    /// Withdraw a provider's verification, called by the owner.
+    call unverify_provider(provider_id: AccountId) -> Provider;
+    /// Get a provider.
+    view get_provider(provider_id: AccountId) -> Option<Provider>;
+    /// Whether a provider is verified.
+    view is_verified_provider(provider_id: AccountId) -> bool;
+}
+
+// Recovery and transfers
+methods! {
+    /// Set the guardians who can recover the caller's records.
+    payable set_recovery(guardians: Vec<AccountId>, threshold: u32, delay: U64) -> ();
+    /// Remove the caller's recovery setup.
+    call remove_recovery() -> Option<RecoveryConfig>;
+    /// Approve moving a patient's records to a new account, called by a guardian. Approving
+    /// another account moves the guardian's approval to it.
+    payable approve_recovery(patient_id: AccountId, new_account_id: AccountId) -> RecoveryRequest;
+    /// Veto every recovery of the caller's records.
+    call veto_recovery() -> Vec<RecoveryRequest>;
+    /// Finish a recovery once approved and past its delay.
+    payable finalize_recovery(patient_id: AccountId, new_account_id: AccountId) -> ();
+    /// Get a patient's recovery setup.
+    view get_recovery_config(patient_id: AccountId) -> Option<RecoveryConfig>;
+    /// Get a patient's pending recoveries, one per candidate account.
+    view get_recovery_requests(patient_id: AccountId) -> Vec<RecoveryRequest>;
+    /// Start moving the caller's records to another account.
+    payable initiate_transfer(new_account_id: AccountId) -> ();
+    /// Cancel the caller's pending transfer.
+    call cancel_transfer() -> Option<AccountId>;
+    /// Get where a patient's records are being moved to.
+    view get_pending_transfer(patient_id: AccountId) -> Option<AccountId>;
+    /// Accept records being moved to the caller.
+    payable accept_transfer(old_account_id: AccountId) -> ();
+}
+
+// Referrals
+methods! {
+    /// Refer a patient to another provider, called by a verified provider with access to the records.
+    payable create_referral(patient_id: AccountId, to_provider_id: AccountId, reason: String,
+        record_ids: Vec<u64>) -> u64;
+    /// Accept a referral, called by the patient.
+    payable accept_referral(referral_id: u64) -> Referral;
+    /// Decline a referral, called by the patient.
+    call decline_referral(referral_id: u64) -> Referral;
+    /// Get a referral the caller is party to.
+    call get_referral(referral_id: u64) -> Option<Referral>;
+    /// Read a page of the referrals the caller is party to.
+    call read_referrals(start: u32, limit: u32) -> Vec<Referral>;
+}
+
+// Research
+methods! {
+    /// Approve an institution to run research programs, called by the owner.
+    payable approve_institution(institution_id: AccountId, name: String) -> ();
+    /// Withdraw an institution's approval, called by the owner.
+    call unapprove_institution(institution_id: AccountId) -> Institution;
+    /// Get an institution.
+    view get_institution(institution_id: AccountId) -> Option<Institution>;
+    /// Register a research program, called by an approved institution.
+    payable register_program(name: String, purpose: String) -> u64;
+    /// Stop a program taking new participants.
+    call close_program(program_id: u64) -> Program;
+    /// Set the minimum an insurer pays to fund a program and how long that buys access for.
+    call set_funding_terms(program_id: u64, min_amount: U128, access_period: U64) -> Program;
+    /// Get a research program.
+    view get_program(program_id: u64) -> Option<Program>;
+    /// Consent to share the caller's records with a program.
+    payable opt_in(program_id: u64, purpose: String, expires_at: U64) -> Consent;
+    /// Withdraw the caller's consent to a program.
+    call opt_out(program_id: u64) -> Option<Consent>;
+    /// Get a patient's consent to a program, if still in force.
+    view get_consent(program_id: u64, patient_id: AccountId) -> Option<Consent>;
+    /// Export a page of de-identified records from a program's consenting patients. The
+    /// result is part of the public transaction outcome.
+    call export_research_data(program_id: u64, start: u32, limit: u32) -> Vec<DeidentifiedRecord>;
+    /// Fund a program, splitting the amount between its consenting patients. An insurer
+    /// paying at least the program's minimum can export its data for the access period.
+    payable fund_program(program_id: u64, amount: U128) -> ();
+    /// Get how much compensation an account can claim.
+    view get_claimable(account_id: AccountId) -> U128;
+    /// Claim the caller's compensation.
+    call claim_compensation() -> U128;
+}
+
+// Upgrades. Deploying code burns most of a transaction's gas, so raise it with
+// `with_gas` before calling `upgrade` or `apply_upgrade`.
+methods! {
+    /// Deploy new code and migrate the state, called by the owner until approvers are set.
+    call upgrade(code: Base64VecU8) -> ();
+    /// Hand upgrades over to an M-of-N approver set with a time lock, called by the owner.
+    call set_upgrade_approvers(approvers: Vec<AccountId>, threshold: u32, delay: U64) -> ();
+    /// Stage new code for the approvers, paying to store it until it is deployed or cancelled.
+    payable propose_upgrade(code: Base64VecU8) -> UpgradeProposal;
+    /// Agree to the staged code with the given hash, called by an approver.
+    call approve_upgrade(code_hash: Base58CryptoHash) -> UpgradeProposal;
+    /// Drop the staged code and refund its proposer, called by an approver.
+    call cancel_upgrade() -> Option<UpgradeProposal>;
+    /// Deploy the staged code once its time lock has passed, refunding its proposer once migrated, called by an approver.
+    call apply_upgrade() -> ();
+    /// Get the accounts that approve upgrades.
+    view get_upgrade_config() -> Option<UpgradeConfig>;
+    /// Get the upgrade in progress.
+    view get_upgrade_proposal() -> Option<UpgradeProposal>;
+    /// Convert patients stored as the first release laid them out, called by the owner
+    /// after `migrate` with ids from `patient_ids`. Returns how many were converted.
+    call migrate_patients(patient_ids: Vec<AccountId>) -> u32;
+}
+
+// Where near-cli keeps an account's key for a network
+fn credentials_path(network_id: &str, account_id: &AccountId) -> PathBuf {
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join(".near-credentials").join(network_id).join(format!("{}.json", account_id))
+}
+
+fn to_near_account(account_id: &AccountId) -> near_primitives::types::AccountId {
+    // Both types check account ids the same way, so this can't fail
+    account_id.as_str().parse().unwrap()
+}
+
+fn args<const N: usize>(fields: [(&str, Value); N]) -> Value {
+    Value::Object(fields.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+}
+
+// Methods returning nothing give back no bytes rather than `null`
+fn parse_result<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    Ok(serde_json::from_slice(if bytes.is_empty() { b"null" } else { bytes })?)
+}
+
+fn failed(method_name: &str, message: String) -> Error {
+    Error::Failed { method: method_name.to_string(), message }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn arguments_are_named_after_the_contract() {
+        let records: Vec<u64> = vec![1, 4];
+        let value = args([("grantee_id", json!("doctor.testnet")), ("record_ids", serde_json::to_value(&records).unwrap())]);
+        assert_eq!(json!({ "grantee_id": "doctor.testnet", "record_ids": [1, 4] }), value);
+        assert_eq!(json!({}), args([]));
+    }
+
+    #[test]
+    fn empty_results_parse_as_unit_or_none() {
+        parse_result::<()>(b"").unwrap();
+        assert_eq!(None, parse_result::<Option<u64>>(b"").unwrap());
+        assert_eq!(vec![3u64], parse_result::<Vec<u64>>(b"[3]").unwrap());
+    }
+
+    #[tokio::test]
+    async fn calls_need_a_signer() {
+        let client = MedBlockClient::new("http://localhost:1", "med.test.near".parse().unwrap());
+        let error = client.delete_records(vec![0]).await.unwrap_err();
+        assert!(matches!(error, Error::NoSigner));
+        assert_eq!(None, client.signer_id());
+    }
+
+    #[test]
+    fn patient_ids_are_read_from_state_keys() {
+        let mut key = b"c".to_vec();
+        key.extend_from_slice(&8u32.to_le_bytes());
+        key.extend_from_slice(b"bob.near");
+        assert_eq!(Some("bob.near".parse().unwrap()), patient_id(&key));
+        assert_eq!(None, patient_id(&key[..key.len() - 1]));
+        assert_eq!(None, patient_id(b"STATE"));
+    }
+
+    #[test]
+    fn validation_errors_are_read_from_failures() {
+        let rejected = ValidationError::TooManyRecords { max: 50, given: 60 };
+        let error = failed("add_records", format!("Smart contract panicked: {}", rejected.panic_message()));
+        assert_eq!(Some(rejected), error.validation());
+        assert_eq!(None, failed("delete_record", String::from("Patient not found!")).validation());
+    }
+}
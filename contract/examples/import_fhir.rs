@@ -0,0 +1,53 @@
+//! Turn a FHIR R4 Bundle into the arguments for `add_records`.
+//!
+//!     cargo run --example import_fhir -- bundle.json bob.testnet [--existing-patient]
+//!
+//! Prints the call arguments and the deposit to attach, in yoctoNEAR, on stdout and
+//! any entries that couldn't be imported on stderr. Exits non-zero when there
+//! were any, so nothing is submitted from a partial import by accident.
+
+use std::process::exit;
+
+use med_block::fhir;
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::{self, json, Value};
+use near_sdk::AccountId;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("usage: import_fhir <bundle.json> <patient-account-id> [--existing-patient]");
+        exit(2);
+    }
+    let patient_id: AccountId = args[1].parse().unwrap_or_else(|_| {
+        eprintln!("invalid account id {}", args[1]);
+        exit(2);
+    });
+    let new_patient = !args.iter().any(|arg| arg == "--existing-patient");
+
+    let bundle: Value = std::fs::read_to_string(&args[0])
+        .map_err(|err| err.to_string())
+        .and_then(|text| serde_json::from_str(&text).map_err(|err| err.to_string()))
+        .unwrap_or_else(|err| {
+            eprintln!("could not read {}: {}", args[0], err);
+            exit(2);
+        });
+
+    let import = fhir::import(&bundle).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        exit(2);
+    });
+    for error in &import.errors {
+        eprintln!("entry {}: {}", error.entry, error.message);
+    }
+
+    let deposit = fhir::required_deposit(&patient_id, &import.records, new_patient);
+    println!("{}", serde_json::to_string_pretty(&json!({
+        "args": { "records": import.records },
+        "deposit": U128(deposit),
+    })).unwrap());
+
+    if !import.errors.is_empty() {
+        exit(1);
+    }
+}
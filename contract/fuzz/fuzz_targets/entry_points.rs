@@ -0,0 +1,318 @@
+//! Drives the record, sharing and statistics entry points with arbitrary JSON
+//! arguments, callers and deposits, the way the wasm glue would: state is read
+//! before each call and written after it, and a call that panics leaves storage
+//! as it was. Crashes when a call panics with anything but one of the contract's
+//! own validation messages, or when the contract stops holding enough to cover
+//! its state or pays an account back more than it put in.
+//!
+//!     cargo +nightly fuzz run entry_points
+
+#![no_main]
+
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use med_block::med_record::NewRecord;
+use med_block::PatientRecord;
+use med_block_types::aggregate::Dimension;
+use near_sdk::mock::{with_mocked_blockchain, VmAction};
+use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+use near_sdk::{env, AccountId, Balance, MockedBlockchain, RuntimeFeesConfig, VMConfig};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+// The first account owns the contract
+const ACCOUNTS: [&str; 3] = ["alice.near", "bob.near", "carol.near"];
+
+// Argument names the methods below take, so generated objects often deserialize
+const FIELDS: [&str; 19] = [
+    "diagnosis", "hospital_name", "medicine_administered", "date_of_admission", "date_of_release",
+    "allergies_recorded", "price", "records", "start", "limit", "id", "ids", "grantee_id", "record_ids",
+    "enabled", "patient_id", "record_id", "min_group_size", "dimension",
+];
+
+// Panics the contract raises on purpose when a call isn't allowed
+const EXPECTED: [&str; 12] = [
+    "Insufficient funds!",
+    "Invalid record! ",
+    "not found!",
+    "Invalid medical record!",
+    "Duplicate medical record!",
+    "No records given!",
+    "No access to this",
+    "Cannot grant access to yourself!",
+    "Bill is still held in escrow!",
+    "Patient has not published a summary!",
+    "Only the owner can call this method",
+    "Groups of one would identify patients!",
+];
+
+#[derive(Arbitrary, Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    Negative(i64),
+    String(String),
+    Account(u8),
+    Array(Vec<Json>),
+    Object(Vec<(Key, Json)>),
+}
+
+#[derive(Arbitrary, Debug)]
+enum Key {
+    Field(u8),
+    Other(String),
+}
+
+impl From<Json> for Value {
+    fn from(json: Json) -> Self {
+        match json {
+            Json::Null => Value::Null,
+            Json::Bool(value) => json!(value),
+            Json::Number(value) => json!(value),
+            Json::Negative(value) => json!(value),
+            Json::String(value) => json!(value),
+            Json::Account(index) => json!(ACCOUNTS[index as usize % ACCOUNTS.len()]),
+            Json::Array(values) => Value::Array(values.into_iter().map(Value::from).collect()),
+            Json::Object(fields) => Value::Object(fields
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Key::Field(index) => FIELDS[index as usize % FIELDS.len()].to_string(),
+                        Key::Other(key) => key,
+                    };
+                    (key, Value::from(value))
+                })
+                .collect::<Map<_, _>>()),
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+enum Method {
+    AddRecord,
+    AddRecords,
+    ReadRecord,
+    ExportFhir,
+    DeleteRecord,
+    DeleteRecords,
+    GrantAccess,
+    RevokeAccess,
+    SetPublicSummary,
+    ReadSharedRecords,
+    ReadSharedRecord,
+    ViewPublicSummary,
+    ViewPublicFhir,
+    GetRecordCount,
+    GetRecordsRoot,
+    ViewRecordStats,
+    ReadRecordStats,
+    SetMinGroupSize,
+    GetVisitCounts,
+}
+
+impl Method {
+    // The glue refuses deposits on any other method before it runs
+    fn payable(&self) -> bool {
+        matches!(self, Method::AddRecord | Method::AddRecords | Method::GrantAccess | Method::SetPublicSummary)
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Deposit {
+    None,
+    Bytes(u16),
+    Near(u8),
+}
+
+impl Deposit {
+    fn amount(&self) -> Balance {
+        match self {
+            Deposit::None => 0,
+            Deposit::Bytes(bytes) => *bytes as Balance * env::STORAGE_PRICE_PER_BYTE,
+            Deposit::Near(near) => *near as Balance * 10u128.pow(24),
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+struct Call {
+    caller: u8,
+    deposit: Deposit,
+    method: Method,
+    args: Json,
+}
+
+#[derive(Deserialize)]
+struct Records { records: Vec<NewRecord> }
+#[derive(Deserialize)]
+struct Page { start: u32, limit: u32 }
+#[derive(Deserialize)]
+struct Id { id: u64 }
+#[derive(Deserialize)]
+struct Ids { ids: Vec<u64> }
+#[derive(Deserialize)]
+struct Grantee { grantee_id: AccountId, record_ids: Option<Vec<u64>> }
+#[derive(Deserialize)]
+struct Enabled { enabled: bool }
+#[derive(Deserialize)]
+struct Reader { patient_id: AccountId, start: Option<u32>, limit: Option<u32>, record_id: Option<u64> }
+#[derive(Deserialize)]
+struct Patient { patient_id: AccountId, start: Option<u32>, limit: Option<u32> }
+#[derive(Deserialize)]
+struct GroupSize { min_group_size: u64 }
+#[derive(Deserialize)]
+struct Counts { dimension: Dimension, start: Option<u32>, limit: Option<u32> }
+
+// Parse the arguments and make the call, or give back the error the glue would panic with
+fn dispatch(contract: &mut PatientRecord, method: Method, args: Value) -> serde_json::Result<()> {
+    use serde_json::from_value;
+    match method {
+        Method::AddRecord => {
+            let r: NewRecord = from_value(args)?;
+            contract.add_record(r.diagnosis, r.hospital_name, r.medicine_administered, r.date_of_admission,
+                r.date_of_release, r.allergies_recorded, r.price);
+        }
+        Method::AddRecords => { contract.add_records(from_value::<Records>(args)?.records); }
+        Method::ReadRecord => {
+            let page: Page = from_value(args)?;
+            contract.read_record(page.start, page.limit);
+        }
+        Method::ExportFhir => {
+            let page: Page = from_value(args)?;
+            contract.export_fhir(page.start, page.limit);
+        }
+        Method::DeleteRecord => { contract.delete_record(from_value::<Id>(args)?.id); }
+        Method::DeleteRecords => { contract.delete_records(from_value::<Ids>(args)?.ids); }
+        Method::GrantAccess => {
+            let grant: Grantee = from_value(args)?;
+            contract.grant_access(grant.grantee_id, grant.record_ids);
+        }
+        Method::RevokeAccess => { contract.revoke_access(from_value::<Grantee>(args)?.grantee_id); }
+        Method::SetPublicSummary => contract.set_public_summary(from_value::<Enabled>(args)?.enabled),
+        Method::ReadSharedRecords => {
+            let read: Reader = from_value(args)?;
+            contract.read_shared_records(read.patient_id, read.start.unwrap_or(0), read.limit.unwrap_or(10));
+        }
+        Method::ReadSharedRecord => {
+            let read: Reader = from_value(args)?;
+            contract.read_shared_record(read.patient_id, read.record_id.unwrap_or(0));
+        }
+        Method::ViewPublicSummary => {
+            let view: Patient = from_value(args)?;
+            contract.view_public_summary(view.patient_id, view.start.unwrap_or(0), view.limit.unwrap_or(10));
+        }
+        Method::ViewPublicFhir => {
+            let view: Patient = from_value(args)?;
+            contract.view_public_fhir(view.patient_id, view.start.unwrap_or(0), view.limit.unwrap_or(10));
+        }
+        Method::GetRecordCount => { contract.get_record_count(from_value::<Patient>(args)?.patient_id); }
+        Method::GetRecordsRoot => { contract.get_records_root(from_value::<Patient>(args)?.patient_id); }
+        Method::ViewRecordStats => { contract.view_record_stats(from_value::<Patient>(args)?.patient_id); }
+        Method::ReadRecordStats => { contract.read_record_stats(from_value::<Patient>(args)?.patient_id); }
+        Method::SetMinGroupSize => contract.set_min_group_size(from_value::<GroupSize>(args)?.min_group_size),
+        Method::GetVisitCounts => {
+            let counts: Counts = from_value(args)?;
+            contract.get_visit_counts(counts.dimension, counts.start.unwrap_or(0), counts.limit.unwrap_or(10));
+        }
+    }
+    Ok(())
+}
+
+// The contract's balance and storage between calls, and what each account has
+// paid in and been paid back
+struct Chain {
+    balance: Balance,
+    storage: u64,
+    deposited: HashMap<AccountId, Balance>,
+    refunded: HashMap<AccountId, Balance>,
+}
+
+impl Chain {
+    // A fresh mocked blockchain for one call over the given state
+    fn enter(&self, caller: &AccountId, deposit: Balance, state: HashMap<Vec<u8>, Vec<u8>>) {
+        let context = VMContextBuilder::new()
+            .predecessor_account_id(caller.clone())
+            .attached_deposit(deposit)
+            .account_balance(self.balance + deposit)
+            .storage_usage(self.storage)
+            .build();
+        env::set_blockchain_interface(MockedBlockchain::new(context, VMConfig::test(), RuntimeFeesConfig::test(),
+            vec![], state, Default::default(), None));
+    }
+
+    fn run(&mut self, call: Call) {
+        let caller: AccountId = ACCOUNTS[call.caller as usize % ACCOUNTS.len()].parse().unwrap();
+        let deposit = call.deposit.amount();
+        if deposit > 0 && !call.method.payable() {
+            return;
+        }
+
+        let state = with_mocked_blockchain(|b| b.take_storage());
+        self.enter(&caller, deposit, state.clone());
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut contract: PatientRecord = env::state_read().expect("Contract is not initialized");
+            dispatch(&mut contract, call.method, Value::from(call.args))?;
+            env::state_write(&contract);
+            Ok::<_, serde_json::Error>(())
+        }));
+
+        match outcome {
+            Ok(Ok(())) => {}
+            // The call never ran or was rolled back, so put the state back as it was
+            Ok(Err(_)) => return self.enter(&caller, 0, state),
+            Err(panic) => {
+                let message = panic.downcast_ref::<String>().cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|message| message.to_string()))
+                    .unwrap_or_default();
+                assert!(EXPECTED.iter().any(|expected| message.contains(expected)),
+                    "{:?} panicked unexpectedly: {}", call.method, message);
+                return self.enter(&caller, 0, state);
+            }
+        }
+
+        let mut paid_out = 0;
+        for receipt in get_created_receipts() {
+            for action in receipt.actions {
+                if let VmAction::Transfer { deposit } = action {
+                    paid_out += deposit;
+                    *self.refunded.entry(receipt.receiver_id.clone()).or_default() += deposit;
+                }
+            }
+        }
+        *self.deposited.entry(caller).or_default() += deposit;
+        self.balance = self.balance + deposit - paid_out;
+        self.storage = env::storage_usage();
+
+        assert!(self.balance >= self.storage as Balance * env::storage_byte_cost(),
+            "{:?} left {} bytes of state with a balance of {}", call.method, self.storage, self.balance);
+        for (account, refunded) in &self.refunded {
+            let deposited = self.deposited.get(account).copied().unwrap_or(0);
+            assert!(*refunded <= deposited, "{} got back {} of {} deposited", account, refunded, deposited);
+        }
+    }
+}
+
+fuzz_target!(|calls: Vec<Call>| {
+    let mut chain = Chain { balance: 0, storage: 0, deposited: HashMap::new(), refunded: HashMap::new() };
+    chain.enter(&ACCOUNTS[0].parse().unwrap(), 0, HashMap::new());
+    env::state_write(&PatientRecord::new(ACCOUNTS[0].parse().unwrap()));
+    chain.storage = env::storage_usage();
+    chain.balance = chain.storage as Balance * env::storage_byte_cost();
+
+    // libfuzzer aborts on any panic, so quiet the hook while calls are allowed to fail
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        for call in calls {
+            chain.run(call);
+        }
+    }));
+    panic::set_hook(hook);
+    if let Err(panic) = result {
+        panic::resume_unwind(panic);
+    }
+});
@@ -2,8 +2,20 @@
 
 source ./scripts/setting.conf
 
-near delete $SUB_ACCOUNT $MASTER_ACCOUNT 
+# Deleting the account would destroy every patient's records, so once the
+# contract is live deploy new code with the CLI's upgrade command instead
+if near state $SUB_ACCOUNT > /dev/null 2>&1; then
+  # The first release has no owner to upgrade it, so redeploy over it with the
+  # contract's own key and move its state to the current layout
+  if ! near view $SUB_ACCOUNT get_owner '{}' > /dev/null 2>&1; then
+    near deploy $SUB_ACCOUNT --wasmFile=./res/med_block.wasm --initFunction migrate --initArgs '{"owner_id": "'$MASTER_ACCOUNT'"}'
+    echo "Now convert its patients with: med-block --contract $SUB_ACCOUNT --account $MASTER_ACCOUNT migrate-patients"
+    exit 0
+  fi
+  echo "$SUB_ACCOUNT already exists, upgrade it with: med-block --contract $SUB_ACCOUNT --account $MASTER_ACCOUNT upgrade ./res/med_block.wasm"
+  exit 1
+fi
 
 near create-account $SUB_ACCOUNT --masterAccount $MASTER_ACCOUNT --initialBalance 20
 
-near deploy $SUB_ACCOUNT --wasmFile=./res/med_block.wasm
\ No newline at end of file
+near deploy $SUB_ACCOUNT --wasmFile=./res/med_block.wasm --initFunction new --initArgs '{"owner_id": "'$MASTER_ACCOUNT'"}'
@@ -0,0 +1,262 @@
+use near_sdk::serde_json::Value;
+use near_sdk::{near_bindgen, env, AccountId};
+
+use crate::{fhir, PatientRecord, PatientRecordExt};
+use crate::med_record::MedRecord;
+pub use med_block_types::access::{Grant, RecordSummary};
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Let an account read some or all of the caller's records
+
+    #[payable]
+    pub fn grant_access(&mut self, grantee_id: AccountId, record_ids: Option<Vec<u64>>) -> Grant {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let grant = self.add_grant(&signer, grantee_id, record_ids);
+
+        self.pay_for_storage(initial_storage, deposit);
+        grant
+    }
+
+    // Take away an account's access to the caller's records
+
+    pub fn revoke_access(&mut self, grantee_id: AccountId) -> Option<Grant> {
+        let signer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+
+        let mut grants = self.grants.get(&signer).unwrap_or_default();
+        let index = grants.iter().position(|grant| grant.grantee_id == grantee_id)?;
+        let removed_grant = grants.remove(index);
+        if grants.is_empty() {
+            self.grants.remove(&signer);
+        } else {
+            self.grants.insert(&signer, &grants);
+        }
+
+        self.refund_storage_cost(initial_storage);
+        Some(removed_grant)
+    }
+
+    // Get the grants a patient has given
+
+    pub fn get_grants(&self, patient_id: AccountId) -> Vec<Grant> {
+        self.grants.get(&patient_id).unwrap_or_default()
+    }
+
+    // Retrieve a paginated list of a patient's records as the patient or an account they granted
+
+    pub fn read_shared_records(&self, patient_id: AccountId, start: u32, limit: u32) -> Vec<MedRecord> {
+        let grant = self.caller_grant(&patient_id);
+
+        self.patients
+            .get(&patient_id)
+            .map(|patient| patient.show(0, u32::MAX))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|record| grant.as_ref().map(|grant| grant.covers(record.id)).unwrap_or(true))
+            .skip(start as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    // Retrieve a single record of a patient's as the patient or an account they granted
+
+    pub fn read_shared_record(&self, patient_id: AccountId, record_id: u64) -> Option<MedRecord> {
+        let grant = self.caller_grant(&patient_id);
+        if let Some(grant) = grant {
+            assert!(grant.covers(record_id), "No access to this record!");
+        }
+        self.patients.get(&patient_id)?.get(record_id).cloned()
+    }
+
+    // Publish or withdraw a summary of the caller's records that anyone can view
+
+    #[payable]
+    pub fn set_public_summary(&mut self, enabled: bool) {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        if enabled {
+            self.public_summaries.insert(&signer, &true);
+        } else {
+            self.public_summaries.remove(&signer);
+        }
+
+        self.settle_storage(initial_storage, deposit);
+    }
+
+    // View a paginated summary of a patient's records, if they have made it public
+
+    pub fn view_public_summary(&self, patient_id: AccountId, start: u32, limit: u32) -> Vec<RecordSummary> {
+        assert!(self.public_summaries.get(&patient_id).unwrap_or(false), "Patient has not published a summary!");
+
+        self.patients
+            .get(&patient_id)
+            .map(|patient| patient.show(start, limit))
+            .unwrap_or_default()
+            .into_iter()
+            .map(RecordSummary::from)
+            .collect()
+    }
+
+    // Export the public summary of a patient's records as a FHIR R4 Bundle. Unlike
+    // `export_fhir` this doesn't depend on the caller, so it works as a view.
+
+    pub fn view_public_fhir(&self, patient_id: AccountId, start: u32, limit: u32) -> Value {
+        let summaries = self.view_public_summary(patient_id.clone(), start, limit);
+        fhir::summary_bundle(&patient_id, &summaries)
+    }
+
+    // Whether a patient has made a summary of their records public
+
+    pub fn has_public_summary(&self, patient_id: AccountId) -> bool {
+        self.public_summaries.get(&patient_id).unwrap_or(false)
+    }
+
+    // The grant the caller reads a patient's records under, None when the caller is the
+    // patient. Views can't tell who is asking, so only calls go through here.
+
+    pub(crate) fn caller_grant(&self, patient_id: &AccountId) -> Option<Grant> {
+        let signer = env::predecessor_account_id();
+        if signer == *patient_id {
+            return None;
+        }
+        Some(self.grant_for(patient_id, &signer).expect("No access to this patient's records!"))
+    }
+
+    // Find the grant a patient has given an account
+
+    pub(crate) fn grant_for(&self, patient_id: &AccountId, grantee_id: &AccountId) -> Option<Grant> {
+        self.grants
+            .get(patient_id)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|grant| grant.grantee_id == *grantee_id)
+    }
+
+    // Create or widen the grant a patient has given an account
+
+    pub(crate) fn add_grant(&mut self, patient_id: &AccountId, grantee_id: AccountId, record_ids: Option<Vec<u64>>) -> Grant {
+        assert_ne!(*patient_id, grantee_id, "Cannot grant access to yourself!");
+
+        let mut grants = self.grants.get(patient_id).unwrap_or_default();
+        let grant = match grants.iter_mut().find(|grant| grant.grantee_id == grantee_id) {
+            Some(grant) => {
+                grant.extend(record_ids);
+                grant.clone()
+            }
+            None => {
+                let grant = Grant { grantee_id, record_ids };
+                grants.push(grant.clone());
+                grant
+            }
+        };
+        self.grants.insert(patient_id, &grants);
+        grant
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(predecessor: &str) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .build()
+    }
+
+    // Bob has three records
+    fn setup() -> PatientRecord {
+        testing_env!(get_context("bob.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        for diagnosis in ["Malaria", "Flu", "Typhoid"] {
+            contract.add_record(String::from(diagnosis), String::from("CGH"), String::from("Panadol"),
+                String::from("01/05/2022"), String::from("03/05/2022"), String::from("None"), 1000);
+        }
+        contract
+    }
+
+    #[test]
+    fn grant_scopes_and_widens() {
+        let mut contract = setup();
+        contract.grant_access("doctor.near".parse().unwrap(), Some(vec![0]));
+        let grant = contract.grant_access("doctor.near".parse().unwrap(), Some(vec![2, 0]));
+        assert_eq!(Some(vec![0, 2]), grant.record_ids);
+
+        testing_env!(get_context("doctor.near"));
+        let records = contract.read_shared_records("bob.near".parse().unwrap(), 0, 10);
+        let diagnoses: Vec<&str> = records.iter().map(|record| record.diagnosis.as_str()).collect();
+        assert_eq!(vec!["Malaria", "Typhoid"], diagnoses);
+    }
+
+    #[test]
+    fn patient_and_grantee_read_as_themselves() {
+        let mut contract = setup();
+        contract.grant_access("doctor.near".parse().unwrap(), Some(vec![1]));
+        let bob: AccountId = "bob.near".parse().unwrap();
+        assert_eq!(3, contract.read_shared_records(bob.clone(), 0, 10).len());
+
+        testing_env!(get_context("doctor.near"));
+        let shared = contract.read_shared_records(bob.clone(), 0, 10);
+        assert_eq!(vec![1], shared.iter().map(|record| record.id).collect::<Vec<_>>());
+        assert_eq!("Flu", contract.read_shared_record(bob, 1).unwrap().diagnosis);
+    }
+
+    #[test]
+    #[should_panic(expected = "No access to this record!")]
+    fn read_outside_grant_is_refused() {
+        let mut contract = setup();
+        contract.grant_access("doctor.near".parse().unwrap(), Some(vec![1]));
+
+        testing_env!(get_context("doctor.near"));
+        contract.read_shared_record("bob.near".parse().unwrap(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "No access to this patient's records!")]
+    fn stranger_cannot_read() {
+        let contract = setup();
+
+        testing_env!(get_context("mallory.near"));
+        contract.read_shared_record("bob.near".parse().unwrap(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Patient has not published a summary!")]
+    fn public_summary_is_opt_in() {
+        let mut contract = setup();
+        contract.set_public_summary(true);
+
+        testing_env!(VMContextBuilder::new().is_view(true).build());
+        let summary = contract.view_public_summary("bob.near".parse().unwrap(), 0, 10);
+        assert_eq!(3, summary.len());
+        assert_eq!("CGH", summary[0].hospital_name);
+        let bundle = contract.view_public_fhir("bob.near".parse().unwrap(), 0, 10);
+        assert_eq!(4, bundle["entry"].as_array().unwrap().len());
+
+        testing_env!(get_context("bob.near"));
+        contract.set_public_summary(false);
+        contract.view_public_summary("bob.near".parse().unwrap(), 0, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "No access to this patient's records!")]
+    fn revoked_grant_cannot_read() {
+        let mut contract = setup();
+        contract.grant_access("doctor.near".parse().unwrap(), None);
+        assert!(contract.revoke_access("doctor.near".parse().unwrap()).is_some());
+
+        testing_env!(get_context("doctor.near"));
+        contract.read_shared_records("bob.near".parse().unwrap(), 0, 10);
+    }
+}
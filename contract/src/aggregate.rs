@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::collections::UnorderedSet;
+use near_sdk::near_bindgen;
+
+use crate::{PatientRecord, PatientRecordExt};
+use crate::med_record::MedRecord;
+use crate::patient::Patient;
+pub use med_block_types::aggregate::{Dimension, DIMENSIONS};
+
+// Smallest number of patients a group is shown for unless the owner sets otherwise
+pub const DEFAULT_MIN_GROUP_SIZE: u64 = 5;
+
+// How many visits fall in a group and how many different patients made them
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct GroupCount {
+    pub visits: u64,
+    pub patients: u64,
+}
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Set the smallest number of patients a group is shown for, called by the owner
+
+    pub fn set_min_group_size(&mut self, min_group_size: u64) {
+        self.assert_owner();
+        assert!(min_group_size > 1, "Groups of one would identify patients!");
+        self.min_group_size = min_group_size;
+    }
+
+    // Get the smallest number of patients a group is shown for
+
+    pub fn get_min_group_size(&self) -> u64 {
+        self.min_group_size
+    }
+
+    // Get a page of visit counts across all patients for one dimension, in the order
+    // the groups were first seen. Groups with visits from fewer patients than the
+    // minimum group size are left out entirely, so a page can come back short.
+    // The threshold is advisory: it only filters what these methods return. The
+    // counts, like the records they are taken from, sit in contract state that
+    // anyone can read with `view_state`, so it keeps casual readers from singling
+    // out a few patients but does not stop someone reading the state directly.
+
+    pub fn get_visit_counts(&self, dimension: Dimension, start: u32, limit: u32) -> BTreeMap<String, u64> {
+        let Some(groups) = self.visit_groups.get(&dimension) else { return BTreeMap::new() };
+        groups
+            .iter()
+            .skip(start as usize)
+            .take(limit as usize)
+            .filter_map(|group| {
+                let count = self.visit_counts.get(&(dimension, group.clone()))?;
+                (count.patients >= self.min_group_size).then_some((group, count.visits))
+            })
+            .collect()
+    }
+
+    // Get the visit count for one group, None when too few patients are in it to show.
+    // Advisory in the same way as `get_visit_counts`.
+
+    pub fn get_visit_count(&self, dimension: Dimension, group: String) -> Option<u64> {
+        self.visit_counts
+            .get(&(dimension, group))
+            .filter(|count| count.patients >= self.min_group_size)
+            .map(|count| count.visits)
+    }
+
+    // Count the records added to and removed from a patient in or out of the contract
+    // wide totals, given the patient as it is after the change. A patient counts once
+    // towards a group however many of their visits fall in it. Each group has its own
+    // entry, so a call only touches the groups of the records it changes.
+
+    pub(crate) fn count_visits(&mut self, patient: &Patient, added: &[u64], removed: &[MedRecord]) {
+        for dimension in DIMENSIONS {
+            // Visits added and removed per group
+            let mut changes: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+            for record in added.iter().filter_map(|id| patient.get(*id)) {
+                let Some(group) = dimension.group(record) else { continue };
+                changes.entry(group).or_default().0 += 1;
+            }
+            for record in removed {
+                let Some(group) = dimension.group(record) else { continue };
+                changes.entry(group).or_default().1 += 1;
+            }
+            if changes.is_empty() {
+                continue;
+            }
+
+            // The patient's visits in each changed group after the change
+            let mut visits: BTreeMap<&String, u64> = BTreeMap::new();
+            for record in patient.records() {
+                let Some(group) = dimension.group(record) else { continue };
+                if let Some((group, _)) = changes.get_key_value(&group) {
+                    *visits.entry(group).or_default() += 1;
+                }
+            }
+
+            for (group, (plus, minus)) in &changes {
+                let after = visits.get(group).copied().unwrap_or(0);
+                let before = (after + minus).saturating_sub(*plus);
+                let key = (dimension, group.clone());
+                let mut count = self.visit_counts.get(&key).unwrap_or_default();
+                let was_counted = count.visits > 0;
+                count.visits = (count.visits + plus).saturating_sub(*minus);
+                if before == 0 && after > 0 {
+                    count.patients += 1;
+                } else if before > 0 && after == 0 {
+                    count.patients = count.patients.saturating_sub(1);
+                }
+
+                if count.visits > 0 {
+                    self.visit_counts.insert(&key, &count);
+                    if !was_counted {
+                        self.update_visit_groups(dimension, group, true);
+                    }
+                } else {
+                    self.visit_counts.remove(&key);
+                    if was_counted {
+                        self.update_visit_groups(dimension, group, false);
+                    }
+                }
+            }
+        }
+    }
+
+    // Add a group to or drop it from the index of groups seen for a dimension, which
+    // only happens when it is first counted or counted out to nothing
+
+    fn update_visit_groups(&mut self, dimension: Dimension, group: &String, counted: bool) {
+        let mut groups = self.visit_groups
+            .get(&dimension)
+            .unwrap_or_else(|| UnorderedSet::new(vec![b'G', dimension as u8]));
+        if counted {
+            groups.insert(group);
+        } else {
+            groups.remove(group);
+        }
+        self.visit_groups.insert(&dimension, &groups);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(predecessor: &str) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .build()
+    }
+
+    // Five patients treated for malaria at CGH in May and one for typhoid at KNH in June
+    fn setup() -> PatientRecord {
+        testing_env!(get_context("alice.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        for patient in ["a.near", "b.near", "c.near", "d.near", "e.near"] {
+            testing_env!(get_context(patient));
+            contract.add_record(String::from("Malaria "), String::from("CGH"), String::from("Coartem"),
+                String::from("01/05/2022"), String::from("03/05/2022"), String::from("None"), 1000);
+        }
+        testing_env!(get_context("f.near"));
+        contract.add_record(String::from("Typhoid"), String::from("KNH"), String::from("Cipro"),
+            String::from("2022-06-10"), String::from("2022-06-12"), String::from("None"), 1000);
+        contract
+    }
+
+    #[test]
+    fn small_groups_are_withheld() {
+        let contract = setup();
+
+        let diagnoses = contract.get_visit_counts(Dimension::Diagnosis, 0, 10);
+        assert_eq!(vec![(String::from("malaria"), 5)], diagnoses.into_iter().collect::<Vec<_>>());
+        assert_eq!(Some(5), contract.get_visit_count(Dimension::Month, String::from("2022-05")));
+        assert_eq!(None, contract.get_visit_count(Dimension::Hospital, String::from("KNH")));
+    }
+
+    #[test]
+    fn groups_are_sized_by_patients_not_visits() {
+        let mut contract = setup();
+
+        testing_env!(get_context("f.near"));
+        for _ in 0..5 {
+            contract.add_record(String::from("Typhoid"), String::from("KNH"), String::from("Cipro"),
+                String::from("2022-06-10"), String::from("2022-06-12"), String::from("None"), 1000);
+        }
+        assert_eq!(None, contract.get_visit_count(Dimension::Diagnosis, String::from("typhoid")));
+
+        testing_env!(get_context("alice.near"));
+        contract.set_min_group_size(2);
+        testing_env!(get_context("a.near"));
+        contract.add_record(String::from("Typhoid"), String::from("KNH"), String::from("Cipro"),
+            String::from("2022-06-10"), String::from("2022-06-12"), String::from("None"), 1000);
+        assert_eq!(Some(7), contract.get_visit_count(Dimension::Diagnosis, String::from("typhoid")));
+
+        // Deleting one of several visits leaves the patient counted
+        testing_env!(get_context("f.near"));
+        contract.delete_records(vec![0, 1]);
+        assert_eq!(Some(5), contract.get_visit_count(Dimension::Diagnosis, String::from("typhoid")));
+        assert_eq!(2, contract.visit_counts.get(&(Dimension::Diagnosis, String::from("typhoid"))).unwrap().patients);
+    }
+
+    #[test]
+    fn deleted_records_are_counted_out() {
+        let mut contract = setup();
+
+        testing_env!(get_context("a.near"));
+        contract.delete_record(0);
+        assert!(contract.get_visit_counts(Dimension::Hospital, 0, 10).is_empty());
+
+        testing_env!(get_context("alice.near"));
+        contract.set_min_group_size(4);
+        assert_eq!(Some(4), contract.get_visit_count(Dimension::Hospital, String::from("CGH")));
+    }
+
+    #[test]
+    fn groups_counted_out_leave_the_index() {
+        let mut contract = setup();
+
+        testing_env!(get_context("alice.near"));
+        contract.set_min_group_size(2);
+        testing_env!(get_context("a.near"));
+        contract.add_record(String::from("Typhoid"), String::from("KNH"), String::from("Cipro"),
+            String::from("2022-06-10"), String::from("2022-06-12"), String::from("None"), 1000);
+        assert_eq!(Some(2), contract.get_visit_count(Dimension::Hospital, String::from("KNH")));
+
+        testing_env!(get_context("f.near"));
+        contract.delete_record(0);
+        testing_env!(get_context("a.near"));
+        contract.delete_record(1);
+        assert_eq!(1, contract.visit_groups.get(&Dimension::Hospital).unwrap().len());
+        let page = contract.get_visit_counts(Dimension::Hospital, 1, 10);
+        assert!(page.is_empty());
+        assert_eq!(Some(5), contract.get_visit_counts(Dimension::Hospital, 0, 1).get("CGH").copied());
+    }
+}
@@ -0,0 +1,427 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{near_bindgen, env, AccountId, Promise};
+
+use crate::{now_ms, PatientRecord, PatientRecordExt};
+use crate::patient::Patient;
+use crate::med_record::NewRecord;
+pub use med_block_types::appointment::{Appointment, AppointmentStatus, DEPOSIT_GRACE_PERIOD};
+
+// An account other than the patient that paid for a record's storage, and how many
+// bytes it paid for, so deleting the record refunds them and not the patient
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct RecordPayer {
+    pub account_id: AccountId,
+    pub bytes: u64,
+}
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Open a slot patients can book, called by a verified provider
+
+    #[payable]
+    pub fn publish_slot(&mut self, starts_at: U64, ends_at: U64, no_show_deposit: U128) -> u64 {
+        let hospital_id = self.assert_verified_provider();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        assert!(starts_at.0 < ends_at.0, "Slot must end after it starts!");
+        assert!(starts_at.0 > now_ms(), "Slot must be in the future!");
+
+        let id = self.next_appointment_id;
+        self.appointments.insert(&id, &Appointment {
+            id,
+            hospital_id: hospital_id.clone(),
+            starts_at,
+            ends_at,
+            no_show_deposit,
+            patient_id: None,
+            status: AppointmentStatus::Open,
+            record_id: None,
+        });
+        self.next_appointment_id += 1;
+        self.index_appointment(&hospital_id, id);
+
+        self.pay_for_storage(initial_storage, deposit);
+        id
+    }
+
+    // Withdraw a slot nobody has booked, or whose booking was cancelled, called by its hospital
+
+    pub fn withdraw_slot(&mut self, appointment_id: u64) -> Appointment {
+        let initial_storage = env::storage_usage();
+        let appointment = self.appointment_for_hospital(appointment_id);
+        assert!(
+            matches!(appointment.status, AppointmentStatus::Open | AppointmentStatus::Cancelled),
+            "Slot has already been booked!"
+        );
+
+        self.appointments.remove(&appointment_id);
+        self.unindex_appointment(&appointment.hospital_id, appointment_id);
+
+        self.refund_storage_cost(initial_storage);
+        appointment
+    }
+
+    // Book an open slot. The attached deposit must cover the no-show deposit,
+    // anything above it pays for storage and the rest is returned.
+
+    #[payable]
+    pub fn book_appointment(&mut self, appointment_id: u64) -> Appointment {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let mut appointment = self.appointments.get(&appointment_id).expect("Appointment not found!");
+        assert_eq!(appointment.status, AppointmentStatus::Open, "Slot is not available!");
+        assert!(appointment.starts_at.0 > now_ms(), "Slot has already started!");
+        assert!(deposit >= appointment.no_show_deposit.0, "Insufficient funds!");
+
+        appointment.patient_id = Some(signer.clone());
+        appointment.status = AppointmentStatus::Booked;
+        self.appointments.insert(&appointment_id, &appointment);
+        self.index_appointment(&signer, appointment_id);
+
+        self.pay_for_storage(initial_storage, deposit - appointment.no_show_deposit.0);
+        appointment
+    }
+
+    // Cancel a booking before it starts, reopening the slot and returning the deposit
+
+    pub fn cancel_appointment(&mut self, appointment_id: u64) -> Appointment {
+        let signer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+
+        let mut appointment = self.appointments.get(&appointment_id).expect("Appointment not found!");
+        assert_eq!(appointment.patient_id.as_ref(), Some(&signer), "Not your appointment!");
+        assert_eq!(appointment.status, AppointmentStatus::Booked, "Appointment is not booked!");
+        assert!(appointment.starts_at.0 > now_ms(), "Appointment has already started!");
+
+        appointment.patient_id = None;
+        appointment.status = AppointmentStatus::Open;
+        self.appointments.insert(&appointment_id, &appointment);
+        self.unindex_appointment(&signer, appointment_id);
+
+        if appointment.no_show_deposit.0 > 0 {
+            Promise::new(signer).transfer(appointment.no_show_deposit.0);
+        }
+        self.refund_storage_cost(initial_storage);
+        appointment
+    }
+
+    // Call off a booking, called by the hospital. The patient gets their deposit back along
+    // with the storage the booking took, and the slot stays closed.
+
+    pub fn cancel_booking(&mut self, appointment_id: u64) -> Appointment {
+        let initial_storage = env::storage_usage();
+
+        let mut appointment = self.appointment_for_hospital(appointment_id);
+        assert_eq!(appointment.status, AppointmentStatus::Booked, "Appointment is not booked!");
+        let patient_id = appointment.patient_id.take().expect("Appointment is not booked!");
+
+        appointment.status = AppointmentStatus::Cancelled;
+        self.appointments.insert(&appointment_id, &appointment);
+        self.unindex_appointment(&patient_id, appointment_id);
+
+        if appointment.no_show_deposit.0 > 0 {
+            Promise::new(patient_id.clone()).transfer(appointment.no_show_deposit.0);
+        }
+        self.refund_storage_cost_to(&patient_id, initial_storage);
+        appointment
+    }
+
+    // Take back the deposit for a booking the hospital never closed, called by the
+    // patient once the grace period after the slot has passed
+
+    pub fn reclaim_deposit(&mut self, appointment_id: u64) -> Appointment {
+        let signer = env::predecessor_account_id();
+
+        let mut appointment = self.appointments.get(&appointment_id).expect("Appointment not found!");
+        assert_eq!(appointment.patient_id.as_ref(), Some(&signer), "Not your appointment!");
+        assert_eq!(appointment.status, AppointmentStatus::Booked, "Appointment is not booked!");
+        assert!(now_ms() >= appointment.ends_at.0 + DEPOSIT_GRACE_PERIOD, "The hospital can still close this appointment!");
+
+        appointment.status = AppointmentStatus::Lapsed;
+        self.appointments.insert(&appointment_id, &appointment);
+
+        if appointment.no_show_deposit.0 > 0 {
+            Promise::new(signer).transfer(appointment.no_show_deposit.0);
+        }
+        appointment
+    }
+
+    // Record that the patient did not turn up, keeping their deposit, called by the hospital
+
+    pub fn mark_no_show(&mut self, appointment_id: u64) -> Appointment {
+        let mut appointment = self.appointment_for_hospital(appointment_id);
+        assert_eq!(appointment.status, AppointmentStatus::Booked, "Appointment is not booked!");
+        assert!(appointment.starts_at.0 <= now_ms(), "Appointment has not started yet!");
+
+        appointment.status = AppointmentStatus::NoShow;
+        self.appointments.insert(&appointment_id, &appointment);
+
+        if appointment.no_show_deposit.0 > 0 {
+            Promise::new(appointment.hospital_id.clone()).transfer(appointment.no_show_deposit.0);
+        }
+        appointment
+    }
+
+    // Close a booked appointment by writing its record into the patient's records.
+    // The hospital pays the record's storage, and gets it back if the patient deletes
+    // the record, while the patient gets their deposit back.
+
+    #[payable]
+    #[allow(clippy::too_many_arguments)]
+    pub fn complete_appointment(&mut self, appointment_id: u64, diagnosis: String, medicine_administered: String,
+        date_of_admission: String, date_of_release: String,
+        allergies_recorded: String, price: u64) -> u64 {
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let mut appointment = self.appointment_for_hospital(appointment_id);
+        assert_eq!(appointment.status, AppointmentStatus::Booked, "Appointment is not booked!");
+        assert!(appointment.starts_at.0 <= now_ms(), "Appointment has not started yet!");
+        let patient_id = appointment.patient_id.clone().expect("Appointment is not booked!");
+        let hospital_name = self.providers.get(&appointment.hospital_id).map(|provider| provider.name).unwrap_or_default();
+        let record = NewRecord { diagnosis, hospital_name, medicine_administered, date_of_admission, date_of_release, allergies_recorded, price };
+        self.assert_valid_records(&patient_id, std::slice::from_ref(&record));
+        let NewRecord { diagnosis, hospital_name, medicine_administered, date_of_admission, date_of_release, allergies_recorded, price } = record;
+
+        let record_storage = env::storage_usage();
+        let mut patient = self.patients.get(&patient_id).unwrap_or_else(Patient::new_patient);
+        let record_id = patient.add(
+            diagnosis,
+            hospital_name,
+            medicine_administered,
+            date_of_admission,
+            date_of_release,
+            allergies_recorded,
+            price as f64,
+            Some(appointment.hospital_id.clone())
+        );
+        self.save_patient(&patient_id, &patient, &[record_id], &[]);
+        // Write the entry once so the bytes it records include the entry itself
+        let record_payer = RecordPayer { account_id: appointment.hospital_id.clone(), bytes: 0 };
+        self.record_payers.insert(&(patient_id.clone(), record_id), &record_payer);
+        let bytes = env::storage_usage().saturating_sub(record_storage);
+        self.record_payers.insert(&(patient_id.clone(), record_id), &RecordPayer { bytes, ..record_payer });
+
+        appointment.status = AppointmentStatus::Completed;
+        appointment.record_id = Some(record_id);
+        self.appointments.insert(&appointment_id, &appointment);
+
+        if appointment.no_show_deposit.0 > 0 {
+            Promise::new(patient_id).transfer(appointment.no_show_deposit.0);
+        }
+        self.pay_for_storage(initial_storage, deposit);
+        record_id
+    }
+
+    // Get an appointment
+
+    pub fn get_appointment(&self, appointment_id: u64) -> Option<Appointment> {
+        self.appointments.get(&appointment_id)
+    }
+
+    // Get a hospital's open slots
+
+    pub fn get_open_slots(&self, hospital_id: AccountId, start: u32, limit: u32) -> Vec<Appointment> {
+        self.appointments_by_account
+            .get(&hospital_id)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|id| self.appointments.get(id))
+            .filter(|appointment| appointment.hospital_id == hospital_id && appointment.status == AppointmentStatus::Open)
+            .skip(start as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    // Get a paginated list of the caller's appointments, as patient or hospital
+
+    pub fn read_appointments(&self, start: u32, limit: u32) -> Vec<Appointment> {
+        let signer = env::predecessor_account_id();
+
+        self.appointments_by_account
+            .get(&signer)
+            .unwrap_or_default()
+            .iter()
+            .skip(start as usize)
+            .take(limit as usize)
+            .filter_map(|id| self.appointments.get(id))
+            .collect()
+    }
+
+    // Load an appointment, panicking unless the caller is its hospital
+
+    fn appointment_for_hospital(&self, appointment_id: u64) -> Appointment {
+        let appointment = self.appointments.get(&appointment_id).expect("Appointment not found!");
+        assert_eq!(env::predecessor_account_id(), appointment.hospital_id, "Only the hospital can manage this appointment");
+        appointment
+    }
+
+    fn index_appointment(&mut self, account_id: &AccountId, appointment_id: u64) {
+        let mut ids = self.appointments_by_account.get(account_id).unwrap_or_default();
+        ids.push(appointment_id);
+        self.appointments_by_account.insert(account_id, &ids);
+    }
+
+    fn unindex_appointment(&mut self, account_id: &AccountId, appointment_id: u64) {
+        let mut ids = self.appointments_by_account.get(account_id).unwrap_or_default();
+        ids.retain(|id| *id != appointment_id);
+        if ids.is_empty() {
+            self.appointments_by_account.remove(account_id);
+        } else {
+            self.appointments_by_account.insert(account_id, &ids);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, VMContext};
+
+    const NO_SHOW_DEPOSIT: u128 = 100000000000000000000000;
+
+    fn get_context(predecessor: &str, timestamp_ms: u64) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build()
+    }
+
+    // hospital.near is verified and has opened a slot that bob has booked
+    fn setup() -> (PatientRecord, u64) {
+        testing_env!(get_context("alice.near", 1000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.verify_provider("hospital.near".parse().unwrap(), String::from("CGH"));
+
+        testing_env!(get_context("hospital.near", 1000));
+        let id = contract.publish_slot(U64(5000), U64(6000), U128(NO_SHOW_DEPOSIT));
+        assert_eq!(1, contract.get_open_slots("hospital.near".parse().unwrap(), 0, 10).len());
+
+        testing_env!(get_context("bob.near", 2000));
+        contract.book_appointment(id);
+        (contract, id)
+    }
+
+    #[test]
+    fn completed_appointment_becomes_record() {
+        let (mut contract, id) = setup();
+        assert!(contract.get_open_slots("hospital.near".parse().unwrap(), 0, 10).is_empty());
+
+        testing_env!(get_context("hospital.near", 5500));
+        let record_id = contract.complete_appointment(id, String::from("Malaria"), String::from("Coartem"),
+            String::from("01/05/2022"), String::from("01/05/2022"), String::from("None"), 1000);
+
+        testing_env!(get_context("bob.near", 7000));
+        let records = contract.read_record(0, 10).unwrap();
+        assert_eq!(record_id, records[0].id);
+        assert_eq!("CGH", records[0].hospital_name);
+        assert_eq!(Some("hospital.near".parse().unwrap()), records[0].author_id);
+        assert_eq!(AppointmentStatus::Completed, contract.get_appointment(id).unwrap().status);
+    }
+
+    #[test]
+    fn deleting_the_record_refunds_the_hospital() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("hospital.near", 5500));
+        let record_id = contract.complete_appointment(id, String::from("Malaria"), String::from("Coartem"),
+            String::from("01/05/2022"), String::from("01/05/2022"), String::from("None"), 1000);
+
+        testing_env!(get_context("bob.near", 7000));
+        contract.delete_record(record_id);
+        let refunded: Vec<String> = get_created_receipts()
+            .into_iter()
+            .filter(|receipt| matches!(receipt.actions[..], [VmAction::Transfer { deposit }] if deposit > 0))
+            .map(|receipt| receipt.receiver_id.to_string())
+            .collect();
+        assert_eq!(vec![String::from("hospital.near")], refunded);
+    }
+
+    #[test]
+    fn cancelled_slot_reopens() {
+        let (mut contract, id) = setup();
+
+        contract.cancel_appointment(id);
+        assert!(contract.read_appointments(0, 10).is_empty());
+        assert_eq!(1, contract.get_open_slots("hospital.near".parse().unwrap(), 0, 10).len());
+    }
+
+    #[test]
+    fn no_show_after_start() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("hospital.near", 5000));
+        assert_eq!(AppointmentStatus::NoShow, contract.mark_no_show(id).status);
+    }
+
+    #[test]
+    #[should_panic(expected = "Appointment has not started yet!")]
+    fn no_completing_before_start() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("hospital.near", 3000));
+        contract.complete_appointment(id, String::from("Malaria"), String::from("Coartem"),
+            String::from("01/05/2022"), String::from("01/05/2022"), String::from("None"), 1000);
+    }
+
+    #[test]
+    fn hospital_cancels_with_a_full_refund() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("hospital.near", 3000));
+        let appointment = contract.cancel_booking(id);
+        assert_eq!(AppointmentStatus::Cancelled, appointment.status);
+        let refunds: Vec<(String, u128)> = get_created_receipts()
+            .into_iter()
+            .filter_map(|receipt| match receipt.actions[..] {
+                [VmAction::Transfer { deposit }] => Some((receipt.receiver_id.to_string(), deposit)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(("bob.near".to_string(), NO_SHOW_DEPOSIT), refunds[0]);
+        assert!(refunds[1].0 == "bob.near" && refunds[1].1 > 0);
+        assert!(contract.get_open_slots("hospital.near".parse().unwrap(), 0, 10).is_empty());
+
+        testing_env!(get_context("bob.near", 3000));
+        assert!(contract.read_appointments(0, 10).is_empty());
+        testing_env!(get_context("hospital.near", 3000));
+        contract.withdraw_slot(id);
+    }
+
+    #[test]
+    fn patient_reclaims_an_unclosed_deposit() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("bob.near", 6000 + DEPOSIT_GRACE_PERIOD));
+        assert_eq!(AppointmentStatus::Lapsed, contract.reclaim_deposit(id).status);
+        assert!(get_created_receipts().into_iter().any(|receipt| receipt.receiver_id.as_str() == "bob.near"
+            && matches!(receipt.actions[..], [VmAction::Transfer { deposit }] if deposit == NO_SHOW_DEPOSIT)));
+    }
+
+    #[test]
+    #[should_panic(expected = "The hospital can still close this appointment!")]
+    fn deposit_waits_out_the_grace_period() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("bob.near", 6000 + DEPOSIT_GRACE_PERIOD - 1));
+        contract.reclaim_deposit(id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Appointment has not started yet!")]
+    fn no_show_before_start() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("hospital.near", 3000));
+        contract.mark_no_show(id);
+    }
+}
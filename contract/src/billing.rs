@@ -0,0 +1,329 @@
+use near_sdk::json_types::U128;
+use near_sdk::{near_bindgen, env, AccountId};
+
+use crate::{PatientRecord, PatientRecordExt};
+use crate::med_record::PaymentStatus;
+use crate::patient::Patient;
+pub use med_block_types::billing::Bill;
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Bill a patient for one of their records, called by the hospital that treated them:
+    // the record's author, or the verified provider under its hospital's name. The amount
+    // is in yoctoNEAR, or in the token's own units when a whitelisted token is given. The
+    // hospital pays for the bill's storage and gets it back once the bill is settled, and
+    // a bill that hasn't been paid yet can be issued again to change it.
+
+    #[payable]
+    pub fn issue_bill(&mut self, patient_id: AccountId, record_id: u64, amount: U128, token_id: Option<AccountId>) -> Bill {
+        let hospital_id = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+
+        let patient = self.patients.get(&patient_id).expect("Patient not found!");
+        let record = patient.get(record_id).expect("Invalid medical record!");
+        assert!(self.treated_record(&hospital_id, record), "Hospital is not the provider on this record!");
+        assert!(
+            record.payment_status == PaymentStatus::Unpaid || record.payment_status == PaymentStatus::Refunded,
+            "Bill has already been paid!"
+        );
+        assert!(amount.0 > 0, "Invalid bill amount!");
+        if let Some(token_id) = &token_id {
+            assert!(self.accepted_tokens.contains_key(token_id), "Token is not accepted!");
+        }
+
+        let key = (patient_id.clone(), record_id);
+        if let Some(previous) = self.bills.get(&key) {
+            assert_eq!(previous.status, PaymentStatus::Unpaid, "Bill has already been paid!");
+            self.close_bill(&previous);
+        }
+
+        let initial_storage = env::storage_usage();
+        let bill = Bill {
+            patient_id,
+            record_id,
+            hospital_id,
+            amount,
+            token_id,
+            status: PaymentStatus::Unpaid,
+            dispute_reason: None,
+        };
+        self.bills.insert(&key, &bill);
+
+        self.pay_for_storage(initial_storage, deposit);
+        bill
+    }
+
+    // Pay the bill issued on one of the caller's records into escrow for the hospital.
+    // The bill's amount is held until released and the rest of the deposit is returned.
+
+    #[payable]
+    pub fn pay_bill(&mut self, record_id: u64) -> Bill {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+
+        let mut bill = self.bills.get(&(signer.clone(), record_id)).expect("Bill not found!");
+        assert!(bill.token_id.is_none(), "Bill is to be paid in a token!");
+        let mut patient = self.patients.get(&signer).expect("Patient not found!");
+        self.escrow_record(&signer, &mut patient, record_id);
+        assert!(deposit >= bill.amount.0, "Insufficient funds!");
+
+        bill.status = PaymentStatus::Escrowed;
+        self.bills.insert(&(signer, record_id), &bill);
+
+        let excess = deposit - bill.amount.0;
+        if excess > 0 {
+            self.return_excess_tokens(excess);
+        }
+        bill
+    }
+
+    // Release the escrowed payment for one of the caller's records to the hospital
+
+    pub fn confirm_bill(&mut self, record_id: u64) -> Bill {
+        let signer = env::predecessor_account_id();
+        let bill = self.escrowed_bill(&signer, record_id);
+
+        self.transfer_bill(&bill, bill.hospital_id.clone());
+        self.settle_bill(bill, PaymentStatus::Paid)
+    }
+
+    // Return the escrowed payment to the patient, called by the hospital on the bill
+
+    pub fn refund_bill(&mut self, patient_id: AccountId, record_id: u64) -> Bill {
+        let bill = self.bills.get(&(patient_id, record_id)).expect("Bill not found!");
+        assert_eq!(env::predecessor_account_id(), bill.hospital_id, "Only the hospital can refund this bill");
+        assert!(
+            bill.status == PaymentStatus::Escrowed || bill.status == PaymentStatus::Disputed,
+            "Bill is not held in escrow!"
+        );
+
+        self.transfer_bill(&bill, bill.patient_id.clone());
+        self.settle_bill(bill, PaymentStatus::Refunded)
+    }
+
+    // Freeze an escrowed payment until the owner resolves it, called by the patient or hospital
+
+    pub fn dispute_bill(&mut self, patient_id: AccountId, record_id: u64, reason: String) -> Bill {
+        let signer = env::predecessor_account_id();
+        let mut bill = self.escrowed_bill(&patient_id, record_id);
+        assert!(signer == bill.patient_id || signer == bill.hospital_id, "Not a party to this bill!");
+
+        bill.dispute_reason = Some(reason);
+        self.settle_bill(bill, PaymentStatus::Disputed)
+    }
+
+    // Settle a disputed payment, either refunding the patient or paying the hospital
+
+    pub fn resolve_dispute(&mut self, patient_id: AccountId, record_id: u64, refund: bool) -> Bill {
+        self.assert_owner();
+        let bill = self.bills.get(&(patient_id, record_id)).expect("Bill not found!");
+        assert_eq!(bill.status, PaymentStatus::Disputed, "Bill is not disputed!");
+
+        if refund {
+            self.transfer_bill(&bill, bill.patient_id.clone());
+            self.settle_bill(bill, PaymentStatus::Refunded)
+        } else {
+            self.transfer_bill(&bill, bill.hospital_id.clone());
+            self.settle_bill(bill, PaymentStatus::Paid)
+        }
+    }
+
+    // Get the bill on a patient's record
+
+    pub fn get_bill(&self, patient_id: AccountId, record_id: u64) -> Option<Bill> {
+        self.bills.get(&(patient_id, record_id))
+    }
+
+    // Load a bill whose funds are still held in escrow
+
+    fn escrowed_bill(&self, patient_id: &AccountId, record_id: u64) -> Bill {
+        let bill = self.bills.get(&(patient_id.clone(), record_id)).expect("Bill not found!");
+        assert_eq!(bill.status, PaymentStatus::Escrowed, "Bill is not held in escrow!");
+        bill
+    }
+
+    // Mark a record's bill as being paid, unless it already has been
+
+    pub(crate) fn escrow_record(&mut self, patient_id: &AccountId, patient: &mut Patient, record_id: u64) {
+        let record = patient.get_mut(record_id).expect("Invalid medical record!");
+        assert!(
+            record.payment_status == PaymentStatus::Unpaid || record.payment_status == PaymentStatus::Refunded,
+            "Bill has already been paid!"
+        );
+        record.payment_status = PaymentStatus::Escrowed;
+        self.patients.insert(patient_id, patient);
+    }
+
+    // Persist a bill's new status on both the bill and its record. A bill paid out or
+    // refunded in NEAR is closed there and then, one in a token once its transfer went through.
+
+    pub(crate) fn settle_bill(&mut self, mut bill: Bill, status: PaymentStatus) -> Bill {
+        let key = (bill.patient_id.clone(), bill.record_id);
+        bill.status = status.clone();
+        let settled = status == PaymentStatus::Paid || status == PaymentStatus::Refunded;
+        if settled && bill.token_id.is_none() {
+            self.close_bill(&bill);
+        } else {
+            self.bills.insert(&key, &bill);
+        }
+
+        if let Some(mut patient) = self.patients.get(&bill.patient_id) {
+            if let Some(record) = patient.get_mut(bill.record_id) {
+                record.payment_status = status;
+                self.patients.insert(&bill.patient_id, &patient);
+            }
+        }
+        bill
+    }
+
+    // Remove a bill, returning the storage it took to the hospital that issued it
+
+    pub(crate) fn close_bill(&mut self, bill: &Bill) {
+        let initial_storage = env::storage_usage();
+        if self.bills.remove(&(bill.patient_id.clone(), bill.record_id)).is_some() {
+            self.refund_storage_cost_to(&bill.hospital_id, initial_storage);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(predecessor: &str, deposit: u128) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(deposit)
+            .build()
+    }
+
+    // Bob has one record from CGH, which bills him 20 NEAR for it
+    fn issue() -> PatientRecord {
+        testing_env!(get_context("alice.near", 1000000000000000000000000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.verify_provider("hospital.near".parse().unwrap(), String::from("CGH"));
+
+        testing_env!(get_context("bob.near", 1000000000000000000000000));
+        contract.add_record(String::from("Malaria"), String::from("CGH"), String::from("Coartem"),
+            String::from("01/05/2022"), String::from("03/05/2022"), String::from("None"), 1000);
+
+        testing_env!(get_context("hospital.near", 1000000000000000000000000));
+        contract.issue_bill("bob.near".parse().unwrap(), 0, U128(TWENTY_NEAR), None);
+        contract
+    }
+
+    // Bob pays the bill into escrow
+    fn setup() -> PatientRecord {
+        let mut contract = issue();
+        testing_env!(get_context("bob.near", TWENTY_NEAR));
+        contract.pay_bill(0);
+        contract
+    }
+
+    const TWENTY_NEAR: u128 = 20_000_000_000_000_000_000_000_000;
+
+    fn payment_status(contract: &PatientRecord) -> PaymentStatus {
+        contract.read_record(0, 1).unwrap()[0].payment_status.clone()
+    }
+
+    #[test]
+    fn confirm_releases_payment() {
+        let mut contract = setup();
+        assert_eq!(PaymentStatus::Escrowed, payment_status(&contract));
+        let bill = contract.get_bill("bob.near".parse().unwrap(), 0).unwrap();
+        assert_eq!(TWENTY_NEAR, bill.amount.0);
+
+        let bill = contract.confirm_bill(0);
+        assert_eq!(PaymentStatus::Paid, bill.status);
+        assert_eq!(PaymentStatus::Paid, payment_status(&contract));
+        assert!(contract.get_bill("bob.near".parse().unwrap(), 0).is_none());
+    }
+
+    #[test]
+    fn refunded_record_can_be_billed_again() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 1000000000000000000000000));
+        contract.refund_bill("bob.near".parse().unwrap(), 0);
+        assert!(contract.get_bill("bob.near".parse().unwrap(), 0).is_none());
+        contract.issue_bill("bob.near".parse().unwrap(), 0, U128(500), None);
+
+        testing_env!(get_context("bob.near", 500));
+        assert_eq!(PaymentStatus::Escrowed, contract.pay_bill(0).status);
+    }
+
+    #[test]
+    fn unpaid_bill_goes_with_its_record() {
+        let mut contract = issue();
+
+        testing_env!(get_context("bob.near", 0));
+        contract.delete_record(0);
+        assert!(contract.get_bill("bob.near".parse().unwrap(), 0).is_none());
+    }
+
+    #[test]
+    fn dispute_resolved_by_refund() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 0));
+        contract.dispute_bill("bob.near".parse().unwrap(), 0, String::from("Wrong patient"));
+
+        testing_env!(get_context("alice.near", 0));
+        let bill = contract.resolve_dispute("bob.near".parse().unwrap(), 0, true);
+        assert_eq!(PaymentStatus::Refunded, bill.status);
+
+        testing_env!(get_context("bob.near", 0));
+        assert_eq!(PaymentStatus::Refunded, payment_status(&contract));
+    }
+
+    #[test]
+    #[should_panic(expected = "Bill is not held in escrow!")]
+    fn disputed_bill_cannot_be_confirmed() {
+        let mut contract = setup();
+        contract.dispute_bill("bob.near".parse().unwrap(), 0, String::from("Not treated"));
+        contract.confirm_bill(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bill is still held in escrow!")]
+    fn escrowed_record_cannot_be_deleted() {
+        let mut contract = setup();
+        contract.delete_record(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Hospital is not the provider on this record!")]
+    fn only_the_provider_on_the_record_bills() {
+        let mut contract = issue();
+
+        testing_env!(get_context("mallory.near", 1000000000000000000000000));
+        contract.issue_bill("bob.near".parse().unwrap(), 0, U128(1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient funds!")]
+    fn bill_needs_the_full_amount() {
+        let mut contract = issue();
+
+        testing_env!(get_context("bob.near", TWENTY_NEAR - 1));
+        contract.pay_bill(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bill is to be paid in a token!")]
+    fn token_bill_is_not_paid_in_near() {
+        let mut contract = issue();
+
+        testing_env!(get_context("alice.near", 1000000000000000000000000));
+        contract.whitelist_token("usdc.near".parse().unwrap(), U128(10));
+        testing_env!(get_context("hospital.near", 1000000000000000000000000));
+        contract.issue_bill("bob.near".parse().unwrap(), 0, U128(1000), Some("usdc.near".parse().unwrap()));
+
+        testing_env!(get_context("bob.near", TWENTY_NEAR));
+        contract.pay_bill(0);
+    }
+}
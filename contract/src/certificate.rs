@@ -0,0 +1,401 @@
+use near_sdk::serde_json::json;
+use near_sdk::json_types::U64;
+use near_sdk::{near_bindgen, env, log, AccountId, PromiseOrValue};
+
+use crate::{now_ms, PatientRecord, PatientRecordExt};
+pub use med_block_types::certificate::{Certificate, CertificateApproval, CertificateVerification,
+    NFTContractMetadata, Token, TokenId, CERTIFICATE_TITLE};
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Let a provider issue one certificate from one of the caller's records. Approving
+    // again for the record replaces the earlier approval. Verifiers are only shown the
+    // record's diagnosis as the title when the patient chooses to disclose it.
+
+    #[payable]
+    pub fn approve_certificate(&mut self, issuer_id: AccountId, record_id: u64, disclose_diagnosis: bool) {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let patient = self.patients.get(&signer).expect("Patient not found!");
+        assert!(patient.get(record_id).is_some(), "Invalid medical record!");
+        self.certificate_approvals.insert(&(signer, record_id), &CertificateApproval { issuer_id, disclose_diagnosis });
+
+        self.settle_storage(initial_storage, deposit);
+    }
+
+    // Get the provider a patient has approved to certify one of their records
+
+    pub fn get_certificate_approval(&self, patient_id: AccountId, record_id: u64) -> Option<CertificateApproval> {
+        self.certificate_approvals.get(&(patient_id, record_id))
+    }
+
+    // Issue a certificate to a patient from one of their records, called by a verified
+    // provider the patient approved that wrote the record or that the patient has shared
+    // it with. The approval is used up. The title is the record's diagnosis if the patient
+    // disclosed it, so a certificate can't claim more than the record says.
+
+    #[payable]
+    pub fn mint_certificate(&mut self, patient_id: AccountId, record_id: u64,
+        description: Option<String>, starts_at: Option<U64>, expires_at: Option<U64>) -> Token {
+        let issuer_id = self.assert_verified_provider();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let patient = self.patients.get(&patient_id).expect("Patient not found!");
+        let record = patient.get(record_id).expect("Invalid medical record!");
+        let approval = self.certificate_approvals
+            .get(&(patient_id.clone(), record_id))
+            .filter(|approval| approval.issuer_id == issuer_id)
+            .expect("Patient has not approved this certificate!");
+        let authored = record.author_id.as_ref() == Some(&issuer_id);
+        let granted = self.grant_for(&patient_id, &issuer_id).map(|grant| grant.covers(record_id)).unwrap_or(false);
+        assert!(authored || granted, "No access to this record!");
+        let title = approval.disclose_diagnosis.then(|| record.diagnosis.trim().to_string());
+
+        // The patient paid for the approval, so its storage goes back to them
+        self.certificate_approvals.remove(&(patient_id.clone(), record_id));
+        self.refund_storage_cost_to(&patient_id, initial_storage);
+        let initial_storage = env::storage_usage();
+
+        let token_id = self.next_certificate_id.to_string();
+        let certificate = Certificate {
+            token_id: token_id.clone(),
+            owner_id: patient_id.clone(),
+            issuer_id,
+            record_id,
+            title,
+            description,
+            issued_at: now_ms(),
+            starts_at: starts_at.map(|at| at.0),
+            expires_at: expires_at.map(|at| at.0),
+            revoked: false,
+        };
+        self.certificates.insert(&token_id, &certificate);
+        self.next_certificate_id += 1;
+        self.certificate_supply += 1;
+
+        let mut tokens = self.certificates_by_owner.get(&patient_id).unwrap_or_default();
+        tokens.push(token_id.clone());
+        self.certificates_by_owner.insert(&patient_id, &tokens);
+
+        self.pay_for_storage(initial_storage, deposit);
+
+        // NEP-297 event so indexers pick up the new token
+        log!("EVENT_JSON:{}", json!({
+            "standard": "nep171",
+            "version": "1.0.0",
+            "event": "nft_mint",
+            "data": [{ "owner_id": patient_id, "token_ids": [token_id] }],
+        }));
+
+        certificate.into()
+    }
+
+    // Revoke a certificate, called by its issuer or the owner of the contract
+
+    pub fn revoke_certificate(&mut self, token_id: TokenId) -> Certificate {
+        let signer = env::predecessor_account_id();
+        let mut certificate = self.certificates.get(&token_id).expect("Certificate not found!");
+        assert!(
+            signer == certificate.issuer_id || signer == self.owner_id,
+            "Only the issuer can revoke this certificate"
+        );
+
+        certificate.revoked = true;
+        self.certificates.insert(&token_id, &certificate);
+        certificate
+    }
+
+    // Burn one of the caller's certificates, so it no longer shows or verifies. The
+    // storage goes back to the issuer that paid for it.
+
+    pub fn burn_certificate(&mut self, token_id: TokenId) {
+        let signer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+
+        let certificate = self.certificates.get(&token_id).expect("Certificate not found!");
+        assert_eq!(signer, certificate.owner_id, "Only the patient can burn this certificate");
+        self.certificates.remove(&token_id);
+        self.certificate_supply -= 1;
+
+        let mut tokens = self.certificates_by_owner.get(&signer).unwrap_or_default();
+        tokens.retain(|id| *id != token_id);
+        if tokens.is_empty() {
+            self.certificates_by_owner.remove(&signer);
+        } else {
+            self.certificates_by_owner.insert(&signer, &tokens);
+        }
+
+        self.refund_storage_cost_to(&certificate.issuer_id, initial_storage);
+
+        log!("EVENT_JSON:{}", json!({
+            "standard": "nep171",
+            "version": "1.0.0",
+            "event": "nft_burn",
+            "data": [{ "owner_id": signer, "token_ids": [token_id] }],
+        }));
+    }
+
+    // Check a certificate is still valid and was issued by a verified provider
+
+    pub fn verify_certificate(&self, token_id: TokenId) -> Option<CertificateVerification> {
+        let certificate = self.certificates.get(&token_id)?;
+        let now = now_ms();
+
+        let issuer_verified = self.is_verified_provider(certificate.issuer_id.clone());
+        let started = certificate.starts_at.map(|at| at <= now).unwrap_or(true);
+        let expired = certificate.expires_at.map(|at| at <= now).unwrap_or(false);
+
+        Some(CertificateVerification {
+            valid: issuer_verified && !certificate.revoked && started && !expired,
+            token_id: certificate.token_id,
+            owner_id: certificate.owner_id,
+            issuer_id: certificate.issuer_id,
+            title: certificate.title.unwrap_or_else(|| String::from(CERTIFICATE_TITLE)),
+            issuer_verified,
+            revoked: certificate.revoked,
+            started,
+            expired,
+        })
+    }
+
+    // NEP-177 contract metadata
+
+    pub fn nft_metadata(&self) -> NFTContractMetadata {
+        NFTContractMetadata {
+            spec: String::from("nft-1.0.0"),
+            name: String::from("HosBlock Health Certificates"),
+            symbol: String::from("HOSCERT"),
+            icon: None,
+            base_uri: None,
+            reference: None,
+            reference_hash: None,
+        }
+    }
+
+    // NEP-171 token lookup
+
+    pub fn nft_token(&self, token_id: TokenId) -> Option<Token> {
+        self.certificates.get(&token_id).map(Token::from)
+    }
+
+    // NEP-181 enumeration of a patient's certificates
+
+    pub fn nft_tokens_for_owner(&self, account_id: AccountId, from_index: Option<U64>, limit: Option<u64>) -> Vec<Token> {
+        self.certificates_by_owner
+            .get(&account_id)
+            .unwrap_or_default()
+            .iter()
+            .skip(from_index.map(|index| index.0).unwrap_or(0) as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .filter_map(|token_id| self.nft_token(token_id.clone()))
+            .collect()
+    }
+
+    pub fn nft_supply_for_owner(&self, account_id: AccountId) -> U64 {
+        U64(self.certificates_by_owner.get(&account_id).map(|tokens| tokens.len() as u64).unwrap_or(0))
+    }
+
+    pub fn nft_total_supply(&self) -> U64 {
+        U64(self.certificate_supply)
+    }
+
+    // Hand a patient's certificates to the account their records moved to
+
+    pub(crate) fn move_certificates(&mut self, old_account_id: &AccountId, new_account_id: &AccountId) {
+        let Some(token_ids) = self.certificates_by_owner.remove(old_account_id) else { return };
+        for token_id in &token_ids {
+            if let Some(mut certificate) = self.certificates.get(token_id) {
+                certificate.owner_id = new_account_id.clone();
+                self.certificates.insert(token_id, &certificate);
+            }
+        }
+        let mut tokens = self.certificates_by_owner.get(new_account_id).unwrap_or_default();
+        tokens.extend(token_ids.iter().cloned());
+        self.certificates_by_owner.insert(new_account_id, &tokens);
+
+        log!("EVENT_JSON:{}", json!({
+            "standard": "nep171",
+            "version": "1.0.0",
+            "event": "nft_transfer",
+            "data": [{ "old_owner_id": old_account_id, "new_owner_id": new_account_id, "token_ids": token_ids }],
+        }));
+    }
+
+    // Certificates are bound to the patient they were issued to
+
+    #[payable]
+    pub fn nft_transfer(&mut self, receiver_id: AccountId, token_id: TokenId, approval_id: Option<u64>, memo: Option<String>) {
+        let _ = (receiver_id, token_id, approval_id, memo);
+        panic!("Certificates are non-transferable");
+    }
+
+    #[payable]
+    pub fn nft_transfer_call(&mut self, receiver_id: AccountId, token_id: TokenId, approval_id: Option<u64>,
+        memo: Option<String>, msg: String) -> PromiseOrValue<bool> {
+        let _ = (receiver_id, token_id, approval_id, memo, msg);
+        panic!("Certificates are non-transferable");
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(predecessor: &str, timestamp_ms: u64) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build()
+    }
+
+    // Bob has a vaccination record he shared with hospital.near, a verified provider,
+    // and approved it to certify without disclosing the diagnosis
+    fn setup() -> PatientRecord {
+        testing_env!(get_context("alice.near", 1000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.verify_provider("hospital.near".parse().unwrap(), String::from("CGH"));
+        contract.verify_provider("clinic.near".parse().unwrap(), String::from("KNH"));
+
+        testing_env!(get_context("bob.near", 1000));
+        contract.add_record(String::from("Yellow fever vaccination"), String::from("CGH"), String::from("YF-Vax"),
+            String::from("01/05/2022"), String::from("01/05/2022"), String::from("None"), 50);
+        contract.grant_access("hospital.near".parse().unwrap(), Some(vec![0]));
+        contract.approve_certificate("hospital.near".parse().unwrap(), 0, false);
+        contract
+    }
+
+    #[test]
+    fn certificate_valid_until_expiry() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 1000));
+        let token = contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, Some(U64(5000)));
+        assert_eq!("bob.near", token.owner_id.as_str());
+        assert_eq!(Some(String::from(CERTIFICATE_TITLE)), token.metadata.unwrap().title);
+        assert!(contract.verify_certificate(token.token_id.clone()).unwrap().valid);
+        assert_eq!(1, contract.nft_tokens_for_owner("bob.near".parse().unwrap(), None, None).len());
+
+        testing_env!(get_context("carol.near", 5000));
+        let verification = contract.verify_certificate(token.token_id).unwrap();
+        assert_eq!(CERTIFICATE_TITLE, verification.title);
+        assert!(verification.expired);
+        assert!(!verification.valid);
+    }
+
+    #[test]
+    fn disclosed_diagnosis_is_shown_to_verifiers_only() {
+        let mut contract = setup();
+        contract.approve_certificate("hospital.near".parse().unwrap(), 0, true);
+
+        testing_env!(get_context("hospital.near", 1000));
+        let token = contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None);
+        assert_eq!(Some(String::from(CERTIFICATE_TITLE)), token.metadata.unwrap().title);
+        assert_eq!("Yellow fever vaccination", contract.verify_certificate(token.token_id).unwrap().title);
+    }
+
+    #[test]
+    #[should_panic(expected = "Patient has not approved this certificate!")]
+    fn each_certificate_needs_an_approval() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 1000));
+        contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None);
+        assert!(contract.get_certificate_approval("bob.near".parse().unwrap(), 0).is_none());
+        contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None);
+    }
+
+    #[test]
+    fn revoked_or_unverified_issuer_is_invalid() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 1000));
+        let first = contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None).token_id;
+        testing_env!(get_context("bob.near", 1000));
+        contract.approve_certificate("hospital.near".parse().unwrap(), 0, false);
+        testing_env!(get_context("hospital.near", 1000));
+        let second = contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None).token_id;
+        contract.revoke_certificate(first.clone());
+        assert!(contract.verify_certificate(first).unwrap().revoked);
+
+        testing_env!(get_context("alice.near", 1000));
+        contract.unverify_provider("hospital.near".parse().unwrap());
+        let verification = contract.verify_certificate(second).unwrap();
+        assert!(!verification.issuer_verified);
+        assert!(!verification.valid);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only verified providers can call this method")]
+    fn unverified_account_cannot_mint() {
+        let mut contract = setup();
+        contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Certificates are non-transferable")]
+    fn certificates_cannot_be_transferred() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 1000));
+        let token = contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None);
+
+        testing_env!(get_context("bob.near", 1000));
+        contract.nft_transfer("carol.near".parse().unwrap(), token.token_id, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "No access to this record!")]
+    fn provider_needs_the_record() {
+        let mut contract = setup();
+        contract.approve_certificate("clinic.near".parse().unwrap(), 0, false);
+
+        testing_env!(get_context("clinic.near", 1000));
+        contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None);
+    }
+
+    #[test]
+    fn patient_can_burn_a_certificate() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 1000));
+        let token = contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None);
+
+        testing_env!(get_context("bob.near", 1000));
+        contract.burn_certificate(token.token_id.clone());
+        assert!(contract.nft_token(token.token_id.clone()).is_none());
+        assert!(contract.verify_certificate(token.token_id).is_none());
+        assert_eq!(U64(0), contract.nft_total_supply());
+        assert!(contract.nft_tokens_for_owner("bob.near".parse().unwrap(), None, None).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the patient can burn this certificate")]
+    fn issuer_cannot_burn() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 1000));
+        let token = contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None);
+        contract.burn_certificate(token.token_id);
+    }
+
+    #[test]
+    fn certificates_follow_the_patient() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 1000));
+        let token = contract.mint_certificate("bob.near".parse().unwrap(), 0, None, None, None);
+
+        contract.move_patient(&"bob.near".parse().unwrap(), &"bob-new.near".parse().unwrap());
+        assert_eq!("bob-new.near", contract.nft_token(token.token_id).unwrap().owner_id.as_str());
+        assert!(contract.nft_tokens_for_owner("bob.near".parse().unwrap(), None, None).is_empty());
+        assert_eq!(1, contract.nft_tokens_for_owner("bob-new.near".parse().unwrap(), None, None).len());
+    }
+}
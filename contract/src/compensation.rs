@@ -0,0 +1,267 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{near_bindgen, env, AccountId, Promise};
+
+use crate::{now_ms, PatientRecord, PatientRecordExt};
+use crate::research::{Consent, Funder};
+
+// One payment into a program, shared equally by the patients consenting when it was made
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct FundingRound {
+    // Epoch milliseconds the payment was made
+    pub paid_at: u64,
+    // What each consenting patient earned from it, in yoctoNEAR
+    pub share: u128,
+}
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Pay the patients currently consenting to a program for access to their data, called
+    // by the program's institution or a registered insurer. The amount is shared equally
+    // between them, and whatever doesn't divide evenly is returned with the unused deposit.
+    // Shares are recorded once for the program and counted for each patient when they
+    // claim, so funding costs the same however many patients take part. The attached
+    // deposit covers the amount plus the storage for the payment. An insurer must pay at
+    // least the program's minimum, and each payment lets it export the data for the
+    // program's access period.
+
+    #[payable]
+    pub fn fund_program(&mut self, program_id: u64, amount: U128) {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let mut program = self.programs.get(&program_id).expect("Program not found!");
+        let is_institution = signer == program.institution_id;
+        assert!(is_institution || self.insurers.get(&signer).is_some(), "Only the program's institution or an insurer can fund it");
+        assert!(amount.0 > 0, "Nothing to pay!");
+        assert!(deposit >= amount.0, "Insufficient funds!");
+
+        let patients = self.active_consents(program_id).len() as u128;
+        assert!(patients > 0, "No patients consent to the program!");
+        let share = amount.0 / patients;
+        assert!(share > 0, "Amount is too small to share!");
+
+        let now = now_ms();
+        self.funding_rounds.insert(&(program_id, program.funding_rounds), &FundingRound { paid_at: now, share });
+        program.funding_rounds += 1;
+
+        if !is_institution {
+            let terms = program.funding_terms.clone().expect("Program is not open to funding!");
+            assert!(amount.0 >= terms.min_amount.0, "Payment is below the program's minimum!");
+
+            // A payment extends access already paid for rather than overlapping it
+            match program.funders.iter_mut().find(|funder| funder.account_id == signer) {
+                Some(funder) => funder.access_until = U64(funder.access_until.0.max(now) + terms.access_period.0),
+                None => program.funders.push(Funder { account_id: signer, access_until: U64(now + terms.access_period.0) }),
+            }
+        }
+        self.programs.insert(&program_id, &program);
+
+        self.pay_for_storage(initial_storage, deposit - share * patients);
+    }
+
+    // Get what an account has been paid for its data and not yet claimed
+
+    pub fn get_claimable(&self, account_id: AccountId) -> U128 {
+        let mut amount = 0;
+        for program_id in self.consents_by_patient.get(&account_id).unwrap_or_default() {
+            let consents = self.consents.get(&program_id).unwrap_or_default();
+            if let Some(mut consent) = consents.into_iter().find(|consent| consent.patient_id == account_id) {
+                self.count_rounds(program_id, &mut consent);
+                amount += consent.earned.0;
+            }
+        }
+        U128(amount)
+    }
+
+    // Withdraw everything the caller has been paid for their data
+
+    pub fn claim_compensation(&mut self) -> U128 {
+        let signer = env::predecessor_account_id();
+
+        let mut amount = 0;
+        for program_id in self.consents_by_patient.get(&signer).unwrap_or_default() {
+            let mut consents = self.consents.get(&program_id).unwrap_or_default();
+            let Some(consent) = consents.iter_mut().find(|consent| consent.patient_id == signer) else { continue };
+            self.count_rounds(program_id, consent);
+            amount += consent.earned.0;
+            consent.earned = U128(0);
+            self.consents.insert(&program_id, &consents);
+        }
+        assert!(amount > 0, "Nothing to claim!");
+
+        Promise::new(signer).transfer(amount);
+        U128(amount)
+    }
+
+    // Add the program's funding rounds a consent hasn't counted yet to what it earned.
+    // A round counts if the consent was still in force when it was paid.
+
+    pub(crate) fn count_rounds(&self, program_id: u64, consent: &mut Consent) {
+        let rounds = self.programs.get(&program_id).map(|program| program.funding_rounds).unwrap_or(0);
+        let earned: u128 = (consent.rounds_counted..rounds)
+            .filter_map(|round| self.funding_rounds.get(&(program_id, round)))
+            .filter(|round| round.paid_at < consent.expires_at.0)
+            .map(|round| round.share)
+            .sum();
+        consent.earned = U128(consent.earned.0 + earned);
+        consent.rounds_counted = rounds;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::get_created_receipts;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    const PURPOSE: &str = "Malaria treatment outcomes";
+
+    fn get_context(predecessor: &str) -> VMContext {
+        context_at(predecessor, 0)
+    }
+
+    fn context_at(predecessor: &str, timestamp_ms: u64) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build()
+    }
+
+    // A program charging insurers at least 1000 yocto for 1000 ms of access, with bob
+    // contributing two records, carol one and dave none
+    fn setup() -> (PatientRecord, u64) {
+        testing_env!(get_context("alice.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.approve_institution("uni.near".parse().unwrap(), String::from("University"));
+        contract.register_insurer("insurer.near".parse().unwrap(), String::from("NHIF"));
+
+        testing_env!(get_context("uni.near"));
+        let id = contract.register_program(String::from("Malaria study"), String::from(PURPOSE));
+        contract.set_funding_terms(id, U128(1000), U64(1000));
+
+        for (patient, records) in [("bob.near", 2), ("carol.near", 1), ("dave.near", 0)] {
+            testing_env!(get_context(patient));
+            for _ in 0..records {
+                contract.add_record(String::from("Malaria"), String::from("CGH"), String::from("Coartem"),
+                    String::from("01/05/2022"), String::from("03/05/2022"), String::from("None"), 1000);
+            }
+            contract.opt_in(id, String::from(PURPOSE), U64(u64::MAX));
+        }
+        (contract, id)
+    }
+
+    // Transfers sent by the last call, as receiver and amount
+    fn transfers() -> Vec<(String, u128)> {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions.into_iter().map(move |action| (receipt.receiver_id.to_string(), action)))
+            .filter_map(|(receiver, action)| match action {
+                VmAction::Transfer { deposit } => Some((receiver, deposit)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn payment_is_shared_equally() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("insurer.near"));
+        contract.fund_program(id, U128(1000));
+        for patient in ["bob.near", "carol.near", "dave.near"] {
+            assert_eq!(U128(333), contract.get_claimable(patient.parse().unwrap()));
+        }
+
+        // Funding gave the insurer access to the data
+        assert_eq!(3, contract.export_research_data(id, 0, 10).len());
+
+        testing_env!(get_context("bob.near"));
+        assert_eq!(U128(333), contract.claim_compensation());
+        assert_eq!(U128(0), contract.get_claimable("bob.near".parse().unwrap()));
+    }
+
+    #[test]
+    fn only_consents_in_force_share_a_payment() {
+        let (mut contract, id) = setup();
+
+        testing_env!(context_at("erin.near", 0));
+        contract.opt_in(id, String::from(PURPOSE), U64(1000));
+
+        testing_env!(context_at("uni.near", 500));
+        contract.fund_program(id, U128(1000));
+        testing_env!(context_at("uni.near", 1500));
+        contract.fund_program(id, U128(900));
+        assert_eq!(U128(250), contract.get_claimable("erin.near".parse().unwrap()));
+        assert_eq!(U128(550), contract.get_claimable("bob.near".parse().unwrap()));
+
+        // Renewing doesn't count the payment made while the consent had lapsed
+        testing_env!(context_at("erin.near", 2000));
+        contract.opt_in(id, String::from(PURPOSE), U64(u64::MAX));
+        assert_eq!(U128(250), contract.get_claimable("erin.near".parse().unwrap()));
+
+        // Nor does joining count payments made before
+        testing_env!(context_at("frank.near", 2000));
+        contract.opt_in(id, String::from(PURPOSE), U64(u64::MAX));
+        assert_eq!(U128(0), contract.get_claimable("frank.near".parse().unwrap()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only approved institutions can call this method")]
+    fn access_runs_out() {
+        let (mut contract, id) = setup();
+
+        testing_env!(context_at("insurer.near", 500));
+        contract.fund_program(id, U128(1000));
+        assert_eq!(3, contract.export_research_data(id, 0, 10).len());
+
+        testing_env!(context_at("insurer.near", 1500));
+        contract.export_research_data(id, 0, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Payment is below the program's minimum!")]
+    fn token_payments_buy_nothing() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("insurer.near"));
+        contract.fund_program(id, U128(999));
+    }
+
+    #[test]
+    fn opting_out_pays_what_was_earned() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("insurer.near"));
+        contract.fund_program(id, U128(1000));
+
+        testing_env!(get_context("carol.near"));
+        contract.opt_out(id);
+        assert!(transfers().contains(&("carol.near".to_string(), 333)));
+        assert_eq!(U128(0), contract.get_claimable("carol.near".parse().unwrap()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Nothing to claim!")]
+    fn nothing_to_claim_before_funding() {
+        let (mut contract, _) = setup();
+
+        testing_env!(get_context("bob.near"));
+        contract.claim_compensation();
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the program's institution or an insurer can fund it")]
+    fn strangers_cannot_fund() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("mallory.near"));
+        contract.fund_program(id, U128(1000));
+    }
+}
@@ -0,0 +1,357 @@
+use near_sdk::serde_json;
+use near_sdk::json_types::U128;
+use near_sdk::{near_bindgen, env, ext_contract, AccountId, Gas, Promise, PromiseOrValue, PromiseResult};
+
+use crate::{PatientRecord, PatientRecordExt};
+use crate::billing::Bill;
+use crate::med_record::PaymentStatus;
+pub use med_block_types::fungible_token::{AcceptedToken, CreditPurchase, StorageCredit, TokenPayment};
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas(10_000_000_000_000);
+
+#[allow(dead_code)]
+#[ext_contract(ext_ft)]
+pub trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Accept payments in a NEP-141 token. The price must be above zero, or storage
+    // paid for in the token would come out of the contract's own balance.
+
+    #[payable]
+    pub fn whitelist_token(&mut self, token_id: AccountId, storage_byte_price: U128) {
+        self.assert_owner();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+        assert!(storage_byte_price.0 > 0, "Token cannot pay for storage!");
+
+        self.accepted_tokens.insert(&token_id, &AcceptedToken { storage_byte_price });
+
+        self.pay_for_storage(initial_storage, deposit);
+    }
+
+    // Stop accepting a token, bills already paid in it are settled in it
+
+    pub fn remove_token(&mut self, token_id: AccountId) -> Option<AcceptedToken> {
+        self.assert_owner();
+        let initial_storage = env::storage_usage();
+
+        let removed_token = self.accepted_tokens.remove(&token_id);
+        if removed_token.is_some() {
+            self.refund_storage_cost(initial_storage);
+        }
+        removed_token
+    }
+
+    // Get the terms a token is accepted on
+
+    pub fn get_token(&self, token_id: AccountId) -> Option<AcceptedToken> {
+        self.accepted_tokens.get(&token_id)
+    }
+
+    // Get the storage an account has bought with tokens
+
+    pub fn get_storage_credit(&self, account_id: AccountId) -> StorageCredit {
+        self.storage_credits.get(&account_id).unwrap_or_default()
+    }
+
+    // NEP-141 receiver, called by a whitelisted token when a patient pays with `ft_transfer_call`.
+    // Returns the amount that was not used so the token refunds it to the sender.
+
+    pub fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
+        let token_id = env::predecessor_account_id();
+        let token = self.accepted_tokens.get(&token_id).expect("Token is not accepted!");
+        let payment: TokenPayment = serde_json::from_str(&msg).expect("Invalid payment message!");
+
+        let unused = match payment {
+            TokenPayment::PayBill { record_id } => {
+                self.pay_bill_in_token(sender_id, token_id, record_id, amount.0)
+            }
+            TokenPayment::BuyStorage => {
+                let byte_price = token.storage_byte_price.0;
+                assert!(byte_price > 0, "Token cannot pay for storage!");
+
+                let initial_storage = env::storage_usage();
+                let mut credit = self.storage_credits.get(&sender_id).unwrap_or_default();
+                let purchase = CreditPurchase { token_id, byte_price: token.storage_byte_price };
+                match &credit.purchase {
+                    Some(existing) => assert_eq!(*existing, purchase, "Storage credit was bought on other terms!"),
+                    None => credit.purchase = Some(purchase),
+                }
+
+                // Buy no more bytes than the credit can count, what is left over is returned
+                let bytes = u64::try_from(amount.0 / byte_price)
+                    .unwrap_or(u64::MAX)
+                    .min(u64::MAX - credit.available - credit.used);
+                credit.available += bytes;
+                self.storage_credits.insert(&sender_id, &credit);
+
+                // A new credit pays for its own entry out of the bytes it bought
+                let entry_bytes = env::storage_usage().saturating_sub(initial_storage);
+                assert!(credit.available >= entry_bytes, "Insufficient funds!");
+                credit.available -= entry_bytes;
+                credit.used += entry_bytes;
+                self.storage_credits.insert(&sender_id, &credit);
+
+                amount.0 - bytes as u128 * byte_price
+            }
+        };
+        PromiseOrValue::Value(U128(unused))
+    }
+
+    // Withdraw the storage credit the caller hasn't used, paid back in the token it was
+    // bought with at the price it was bought at. Once none of it is in use the credit
+    // is removed and the bytes its own entry took are paid back as well.
+
+    pub fn withdraw_storage_credit(&mut self) -> U128 {
+        let signer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+
+        let mut credit = self.storage_credits.remove(&signer).expect("Storage credit not found!");
+        let purchase = credit.purchase.clone().expect("Storage credit not found!");
+        let entry_bytes = initial_storage.saturating_sub(env::storage_usage());
+
+        let (available, used) = if credit.used <= entry_bytes {
+            (credit.available, credit.used)
+        } else {
+            let available = credit.available;
+            credit.available = 0;
+            self.storage_credits.insert(&signer, &credit);
+            (available, 0)
+        };
+        let amount = (available + used) as u128 * purchase.byte_price.0;
+        assert!(amount > 0, "Nothing to withdraw!");
+
+        ext_ft::ext(purchase.token_id.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(signer.clone(), U128(amount), Some(String::from("Storage credit")))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .on_credit_withdrawal(signer, purchase, available, used)
+            );
+        U128(amount)
+    }
+
+    // Put back a storage credit whose token transfer failed
+
+    #[private]
+    pub fn on_credit_withdrawal(&mut self, account_id: AccountId, purchase: CreditPurchase, available: u64, used: u64) -> bool {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return true;
+        }
+        let mut credit = self.storage_credits.get(&account_id).unwrap_or_default();
+        credit.purchase.get_or_insert(purchase);
+        credit.available += available;
+        credit.used += used;
+        self.storage_credits.insert(&account_id, &credit);
+        false
+    }
+
+    // Close a bill once its token transfer went through, or restore it so it can be settled again
+
+    #[private]
+    pub fn on_bill_transfer(&mut self, patient_id: AccountId, record_id: u64, previous_status: PaymentStatus) -> bool {
+        let bill = self.bills.get(&(patient_id, record_id));
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            if let Some(bill) = bill {
+                self.close_bill(&bill);
+            }
+            return true;
+        }
+        if let Some(bill) = bill {
+            self.settle_bill(bill, previous_status);
+        }
+        false
+    }
+
+    // Escrow a token payment of the bill issued on one of the patient's records,
+    // returning whatever was sent beyond the bill's amount
+
+    fn pay_bill_in_token(&mut self, patient_id: AccountId, token_id: AccountId, record_id: u64, amount: u128) -> u128 {
+        let mut bill = self.bills.get(&(patient_id.clone(), record_id)).expect("Bill not found!");
+        assert_eq!(bill.token_id, Some(token_id), "Bill is not to be paid in this token!");
+        let mut patient = self.patients.get(&patient_id).expect("Patient not found!");
+        self.escrow_record(&patient_id, &mut patient, record_id);
+        assert!(amount >= bill.amount.0, "Insufficient funds!");
+
+        bill.status = PaymentStatus::Escrowed;
+        self.bills.insert(&(patient_id, record_id), &bill);
+        amount - bill.amount.0
+    }
+
+    // Send a bill's escrowed funds to the receiver in whatever they were paid in
+
+    pub(crate) fn transfer_bill(&self, bill: &Bill, receiver_id: AccountId) {
+        match &bill.token_id {
+            None => {
+                Promise::new(receiver_id).transfer(bill.amount.0);
+            }
+            Some(token_id) => {
+                ext_ft::ext(token_id.clone())
+                    .with_attached_deposit(1)
+                    .with_static_gas(GAS_FOR_FT_TRANSFER)
+                    .ft_transfer(receiver_id, bill.amount, Some(format!("Bill for record {}", bill.record_id)))
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                            .on_bill_transfer(bill.patient_id.clone(), bill.record_id, bill.status.clone())
+                    );
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(predecessor: &str, deposit: u128) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(deposit)
+            .build()
+    }
+
+    // Owner accepts usdc.near at 10 units per byte and bob has one record from CGH
+    fn setup() -> PatientRecord {
+        testing_env!(get_context("alice.near", 1000000000000000000000000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.whitelist_token("usdc.near".parse().unwrap(), U128(10));
+        contract.verify_provider("hospital.near".parse().unwrap(), String::from("CGH"));
+
+        testing_env!(get_context("bob.near", 1000000000000000000000000));
+        contract.add_record(String::from("Malaria"), String::from("CGH"), String::from("Coartem"),
+            String::from("01/05/2022"), String::from("03/05/2022"), String::from("None"), 1000);
+        contract
+    }
+
+    fn unused(result: PromiseOrValue<U128>) -> u128 {
+        match result {
+            PromiseOrValue::Value(amount) => amount.0,
+            PromiseOrValue::Promise(_) => panic!("Expected a value"),
+        }
+    }
+
+    #[test]
+    fn pay_bill_with_token() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 1000000000000000000000000));
+        contract.issue_bill("bob.near".parse().unwrap(), 0, U128(1000), Some("usdc.near".parse().unwrap()));
+
+        testing_env!(get_context("usdc.near", 0));
+        let msg = r#"{"pay_bill": {"record_id": 0}}"#;
+        let result = contract.ft_on_transfer("bob.near".parse().unwrap(), U128(1500), msg.to_string());
+        assert_eq!(500, unused(result));
+
+        let bill = contract.get_bill("bob.near".parse().unwrap(), 0).unwrap();
+        assert_eq!(Some("usdc.near".parse().unwrap()), bill.token_id);
+        assert_eq!(PaymentStatus::Escrowed, bill.status);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bill is not to be paid in this token!")]
+    fn near_bill_is_not_paid_in_a_token() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near", 1000000000000000000000000));
+        contract.issue_bill("bob.near".parse().unwrap(), 0, U128(1000), None);
+
+        testing_env!(get_context("usdc.near", 0));
+        let msg = r#"{"pay_bill": {"record_id": 0}}"#;
+        contract.ft_on_transfer("bob.near".parse().unwrap(), U128(1000), msg.to_string());
+    }
+
+    #[test]
+    fn buy_storage_returns_remainder() {
+        let mut contract = setup();
+
+        testing_env!(get_context("usdc.near", 0));
+        let result = contract.ft_on_transfer("bob.near".parse().unwrap(), U128(10_005), String::from("\"buy_storage\""));
+        assert_eq!(5, unused(result));
+        let credit = contract.get_storage_credit("bob.near".parse().unwrap());
+        assert!(credit.used > 0);
+        assert_eq!(1000, credit.available + credit.used);
+        let entry_bytes = credit.used;
+
+        // The next record is paid for out of the credit and released bytes go back to it
+        testing_env!(get_context("bob.near", 0));
+        contract.add_record(String::from("Flu"), String::from("CGH"), String::from("Panadol"),
+            String::from("01/06/2022"), String::from("02/06/2022"), String::from("None"), 500);
+        let credit = contract.get_storage_credit("bob.near".parse().unwrap());
+        assert!(credit.used > 0);
+        assert_eq!(1000, credit.available + credit.used);
+
+        contract.delete_record(1);
+        let credit = contract.get_storage_credit("bob.near".parse().unwrap());
+        assert_eq!(entry_bytes, credit.used);
+        assert_eq!(1000, credit.available + credit.used);
+    }
+
+    #[test]
+    fn oversized_purchase_returns_what_the_credit_cannot_hold() {
+        let mut contract = setup();
+
+        testing_env!(get_context("usdc.near", 0));
+        let result = contract.ft_on_transfer("bob.near".parse().unwrap(), U128(u128::MAX), String::from("\"buy_storage\""));
+        let credit = contract.get_storage_credit("bob.near".parse().unwrap());
+        assert_eq!(u64::MAX, credit.available + credit.used);
+        assert_eq!(u128::MAX - u64::MAX as u128 * 10, unused(result));
+    }
+
+    #[test]
+    fn unused_credit_is_withdrawn_in_the_token() {
+        let mut contract = setup();
+
+        testing_env!(get_context("usdc.near", 0));
+        contract.ft_on_transfer("bob.near".parse().unwrap(), U128(10_000), String::from("\"buy_storage\""));
+
+        // With nothing stored on it the whole credit goes back, entry and all
+        testing_env!(get_context("bob.near", 0));
+        assert_eq!(10_000, contract.withdraw_storage_credit().0);
+        assert!(contract.get_storage_credit("bob.near".parse().unwrap()).purchase.is_none());
+    }
+
+    #[test]
+    fn credit_in_use_stays() {
+        let mut contract = setup();
+
+        testing_env!(get_context("usdc.near", 0));
+        contract.ft_on_transfer("bob.near".parse().unwrap(), U128(10_000), String::from("\"buy_storage\""));
+        testing_env!(get_context("bob.near", 0));
+        contract.add_record(String::from("Flu"), String::from("CGH"), String::from("Panadol"),
+            String::from("01/06/2022"), String::from("02/06/2022"), String::from("None"), 500);
+        let credit = contract.get_storage_credit("bob.near".parse().unwrap());
+
+        assert_eq!(credit.available as u128 * 10, contract.withdraw_storage_credit().0);
+        let left = contract.get_storage_credit("bob.near".parse().unwrap());
+        assert_eq!(0, left.available);
+        assert_eq!(credit.used, left.used);
+    }
+
+    #[test]
+    #[should_panic(expected = "Token cannot pay for storage!")]
+    fn free_storage_is_refused() {
+        let mut contract = setup();
+        testing_env!(get_context("alice.near", 1000000000000000000000000));
+        contract.whitelist_token("free.near".parse().unwrap(), U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is not accepted!")]
+    fn rejects_unlisted_token() {
+        let mut contract = setup();
+
+        testing_env!(get_context("fake.near", 0));
+        contract.ft_on_transfer("bob.near".parse().unwrap(), U128(100), String::from("\"buy_storage\""));
+    }
+}
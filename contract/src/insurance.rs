@@ -0,0 +1,296 @@
+use near_sdk::{near_bindgen, env, AccountId};
+
+use crate::{PatientRecord, PatientRecordExt};
+pub use med_block_types::insurance::{Claim, ClaimStatus, Insurer, Policy};
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Register an insurer account that patients can link policies with
+
+    #[payable]
+    pub fn register_insurer(&mut self, insurer_id: AccountId, name: String) {
+        self.assert_owner();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        self.insurers.insert(&insurer_id, &Insurer { name });
+
+        self.pay_for_storage(initial_storage, deposit);
+    }
+
+    // Get an insurer's details
+
+    pub fn get_insurer(&self, insurer_id: AccountId) -> Option<Insurer> {
+        self.insurers.get(&insurer_id)
+    }
+
+    // Link the caller's records to a policy with a registered insurer
+
+    #[payable]
+    pub fn link_policy(&mut self, insurer_id: AccountId, policy_number: String) {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        assert!(self.insurers.contains_key(&insurer_id), "Insurer is not registered!");
+
+        self.policies.insert(&signer, &Policy { insurer_id, policy_number });
+
+        self.pay_for_storage(initial_storage, deposit);
+    }
+
+    // Remove the caller's policy, claims already submitted are kept
+
+    pub fn unlink_policy(&mut self) -> Option<Policy> {
+        let signer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+
+        let removed_policy = self.policies.remove(&signer);
+        if removed_policy.is_some() {
+            self.refund_storage_cost(initial_storage);
+        }
+        removed_policy
+    }
+
+    // Get a patient's policy
+
+    pub fn get_policy(&self, patient_id: AccountId) -> Option<Policy> {
+        self.policies.get(&patient_id)
+    }
+
+    // Submit a claim for a patient's record to the insurer on the patient's policy,
+    // called by the verified provider that treated them: the one that wrote the record,
+    // or for a record the patient added, the one registered under its hospital's name.
+    // The caller is recorded as the hospital and the record's price as the amount. A
+    // record can only be claimed for again once its last claim was rejected.
+
+    #[payable]
+    pub fn submit_claim(&mut self, patient_id: AccountId, record_id: u64) -> u64 {
+        let hospital_id = self.assert_verified_provider();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let policy = self.policies.get(&patient_id).expect("Patient has no linked policy!");
+        let patient = self.patients.get(&patient_id).expect("Patient not found!");
+        let record = patient.get(record_id).expect("Invalid medical record!");
+        assert!(self.treated_record(&hospital_id, record), "Only the provider on the record can claim for it");
+        let claimed = self.claims_by_record
+            .get(&(patient_id.clone(), record_id))
+            .and_then(|claim_id| self.claims.get(&claim_id))
+            .map(|claim| claim.status != ClaimStatus::Rejected)
+            .unwrap_or(false);
+        assert!(!claimed, "Record has already been claimed for!");
+
+        let id = self.next_claim_id;
+        let claim = Claim {
+            id,
+            patient_id: patient_id.clone(),
+            hospital_id: hospital_id.clone(),
+            insurer_id: policy.insurer_id.clone(),
+            policy_number: policy.policy_number,
+            record_id,
+            amount: record.price,
+            approved_amount: 0.0,
+            status: ClaimStatus::Pending,
+            reason: None,
+        };
+        self.claims.insert(&id, &claim);
+        self.claims_by_record.insert(&(patient_id.clone(), record_id), &id);
+        self.next_claim_id += 1;
+
+        // Index the claim for every party so each can list it
+        for account_id in [&patient_id, &hospital_id, &policy.insurer_id] {
+            let mut ids = self.claims_by_account.get(account_id).unwrap_or_default();
+            if !ids.contains(&id) {
+                ids.push(id);
+                self.claims_by_account.insert(account_id, &ids);
+            }
+        }
+
+        self.pay_for_storage(initial_storage, deposit);
+        id
+    }
+
+    // Approve a claim for its full amount
+
+    pub fn approve_claim(&mut self, claim_id: u64) -> Claim {
+        let mut claim = self.pending_claim_for_insurer(claim_id);
+        claim.approved_amount = claim.amount;
+        claim.status = ClaimStatus::Approved;
+        self.claims.insert(&claim_id, &claim);
+        claim
+    }
+
+    // Approve part of a claim, giving the reason the rest is not covered
+
+    pub fn partially_approve_claim(&mut self, claim_id: u64, approved_amount: f64, reason: String) -> Claim {
+        let mut claim = self.pending_claim_for_insurer(claim_id);
+        assert!(approved_amount > 0.0 && approved_amount < claim.amount, "Invalid approved amount!");
+        claim.approved_amount = approved_amount;
+        claim.status = ClaimStatus::PartiallyApproved;
+        claim.reason = Some(reason);
+        self.claims.insert(&claim_id, &claim);
+        claim
+    }
+
+    // Reject a claim, giving the reason
+
+    pub fn reject_claim(&mut self, claim_id: u64, reason: String) -> Claim {
+        let mut claim = self.pending_claim_for_insurer(claim_id);
+        claim.status = ClaimStatus::Rejected;
+        claim.reason = Some(reason);
+        self.claims.insert(&claim_id, &claim);
+        claim
+    }
+
+    // Get a claim, only the patient, hospital and insurer on it can read it
+
+    pub fn get_claim(&self, claim_id: u64) -> Option<Claim> {
+        let signer = env::predecessor_account_id();
+
+        let claim = self.claims.get(&claim_id)?;
+        assert!(
+            signer == claim.patient_id || signer == claim.hospital_id || signer == claim.insurer_id,
+            "Not a party to this claim!"
+        );
+        Some(claim)
+    }
+
+    // Get a paginated list of the claims the caller is a party to
+
+    pub fn read_claims(&self, start: u32, limit: u32) -> Vec<Claim> {
+        let signer = env::predecessor_account_id();
+
+        self.claims_by_account
+            .get(&signer)
+            .unwrap_or_default()
+            .iter()
+            .skip(start as usize)
+            .take(limit as usize)
+            .filter_map(|id| self.claims.get(id))
+            .collect()
+    }
+
+    // Load a pending claim, panicking unless the caller is its insurer
+
+    fn pending_claim_for_insurer(&self, claim_id: u64) -> Claim {
+        let claim = self.claims.get(&claim_id).expect("Claim not found!");
+        assert_eq!(env::predecessor_account_id(), claim.insurer_id, "Only the insurer can decide on this claim");
+        assert_eq!(claim.status, ClaimStatus::Pending, "Claim has already been decided!");
+        claim
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(predecessor: &str) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .build()
+    }
+
+    // Owner registers an insurer and verifies CGH, bob links a policy and has one record from CGH
+    fn setup() -> PatientRecord {
+        testing_env!(get_context("alice.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.register_insurer("insurer.near".parse().unwrap(), String::from("AAR"));
+        contract.verify_provider("hospital.near".parse().unwrap(), String::from("CGH"));
+        contract.verify_provider("clinic.near".parse().unwrap(), String::from("KNH"));
+
+        testing_env!(get_context("bob.near"));
+        contract.link_policy("insurer.near".parse().unwrap(), String::from("POL-1"));
+        contract.add_record(String::from("Malaria"), String::from("CGH"), String::from("Coartem"),
+            String::from("01/05/2022"), String::from("03/05/2022"), String::from("None"), 1000);
+        contract
+    }
+
+    #[test]
+    fn claim_lifecycle() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near"));
+        let claim_id = contract.submit_claim("bob.near".parse().unwrap(), 0);
+        assert_eq!(1, contract.read_claims(0, 10).len());
+
+        testing_env!(get_context("insurer.near"));
+        let claim = contract.partially_approve_claim(claim_id, 600.0, String::from("Co-pay"));
+        assert_eq!(ClaimStatus::PartiallyApproved, claim.status);
+        assert_eq!(600.0, claim.approved_amount);
+
+        testing_env!(get_context("bob.near"));
+        let claim = contract.get_claim(claim_id).unwrap();
+        assert_eq!(1000.0, claim.amount);
+        assert_eq!("insurer.near", claim.insurer_id.as_str());
+    }
+
+    #[test]
+    #[should_panic(expected = "Record has already been claimed for!")]
+    fn record_is_claimed_once() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near"));
+        let claim_id = contract.submit_claim("bob.near".parse().unwrap(), 0);
+        testing_env!(get_context("insurer.near"));
+        contract.approve_claim(claim_id);
+
+        testing_env!(get_context("hospital.near"));
+        contract.submit_claim("bob.near".parse().unwrap(), 0);
+    }
+
+    #[test]
+    fn rejected_claim_can_be_resubmitted() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near"));
+        let claim_id = contract.submit_claim("bob.near".parse().unwrap(), 0);
+        testing_env!(get_context("insurer.near"));
+        contract.reject_claim(claim_id, String::from("Missing documents"));
+
+        testing_env!(get_context("hospital.near"));
+        assert_ne!(claim_id, contract.submit_claim("bob.near".parse().unwrap(), 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the insurer can decide on this claim")]
+    fn only_insurer_decides() {
+        let mut contract = setup();
+
+        testing_env!(get_context("hospital.near"));
+        let claim_id = contract.submit_claim("bob.near".parse().unwrap(), 0);
+        contract.approve_claim(claim_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insurer is not registered!")]
+    fn policy_requires_registered_insurer() {
+        let mut contract = setup();
+
+        testing_env!(get_context("carol.near"));
+        contract.link_policy("unknown.near".parse().unwrap(), String::from("POL-2"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only verified providers can call this method")]
+    fn stranger_cannot_claim() {
+        let mut contract = setup();
+
+        testing_env!(get_context("mallory.near"));
+        contract.submit_claim("bob.near".parse().unwrap(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the provider on the record can claim for it")]
+    fn other_provider_cannot_claim() {
+        let mut contract = setup();
+
+        testing_env!(get_context("clinic.near"));
+        contract.submit_claim("bob.near".parse().unwrap(), 0);
+    }
+}
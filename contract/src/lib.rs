@@ -1,33 +1,168 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupMap;
+use near_sdk::collections::{LazyOption, LookupMap, UnorderedSet};
+use near_sdk::json_types::Base58CryptoHash;
 use near_sdk::{near_bindgen, env, AccountId, Promise};
 
 mod patient;
-mod med_record;
+pub use med_block_types::{fhir, med_record, merkle};
+mod insurance;
+mod billing;
+mod fungible_token;
+mod provider;
+mod certificate;
+mod appointment;
+mod access;
+mod referral;
+mod transfer;
+mod recovery;
+mod stats;
+mod aggregate;
+mod research;
+mod compensation;
+mod validation;
+mod upgrade;
 
 use patient::Patient;
-use med_record::MedRecord;
+use med_record::{MedRecord, NewRecord, PaymentStatus};
+use insurance::{Insurer, Policy, Claim};
+use billing::Bill;
+use fungible_token::{AcceptedToken, StorageCredit};
+use provider::Provider;
+use certificate::{Certificate, CertificateApproval, TokenId};
+use appointment::{Appointment, RecordPayer};
+use access::Grant;
+use referral::Referral;
+use recovery::{RecoveryConfig, RecoveryRequest};
+use stats::RecordStats;
+use aggregate::{Dimension, GroupCount, DEFAULT_MIN_GROUP_SIZE};
+use research::{Institution, Program, Consent};
+use compensation::FundingRound;
+use validation::RecordLimits;
+use upgrade::{UpgradeConfig, UpgradeProposal};
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct PatientRecord {
     patients: LookupMap<AccountId, Patient>,
+    record_stats: LookupMap<AccountId, RecordStats>,
+    visit_counts: LookupMap<(Dimension, String), GroupCount>,
+    visit_groups: LookupMap<Dimension, UnorderedSet<String>>,
+    min_group_size: u64,
+    record_limits: RecordLimits,
+    owner_id: AccountId,
+    insurers: LookupMap<AccountId, Insurer>,
+    policies: LookupMap<AccountId, Policy>,
+    claims: LookupMap<u64, Claim>,
+    claims_by_account: LookupMap<AccountId, Vec<u64>>,
+    claims_by_record: LookupMap<(AccountId, u64), u64>,
+    next_claim_id: u64,
+    bills: LookupMap<(AccountId, u64), Bill>,
+    accepted_tokens: LookupMap<AccountId, AcceptedToken>,
+    storage_credits: LookupMap<AccountId, StorageCredit>,
+    providers: LookupMap<AccountId, Provider>,
+    certificates: LookupMap<TokenId, Certificate>,
+    certificates_by_owner: LookupMap<AccountId, Vec<TokenId>>,
+    next_certificate_id: u64,
+    certificate_supply: u64,
+    certificate_approvals: LookupMap<(AccountId, u64), CertificateApproval>,
+    appointments: LookupMap<u64, Appointment>,
+    appointments_by_account: LookupMap<AccountId, Vec<u64>>,
+    next_appointment_id: u64,
+    record_payers: LookupMap<(AccountId, u64), RecordPayer>,
+    grants: LookupMap<AccountId, Vec<Grant>>,
+    public_summaries: LookupMap<AccountId, bool>,
+    referrals: LookupMap<u64, Referral>,
+    referrals_by_account: LookupMap<AccountId, Vec<u64>>,
+    next_referral_id: u64,
+    transfers: LookupMap<AccountId, AccountId>,
+    recovery_configs: LookupMap<AccountId, RecoveryConfig>,
+    recovery_requests: LookupMap<AccountId, Vec<RecoveryRequest>>,
+    institutions: LookupMap<AccountId, Institution>,
+    programs: LookupMap<u64, Program>,
+    consents: LookupMap<u64, Vec<Consent>>,
+    consents_by_patient: LookupMap<AccountId, Vec<u64>>,
+    next_program_id: u64,
+    funding_rounds: LookupMap<(u64, u32), FundingRound>,
+    upgrade_config: Option<UpgradeConfig>,
+    upgrade_proposal: Option<UpgradeProposal>,
+    upgrade_code: LazyOption<Vec<u8>>,
+}
+
+// Current block time in epoch milliseconds
+pub(crate) fn now_ms() -> u64 {
+    env::block_timestamp() / 1_000_000
 }
 
 impl Default for PatientRecord {
   fn default() -> Self {
-    Self {
-      patients: LookupMap::new(b"c"),
-    }
+    Self::new(env::current_account_id())
   }
 }
 
 #[near_bindgen]
 impl PatientRecord {
-    
+
+    // Initialize the contract with the account allowed to administer it
+
+    #[init]
+    pub fn new(owner_id: AccountId) -> Self {
+        Self {
+            patients: LookupMap::new(b"c"),
+            record_stats: LookupMap::new(b"w"),
+            visit_counts: LookupMap::new(b"d"),
+            visit_groups: LookupMap::new(b"D"),
+            min_group_size: DEFAULT_MIN_GROUP_SIZE,
+            record_limits: RecordLimits::default(),
+            owner_id,
+            insurers: LookupMap::new(b"i"),
+            policies: LookupMap::new(b"p"),
+            claims: LookupMap::new(b"l"),
+            claims_by_account: LookupMap::new(b"a"),
+            claims_by_record: LookupMap::new(b"L"),
+            next_claim_id: 0,
+            bills: LookupMap::new(b"b"),
+            accepted_tokens: LookupMap::new(b"t"),
+            storage_credits: LookupMap::new(b"s"),
+            providers: LookupMap::new(b"h"),
+            certificates: LookupMap::new(b"n"),
+            certificates_by_owner: LookupMap::new(b"o"),
+            next_certificate_id: 0,
+            certificate_supply: 0,
+            certificate_approvals: LookupMap::new(b"A"),
+            appointments: LookupMap::new(b"m"),
+            appointments_by_account: LookupMap::new(b"x"),
+            next_appointment_id: 0,
+            record_payers: LookupMap::new(b"P"),
+            grants: LookupMap::new(b"g"),
+            public_summaries: LookupMap::new(b"u"),
+            referrals: LookupMap::new(b"r"),
+            referrals_by_account: LookupMap::new(b"f"),
+            next_referral_id: 0,
+            transfers: LookupMap::new(b"v"),
+            recovery_configs: LookupMap::new(b"y"),
+            recovery_requests: LookupMap::new(b"q"),
+            institutions: LookupMap::new(b"e"),
+            programs: LookupMap::new(b"j"),
+            consents: LookupMap::new(b"k"),
+            consents_by_patient: LookupMap::new(b"K"),
+            next_program_id: 0,
+            funding_rounds: LookupMap::new(b"F"),
+            upgrade_config: None,
+            upgrade_proposal: None,
+            upgrade_code: LazyOption::new(b"C", None),
+        }
+    }
+
+    // Get the account allowed to administer the contract
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
     // Add a new record object to patients's record
      
     #[payable]
+    #[allow(clippy::too_many_arguments)]
     pub fn add_record(&mut self, diagnosis: String, hospital_name: String, medicine_administered: String,
         date_of_admission: String, date_of_release: String,
         allergies_recorded: String, price: u64) {
@@ -40,20 +175,26 @@ impl PatientRecord {
         // Get initial storage space used
         let initial_storage = env::storage_usage();
 
+        // Check the record against the limits before anything is stored
+        let record = NewRecord { diagnosis, hospital_name, medicine_administered, date_of_admission, date_of_release, allergies_recorded, price };
+        self.assert_valid_records(&signer, std::slice::from_ref(&record));
+        let NewRecord { diagnosis, hospital_name, medicine_administered, date_of_admission, date_of_release, allergies_recorded, price } = record;
+
         // Checking if the patient already exists 
         if let Some(mut patient) = self.patients.get(&signer) {
             // Update patient object with the record info if patient is present
-            patient.add(
+            let id = patient.add(
                 diagnosis,
                 hospital_name, 
                 medicine_administered,
                 date_of_admission,
                 date_of_release, 
                 allergies_recorded, 
-                price as f64
+                price as f64,
+                None
             );
             // Update Patient object on the blockchain
-            self.patients.insert(&signer, &patient);
+            self.save_patient(&signer, &patient, &[id], &[]);
 
             // Pay storage cost
             self.pay_for_storage(initial_storage, deposit);
@@ -62,18 +203,19 @@ impl PatientRecord {
             let mut patient = Patient::new_patient();
 
             // Update patient object with the record info
-            patient.add(
+            let id = patient.add(
                 diagnosis,
                 hospital_name, 
                 medicine_administered,
                 date_of_admission,
                 date_of_release, 
                 allergies_recorded, 
-                price as f64
+                price as f64,
+                None
             );
 
             // Persist patient object on blockchain
-            self.patients.insert(&signer, &patient);
+            self.save_patient(&signer, &patient, &[id], &[]);
 
             // Settle storage cost
             self.pay_for_storage(initial_storage, deposit);
@@ -81,6 +223,26 @@ impl PatientRecord {
     }
 
     
+    // Add several records to the caller's record in one transaction, settling storage once.
+    // Either every record is added or, if the deposit falls short, none are.
+
+    #[payable]
+    pub fn add_records(&mut self, records: Vec<NewRecord>) -> Vec<u64> {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        assert!(!records.is_empty(), "No records given!");
+        self.assert_valid_records(&signer, &records);
+
+        let mut patient = self.patients.get(&signer).unwrap_or_else(Patient::new_patient);
+        let ids = patient.add_many(records, None);
+        self.save_patient(&signer, &patient, &ids, &[]);
+
+        self.pay_for_storage(initial_storage, deposit);
+        ids
+    }
+
     //Retreive/Get a paginated patient record list.
   
     pub fn read_record(&self, start: u32, limit: u32) -> Option<Vec<MedRecord>> {
@@ -99,6 +261,22 @@ impl PatientRecord {
         }
     }
 
+
+    // Export a paginated slice of the caller's records as a FHIR R4 Bundle. The caller
+    // is who the records belong to, so this has to be a call; see `view_public_fhir`.
+
+    pub fn export_fhir(&self, start: u32, limit: u32) -> near_sdk::serde_json::Value {
+        let signer = env::predecessor_account_id();
+        let records = self.read_record(start, limit).unwrap_or_default();
+        fhir::bundle(&signer, &records)
+    }
+
+    // Get the Merkle root over a patient's records that disclosure proofs are checked against
+
+    pub fn get_records_root(&self, patient_id: AccountId) -> Option<Base58CryptoHash> {
+        self.patients.get(&patient_id).map(|patient| patient.records_root().into())
+    }
+
   
     // Remove/Delete a patient object from the patients records given its id (index)
      
@@ -111,14 +289,22 @@ impl PatientRecord {
 
         // Check if user record exist in users storage
         if let Some(mut patient) = self.patients.get(&signer) {
+            // Funds held in escrow for a record must be settled before it goes
+            if let Some(record) = patient.get(id) {
+                assert!(
+                    record.payment_status != PaymentStatus::Escrowed && record.payment_status != PaymentStatus::Disputed,
+                    "Bill is still held in escrow!"
+                );
+            }
+
             // Delete the car object from user wishlist
             let removed_record = patient.remove(id);
 
             // Update user object on blockchain
-            self.patients.insert(&signer, &patient);
+            self.save_patient(&signer, &patient, &[], std::slice::from_ref(&removed_record));
 
             // Credit the tokens unlocked after releasing storage space
-            self.refund_storage_cost(initial_storage);
+            self.refund_record_storage(&signer, std::slice::from_ref(&removed_record), initial_storage);
 
             // Return deleted car object
             Some(removed_record)
@@ -129,15 +315,56 @@ impl PatientRecord {
     }
 
 
+    // Delete several of the caller's records in one transaction, refunding storage once.
+    // Nothing is deleted unless every id exists and none has a bill held in escrow.
+
+    pub fn delete_records(&mut self, ids: Vec<u64>) -> Vec<MedRecord> {
+        let signer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+
+        assert!(!ids.is_empty(), "No records given!");
+        let mut patient = self.patients.get(&signer).expect("Patient not found!");
+        for id in &ids {
+            if let Some(record) = patient.get(*id) {
+                assert!(
+                    record.payment_status != PaymentStatus::Escrowed && record.payment_status != PaymentStatus::Disputed,
+                    "Bill is still held in escrow!"
+                );
+            }
+        }
+
+        let removed_records = patient.remove_many(&ids);
+        self.save_patient(&signer, &patient, &[], &removed_records);
+
+        self.refund_record_storage(&signer, &removed_records, initial_storage);
+        removed_records
+    }
+
    
+    // Panics unless the caller is the contract owner
+
+    fn assert_owner(&self) {
+        assert_eq!(env::predecessor_account_id(), self.owner_id, "Only the owner can call this method");
+    }
+
     // Settles storage expenses
    
-    fn pay_for_storage(&self, initial_storage: u64, attached_storage_cost: u128) {
+    fn pay_for_storage(&mut self, initial_storage: u64, attached_storage_cost: u128) {
         // Get Current Storage
         let current_storage = env::storage_usage();
         
-        // Get Storage Used
-        let storage_used = current_storage - initial_storage;
+        // Get Storage Used, nothing when the change released storage instead
+        let mut storage_used = current_storage.saturating_sub(initial_storage);
+
+        // Cover what we can with storage the user bought in tokens
+        let signer = env::predecessor_account_id();
+        if let Some(mut credit) = self.storage_credits.get(&signer) {
+            let covered = credit.available.min(storage_used);
+            credit.available -= covered;
+            credit.used += covered;
+            self.storage_credits.insert(&signer, &credit);
+            storage_used -= covered;
+        }
         
         // Get Storage cost per byte
         let storage_cost: u128 = env::storage_byte_cost();
@@ -157,9 +384,10 @@ impl PatientRecord {
     }
     
     
-    // Sends back excess tokens to user
+    // Sends back excess tokens to user. Not exported, or anyone could pay
+    // themselves out of the contract's balance.
     
-    pub fn return_excess_tokens(&self, excess_balance: u128) {
+    fn return_excess_tokens(&self, excess_balance: u128) {
         // Get signer address
         let signer = env::predecessor_account_id();
         
@@ -168,27 +396,81 @@ impl PatientRecord {
     }
 
     
+    // Settles storage for a change that may have grown or shrunk state,
+    // returning the attached deposit when storage was released
+     
+    fn settle_storage(&mut self, initial_storage: u64, attached_storage_cost: u128) {
+        if env::storage_usage() >= initial_storage {
+            self.pay_for_storage(initial_storage, attached_storage_cost);
+        } else {
+            self.refund_storage_cost(initial_storage);
+            if attached_storage_cost > 0 {
+                self.return_excess_tokens(attached_storage_cost);
+            }
+        }
+    }
+
+    
     // Refunds user on storage release
      
-    fn refund_storage_cost(&self, initial_storage: u64) {
+    fn refund_storage_cost(&mut self, initial_storage: u64) {
+        self.refund_storage_cost_to(&env::predecessor_account_id(), initial_storage);
+    }
+
+
+    // Refunds storage released to the account that paid for it, which need not be the caller
+     
+    fn refund_storage_cost_to(&mut self, payer_id: &AccountId, initial_storage: u64) {
         // Get current storage space
         let current_storage = env::storage_usage();
 
-        // Compute storage space released
-        let storage_released = initial_storage - current_storage;
+        // Compute storage space released, nothing when the change took more
+        let mut storage_released = initial_storage.saturating_sub(current_storage);
+
+        // Bytes bought in tokens go back to the payer's storage credit
+        if let Some(mut credit) = self.storage_credits.get(payer_id) {
+            let returned = credit.used.min(storage_released);
+            credit.used -= returned;
+            credit.available += returned;
+            self.storage_credits.insert(payer_id, &credit);
+            storage_released -= returned;
+        }
 
         // Get storage unit price (per byte)
         let storage_unit_price = env::storage_byte_cost();
 
         // Compute total refundable storage cost
         if let Some(refundable_storage_cost) = storage_unit_price.checked_mul(storage_released.into()) {
-            // Transfer to user wallet address
-            self.return_excess_tokens(refundable_storage_cost);
+            // Transfer to the payer's wallet address
+            Promise::new(payer_id.clone()).transfer(refundable_storage_cost);
         } else {
             panic!("Error calculating storage cost");
         }
     }
 
+
+    // Refunds storage released by deleting records. The bytes paid for by someone other
+    // than the patient go back to them, up to what was released, and the rest to the caller.
+    // Bills issued on the records go with them, their storage back to the hospital.
+
+    fn refund_record_storage(&mut self, patient_id: &AccountId, removed: &[MedRecord], initial_storage: u64) {
+        let mut initial_storage = initial_storage;
+        for record in removed {
+            if let Some(bill) = self.bills.get(&(patient_id.clone(), record.id)) {
+                let bill_storage = env::storage_usage();
+                self.close_bill(&bill);
+                initial_storage -= bill_storage - env::storage_usage();
+            }
+            if let Some(payer) = self.record_payers.remove(&(patient_id.clone(), record.id)) {
+                let current_storage = env::storage_usage();
+                let bytes = payer.bytes.min(initial_storage.saturating_sub(current_storage));
+                self.refund_storage_cost_to(&payer.account_id, current_storage + bytes);
+                initial_storage -= bytes;
+            }
+        }
+        self.refund_storage_cost(initial_storage);
+    }
+
 }
 
 
@@ -270,5 +552,74 @@ mod tests {
             panic!("Error reading records");
         }
     }
-}
 
+    #[test]
+    fn add_records_with_estimated_deposit() {
+        let params = get_params();
+        let records: Vec<NewRecord> = ["Malaria", "Typhoid", "Flu"]
+            .iter()
+            .map(|diagnosis| NewRecord {
+                diagnosis: String::from(*diagnosis),
+                hospital_name: params.1.clone(),
+                medicine_administered: params.2.clone(),
+                date_of_admission: params.3.clone(),
+                date_of_release: params.4.clone(),
+                allergies_recorded: params.5.clone(),
+                price: params.6,
+            })
+            .collect();
+        let patient_id: AccountId = "bob.near".parse().unwrap();
+        let deposit = fhir::required_deposit(&patient_id, &records, true);
+
+        testing_env!(VMContextBuilder::new().predecessor_account_id(patient_id).attached_deposit(deposit).build());
+        let mut contract = PatientRecord::default();
+        assert_eq!(vec![0, 1, 2], contract.add_records(records));
+        assert_eq!(3, contract.read_record(0, 10).unwrap().len());
+    }
+
+    #[test]
+    fn delete_records_removes_all_at_once() {
+        let context = get_context(false);
+        testing_env!(context);
+        let mut contract = PatientRecord::default();
+        for _ in 0..3 {
+            let params = get_params();
+            contract.add_record(params.0, params.1, params.2, params.3, params.4, params.5, params.6);
+        }
+
+        let removed: Vec<u64> = contract.delete_records(vec![2, 0]).iter().map(|record| record.id).collect();
+        assert_eq!(vec![2, 0], removed);
+        let records = contract.read_record(0, 10).unwrap();
+        assert_eq!(1, records[0].id);
+        let root: near_sdk::CryptoHash = contract.get_records_root("bob.near".parse().unwrap()).unwrap().into();
+        assert_eq!(merkle::records_root(&records), root);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid medical record!")]
+    fn delete_records_is_all_or_nothing() {
+        let context = get_context(false);
+        testing_env!(context);
+        let mut contract = PatientRecord::default();
+        let params = get_params();
+        contract.add_record(params.0, params.1, params.2, params.3, params.4, params.5, params.6);
+
+        contract.delete_records(vec![0, 7]);
+    }
+
+    #[test]
+    fn records_root_matches_library() {
+        let context = get_context(false);
+        testing_env!(context);
+        let mut contract = PatientRecord::default();
+        let params = get_params();
+        contract.add_record(params.0, params.1, params.2, params.3, params.4, params.5, params.6);
+
+        let records = contract.read_record(0, 10).unwrap();
+        let root: near_sdk::CryptoHash = contract.get_records_root("bob.near".parse().unwrap()).unwrap().into();
+        assert_eq!(merkle::records_root(&records), root);
+
+        let proof = merkle::prove_field(&records, 0, "diagnosis").unwrap();
+        assert!(merkle::verify_field(&root, &proof));
+    }
+}
@@ -1,46 +1,66 @@
 use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
 use near_sdk::serde::{Serialize, Deserialize};
-use near_sdk::{near_bindgen};
+use near_sdk::{near_bindgen, env, AccountId, CryptoHash};
 
-use crate::med_record::MedRecord;
+use crate::med_record::{MedRecord, NewRecord};
+use crate::merkle;
 
 /**
  * User structure
  */
 #[near_bindgen]
-#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[derive(Default, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Patient {
  patient_record: Vec<MedRecord>,
+ next_record_id: u64,
+ records_root: CryptoHash,
+ // Each record's hash in record order, so the root is rebuilt without rehashing every field
+ record_leaves: Vec<CryptoHash>,
 }
 
-impl Default for Patient {
-  fn default() -> Self {
-    Self {
-      patient_record: vec![]
-    }
-  }
-}
-
-#[near_bindgen]
 impl Patient {
   // Initializing a new patient object which is empty
  pub fn new_patient() -> Self {
   Self {
-    patient_record: vec![]
+    patient_record: vec![],
+    next_record_id: 0,
+    records_root: [0; 32],
+    record_leaves: vec![],
   }
  }
 
  /**
-  * Adds a medical record object to the patient record
+  * Adds a medical record object to the patient record and returns its id.
+  * Ids are never reused, so they stay valid after other records are deleted.
   */
+ #[allow(clippy::too_many_arguments)]
  pub fn add(&mut self, diagnosis: String, hospital_name: String, medicine_administered: String,
   date_of_admission: String, date_of_release: String,
-  allergies_recorded: String, price: f64) {
-    let record: MedRecord = MedRecord::new(diagnosis,hospital_name, medicine_administered,
-      date_of_admission,date_of_release, allergies_recorded, price);
+  allergies_recorded: String, price: f64, author_id: Option<AccountId>) -> u64 {
+    let id = self.next_record_id;
+    let record: MedRecord = MedRecord::new(id, diagnosis,hospital_name, medicine_administered,
+      date_of_admission,date_of_release, allergies_recorded, price, author_id);
 
-        self.patient_record.push(record);
+        self.push(record);
+        self.records_root = merkle::root_of_leaves(&self.record_leaves);
+        id
+ }
+
+ /**
+  * Adds several records, recomputing the Merkle root once, and returns their ids
+  */
+ pub fn add_many(&mut self, records: Vec<NewRecord>, author_id: Option<AccountId>) -> Vec<u64> {
+  let mut ids = vec![];
+  for record in records {
+    let id = self.next_record_id;
+    self.push(MedRecord::new(id, record.diagnosis, record.hospital_name,
+      record.medicine_administered, record.date_of_admission, record.date_of_release,
+      record.allergies_recorded, record.price as f64, author_id.clone()));
+    ids.push(id);
+  }
+  self.records_root = merkle::root_of_leaves(&self.record_leaves);
+  ids
  }
 
  /**
@@ -52,11 +72,73 @@ impl Patient {
  }
 
  /**
-  * Deletes a MedRecord object from patient_record vector 
+  * Gets every MedRecord object without copying them
+  */
+ pub fn records(&self) -> &[MedRecord] {
+  &self.patient_record
+ }
+
+ /**
+  * Looks up a MedRecord object by its id
+  */
+ pub fn get(&self, id: u64) -> Option<&MedRecord> {
+  self.patient_record.iter().find(|record| record.id == id)
+ }
+
+ /**
+  * Looks up a MedRecord object by its id for updating
+  */
+ pub fn get_mut(&mut self, id: u64) -> Option<&mut MedRecord> {
+  self.patient_record.iter_mut().find(|record| record.id == id)
+ }
+
+ /**
+  * Deletes a MedRecord object from patient_record vector given its id
+  */
+ pub fn remove(&mut self, id: u64) -> MedRecord {
+  let index = self.patient_record.iter().position(|record| record.id == id);
+  assert!(index.is_some(), "Invalid medical record!");
+  let record = self.patient_record.remove(index.unwrap());
+  self.record_leaves.remove(index.unwrap());
+  self.records_root = merkle::root_of_leaves(&self.record_leaves);
+  record
+ }
+
+ /**
+  * Removes several records by id, recomputing the Merkle root once.
+  * Panics without removing anything if any id is missing or repeated.
+  */
+ pub fn remove_many(&mut self, ids: &[u64]) -> Vec<MedRecord> {
+  for (position, id) in ids.iter().enumerate() {
+    assert!(self.get(*id).is_some(), "Invalid medical record!");
+    assert!(!ids[..position].contains(id), "Duplicate medical record!");
+  }
+  let removed = ids.iter().map(|id| {
+    let index = self.patient_record.iter().position(|record| record.id == *id).unwrap();
+    self.record_leaves.remove(index);
+    self.patient_record.remove(index)
+  }).collect();
+  self.records_root = merkle::root_of_leaves(&self.record_leaves);
+  removed
+ }
+
+ /**
+  * Appends a record with a fresh random salt, hashing only the new record.
+  * The salt mixes in the id since records added in one call share a seed.
+  */
+ fn push(&mut self, mut record: MedRecord) {
+  let mut seed = env::random_seed();
+  seed.extend_from_slice(&record.id.to_le_bytes());
+  record.salt = env::sha256_array(&seed);
+  self.record_leaves.push(merkle::record_leaf(&record));
+  self.patient_record.push(record);
+  self.next_record_id += 1;
+ }
+
+ /**
+  * Gets the Merkle root committing to every record, see `merkle`
   */
- pub fn remove(&mut self, index: u64) -> MedRecord {
-  let size: u64 = self.patient_record.len() as u64;
-  assert!(size > 0 && index < size, "Invalid medical record!");
-  self.patient_record.remove(index as usize)
+ pub fn records_root(&self) -> CryptoHash {
+  self.records_root
  }
 }
@@ -0,0 +1,65 @@
+use near_sdk::{near_bindgen, env, AccountId};
+
+use crate::{PatientRecord, PatientRecordExt};
+use crate::med_record::MedRecord;
+pub use med_block_types::provider::Provider;
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Mark an account as a verified healthcare provider
+
+    #[payable]
+    pub fn verify_provider(&mut self, provider_id: AccountId, name: String) {
+        self.assert_owner();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        self.providers.insert(&provider_id, &Provider { name, verified: true });
+
+        self.pay_for_storage(initial_storage, deposit);
+    }
+
+    // Withdraw a provider's verification
+
+    pub fn unverify_provider(&mut self, provider_id: AccountId) -> Provider {
+        self.assert_owner();
+        let mut provider = self.providers.get(&provider_id).expect("Provider not found!");
+        provider.verified = false;
+        self.providers.insert(&provider_id, &provider);
+        provider
+    }
+
+    // Get a provider's details
+
+    pub fn get_provider(&self, provider_id: AccountId) -> Option<Provider> {
+        self.providers.get(&provider_id)
+    }
+
+    // Check whether an account is currently a verified provider
+
+    pub fn is_verified_provider(&self, provider_id: AccountId) -> bool {
+        self.providers.get(&provider_id).map(|provider| provider.verified).unwrap_or(false)
+    }
+
+    // Whether a provider treated the patient on a record: the one that wrote it, or for
+    // a record the patient added, the verified provider registered under its hospital's name
+
+    pub(crate) fn treated_record(&self, provider_id: &AccountId, record: &MedRecord) -> bool {
+        match &record.author_id {
+            Some(author_id) => author_id == provider_id,
+            None => self.providers
+                .get(provider_id)
+                .map(|provider| provider.verified && provider.name.trim().eq_ignore_ascii_case(record.hospital_name.trim()))
+                .unwrap_or(false),
+        }
+    }
+
+    // Panics unless the caller is a verified provider
+
+    pub(crate) fn assert_verified_provider(&self) -> AccountId {
+        let signer = env::predecessor_account_id();
+        assert!(self.is_verified_provider(signer.clone()), "Only verified providers can call this method");
+        signer
+    }
+}
@@ -0,0 +1,278 @@
+use near_sdk::json_types::U64;
+use near_sdk::{near_bindgen, env, AccountId};
+
+use crate::{now_ms, PatientRecord, PatientRecordExt};
+pub use med_block_types::recovery::{RecoveryConfig, RecoveryRequest};
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Nominate the guardians that can recover the caller's records. Any recovery in
+    // progress is dropped, since its approvals were given under the old guardians.
+
+    #[payable]
+    pub fn set_recovery(&mut self, guardians: Vec<AccountId>, threshold: u32, delay: U64) {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        assert!(threshold > 0 && threshold as usize <= guardians.len(), "Invalid recovery threshold!");
+        assert!(!guardians.contains(&signer), "Cannot be your own guardian!");
+        let mut unique = guardians.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), guardians.len(), "Duplicate guardian!");
+
+        self.recovery_requests.remove(&signer);
+        self.recovery_configs.insert(&signer, &RecoveryConfig { guardians, threshold, delay });
+
+        self.settle_storage(initial_storage, deposit);
+    }
+
+    // Remove the caller's guardians along with any recovery in progress
+
+    pub fn remove_recovery(&mut self) -> Option<RecoveryConfig> {
+        let signer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+
+        self.recovery_requests.remove(&signer);
+        let removed = self.recovery_configs.remove(&signer);
+        if removed.is_some() {
+            self.refund_storage_cost(initial_storage);
+        }
+        removed
+    }
+
+    // Approve recovering a patient's records to a new account, called by a guardian.
+    // Each candidate account gathers its own approvals and a guardian backs one at a
+    // time, so approving another account moves their approval over. A candidate that
+    // drops below the threshold has to reach it again and wait out the delay anew.
+
+    #[payable]
+    pub fn approve_recovery(&mut self, patient_id: AccountId, new_account_id: AccountId) -> RecoveryRequest {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let config = self.recovery_configs.get(&patient_id).expect("Patient has no guardians!");
+        assert!(config.guardians.contains(&signer), "Only a guardian can approve a recovery");
+
+        let mut requests = self.recovery_requests.get(&patient_id).unwrap_or_default();
+        for request in requests.iter_mut().filter(|request| request.new_account_id != new_account_id) {
+            request.approvals.retain(|guardian| *guardian != signer);
+            if request.approvals.len() < config.threshold as usize {
+                request.ready_at = None;
+            }
+        }
+        requests.retain(|request| !request.approvals.is_empty());
+
+        let index = match requests.iter().position(|request| request.new_account_id == new_account_id) {
+            Some(index) => index,
+            None => {
+                requests.push(RecoveryRequest { new_account_id, approvals: vec![], ready_at: None });
+                requests.len() - 1
+            }
+        };
+        let request = &mut requests[index];
+        if !request.approvals.contains(&signer) {
+            request.approvals.push(signer);
+        }
+        if request.ready_at.is_none() && request.approvals.len() >= config.threshold as usize {
+            request.ready_at = Some(U64(now_ms() + config.delay.0));
+        }
+        let request = request.clone();
+        self.recovery_requests.insert(&patient_id, &requests);
+
+        self.settle_storage(initial_storage, deposit);
+        request
+    }
+
+    // Stop every recovery of the caller's records
+
+    pub fn veto_recovery(&mut self) -> Vec<RecoveryRequest> {
+        let signer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+
+        let vetoed = self.recovery_requests.remove(&signer);
+        if vetoed.is_some() {
+            self.refund_storage_cost(initial_storage);
+        }
+        vetoed.unwrap_or_default()
+    }
+
+    // Move a patient's records to a recovered account once the delay has passed.
+    // The guardians carry over so the new account stays protected. Anyone may call
+    // this, so the storage freed by the recovery goes to the patient, not the caller.
+
+    #[payable]
+    pub fn finalize_recovery(&mut self, patient_id: AccountId, new_account_id: AccountId) {
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let request = self.recovery_requests
+            .get(&patient_id)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|request| request.new_account_id == new_account_id)
+            .expect("No recovery in progress!");
+        let ready_at = request.ready_at.expect("Not enough guardians have approved!");
+        assert!(now_ms() >= ready_at.0, "Recovery is still in its veto period!");
+
+        self.recovery_requests.remove(&patient_id);
+        self.transfers.remove(&patient_id);
+        self.refund_storage_cost_to(&patient_id, initial_storage);
+
+        // Re-keying can grow storage, which the caller pays for
+        let move_storage = env::storage_usage();
+        self.move_patient(&patient_id, &request.new_account_id);
+
+        if let Some(config) = self.recovery_configs.remove(&patient_id) {
+            if !config.guardians.contains(&request.new_account_id) {
+                self.recovery_configs.insert(&request.new_account_id, &config);
+            }
+        }
+
+        if env::storage_usage() >= move_storage {
+            self.pay_for_storage(move_storage, deposit);
+        } else {
+            self.refund_storage_cost_to(&request.new_account_id, move_storage);
+            if deposit > 0 {
+                self.return_excess_tokens(deposit);
+            }
+        }
+    }
+
+    // Get a patient's guardians
+
+    pub fn get_recovery_config(&self, patient_id: AccountId) -> Option<RecoveryConfig> {
+        self.recovery_configs.get(&patient_id)
+    }
+
+    // Get the recoveries in progress for a patient, one per candidate account
+
+    pub fn get_recovery_requests(&self, patient_id: AccountId) -> Vec<RecoveryRequest> {
+        self.recovery_requests.get(&patient_id).unwrap_or_default()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    const DELAY: u64 = 86_400_000;
+
+    fn get_context(predecessor: &str, timestamp_ms: u64) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build()
+    }
+
+    // Bob has one record and two of his three guardians have approved a recovery
+    fn setup() -> PatientRecord {
+        testing_env!(get_context("bob.near", 1000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.add_record(String::from("Malaria"), String::from("CGH"), String::from("Coartem"),
+            String::from("01/05/2022"), String::from("03/05/2022"), String::from("None"), 1000);
+        let guardians = ["mum.near", "dad.near", "hospital.near"].iter().map(|id| id.parse().unwrap()).collect();
+        contract.set_recovery(guardians, 2, U64(DELAY));
+
+        testing_env!(get_context("mum.near", 1000));
+        let request = contract.approve_recovery("bob.near".parse().unwrap(), "bob-new.near".parse().unwrap());
+        assert!(request.ready_at.is_none());
+
+        testing_env!(get_context("dad.near", 1000));
+        let request = contract.approve_recovery("bob.near".parse().unwrap(), "bob-new.near".parse().unwrap());
+        assert_eq!(Some(U64(1000 + DELAY)), request.ready_at);
+        contract
+    }
+
+    #[test]
+    fn recovery_after_delay_moves_records() {
+        let mut contract = setup();
+
+        testing_env!(get_context("bob-new.near", 1000 + DELAY));
+        contract.finalize_recovery("bob.near".parse().unwrap(), "bob-new.near".parse().unwrap());
+        assert_eq!(1, contract.read_record(0, 10).unwrap().len());
+        assert_eq!(2, contract.get_recovery_config("bob-new.near".parse().unwrap()).unwrap().threshold);
+    }
+
+    #[test]
+    #[should_panic(expected = "Recovery is still in its veto period!")]
+    fn recovery_waits_for_delay() {
+        let mut contract = setup();
+
+        testing_env!(get_context("bob-new.near", DELAY));
+        contract.finalize_recovery("bob.near".parse().unwrap(), "bob-new.near".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "No recovery in progress!")]
+    fn patient_can_veto() {
+        let mut contract = setup();
+
+        testing_env!(get_context("bob.near", 2000));
+        assert_eq!(1, contract.veto_recovery().len());
+
+        testing_env!(get_context("bob-new.near", 1000 + DELAY));
+        contract.finalize_recovery("bob.near".parse().unwrap(), "bob-new.near".parse().unwrap());
+    }
+
+    #[test]
+    fn one_guardian_cannot_block_recovery() {
+        testing_env!(get_context("bob.near", 1000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        let guardians = ["mum.near", "dad.near", "hospital.near"].iter().map(|id| id.parse().unwrap()).collect();
+        contract.set_recovery(guardians, 2, U64(DELAY));
+
+        testing_env!(get_context("hospital.near", 1000));
+        contract.approve_recovery("bob.near".parse().unwrap(), "mallory.near".parse().unwrap());
+
+        testing_env!(get_context("mum.near", 1000));
+        contract.approve_recovery("bob.near".parse().unwrap(), "bob-new.near".parse().unwrap());
+        testing_env!(get_context("dad.near", 1000));
+        let request = contract.approve_recovery("bob.near".parse().unwrap(), "bob-new.near".parse().unwrap());
+        assert_eq!(Some(U64(1000 + DELAY)), request.ready_at);
+        assert_eq!(2, contract.get_recovery_requests("bob.near".parse().unwrap()).len());
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough guardians have approved!")]
+    fn moving_an_approval_resets_the_delay() {
+        let mut contract = setup();
+
+        testing_env!(get_context("dad.near", 2000));
+        let request = contract.approve_recovery("bob.near".parse().unwrap(), "bob-other.near".parse().unwrap());
+        assert_eq!(vec!["dad.near".parse::<AccountId>().unwrap()], request.approvals);
+
+        testing_env!(get_context("bob-new.near", 1000 + DELAY));
+        contract.finalize_recovery("bob.near".parse().unwrap(), "bob-new.near".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "No recovery in progress!")]
+    fn new_guardians_drop_old_approvals() {
+        let mut contract = setup();
+
+        testing_env!(get_context("bob.near", 2000));
+        let guardians = ["mum.near", "dad.near", "hospital.near"].iter().map(|id| id.parse().unwrap()).collect();
+        contract.set_recovery(guardians, 3, U64(DELAY));
+        assert!(contract.get_recovery_requests("bob.near".parse().unwrap()).is_empty());
+
+        testing_env!(get_context("bob-new.near", 1000 + DELAY));
+        contract.finalize_recovery("bob.near".parse().unwrap(), "bob-new.near".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only a guardian can approve a recovery")]
+    fn stranger_cannot_approve() {
+        let mut contract = setup();
+
+        testing_env!(get_context("mallory.near", 2000));
+        contract.approve_recovery("bob.near".parse().unwrap(), "mallory.near".parse().unwrap());
+    }
+}
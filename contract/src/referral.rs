@@ -0,0 +1,192 @@
+use near_sdk::{near_bindgen, env, AccountId};
+
+use crate::{PatientRecord, PatientRecordExt};
+pub use med_block_types::referral::{Referral, ReferralStatus};
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Refer a patient to another verified provider, called by a verified provider that
+    // wrote the referred records or holds a grant covering them
+
+    #[payable]
+    pub fn create_referral(&mut self, patient_id: AccountId, to_provider_id: AccountId,
+        reason: String, record_ids: Vec<u64>) -> u64 {
+        let from_provider_id = self.assert_verified_provider();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        assert!(self.is_verified_provider(to_provider_id.clone()), "Can only refer to a verified provider!");
+        assert_ne!(from_provider_id, to_provider_id, "Cannot refer to yourself!");
+        let patient = self.patients.get(&patient_id).expect("Patient not found!");
+        let grant = self.grant_for(&patient_id, &from_provider_id);
+        for record_id in &record_ids {
+            let record = patient.get(*record_id).expect("Invalid medical record!");
+            let authored = record.author_id.as_ref() == Some(&from_provider_id);
+            let granted = grant.as_ref().map(|grant| grant.covers(*record_id)).unwrap_or(false);
+            assert!(authored || granted, "No access to this record!");
+        }
+
+        let id = self.next_referral_id;
+        self.referrals.insert(&id, &Referral {
+            id,
+            patient_id: patient_id.clone(),
+            from_provider_id: from_provider_id.clone(),
+            to_provider_id: to_provider_id.clone(),
+            reason,
+            record_ids,
+            status: ReferralStatus::Pending,
+        });
+        self.next_referral_id += 1;
+
+        for account_id in [&patient_id, &from_provider_id, &to_provider_id] {
+            let mut ids = self.referrals_by_account.get(account_id).unwrap_or_default();
+            ids.push(id);
+            self.referrals_by_account.insert(account_id, &ids);
+        }
+
+        self.pay_for_storage(initial_storage, deposit);
+        id
+    }
+
+    // Accept a referral, giving the receiving provider read access to the referred records
+
+    #[payable]
+    pub fn accept_referral(&mut self, referral_id: u64) -> Referral {
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let mut referral = self.pending_referral_for_patient(referral_id);
+        referral.status = ReferralStatus::Accepted;
+        self.referrals.insert(&referral_id, &referral);
+
+        self.add_grant(&referral.patient_id, referral.to_provider_id.clone(), Some(referral.record_ids.clone()));
+
+        self.pay_for_storage(initial_storage, deposit);
+        referral
+    }
+
+    // Decline a referral, no access is granted
+
+    pub fn decline_referral(&mut self, referral_id: u64) -> Referral {
+        let mut referral = self.pending_referral_for_patient(referral_id);
+        referral.status = ReferralStatus::Declined;
+        self.referrals.insert(&referral_id, &referral);
+        referral
+    }
+
+    // Get a referral, only the patient and the two providers on it can read it
+
+    pub fn get_referral(&self, referral_id: u64) -> Option<Referral> {
+        let signer = env::predecessor_account_id();
+
+        let referral = self.referrals.get(&referral_id)?;
+        assert!(
+            signer == referral.patient_id || signer == referral.from_provider_id || signer == referral.to_provider_id,
+            "Not a party to this referral!"
+        );
+        Some(referral)
+    }
+
+    // Get a paginated list of the referrals the caller is a party to
+
+    pub fn read_referrals(&self, start: u32, limit: u32) -> Vec<Referral> {
+        let signer = env::predecessor_account_id();
+
+        self.referrals_by_account
+            .get(&signer)
+            .unwrap_or_default()
+            .iter()
+            .skip(start as usize)
+            .take(limit as usize)
+            .filter_map(|id| self.referrals.get(id))
+            .collect()
+    }
+
+    // Load a pending referral, panicking unless the caller is its patient
+
+    fn pending_referral_for_patient(&self, referral_id: u64) -> Referral {
+        let referral = self.referrals.get(&referral_id).expect("Referral not found!");
+        assert_eq!(env::predecessor_account_id(), referral.patient_id, "Only the patient can answer this referral");
+        assert_eq!(referral.status, ReferralStatus::Pending, "Referral has already been answered!");
+        referral
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(predecessor: &str) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .build()
+    }
+
+    // Two verified providers and bob with two records, the second shared with the GP
+    // and referred on to the specialist
+    fn setup() -> (PatientRecord, u64) {
+        testing_env!(get_context("alice.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.verify_provider("gp.near".parse().unwrap(), String::from("CGH"));
+        contract.verify_provider("specialist.near".parse().unwrap(), String::from("KNH"));
+
+        testing_env!(get_context("bob.near"));
+        for diagnosis in ["Flu", "Arrhythmia"] {
+            contract.add_record(String::from(diagnosis), String::from("CGH"), String::from("Panadol"),
+                String::from("01/05/2022"), String::from("03/05/2022"), String::from("None"), 1000);
+        }
+        contract.grant_access("gp.near".parse().unwrap(), Some(vec![1]));
+
+        testing_env!(get_context("gp.near"));
+        let id = contract.create_referral("bob.near".parse().unwrap(), "specialist.near".parse().unwrap(),
+            String::from("Cardiology review"), vec![1]);
+        (contract, id)
+    }
+
+    #[test]
+    fn accepted_referral_grants_scoped_access() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("bob.near"));
+        assert_eq!(1, contract.read_referrals(0, 10).len());
+        contract.accept_referral(id);
+
+        testing_env!(get_context("specialist.near"));
+        let records = contract.read_shared_records("bob.near".parse().unwrap(), 0, 10);
+        assert_eq!(1, records.len());
+        assert_eq!("Arrhythmia", records[0].diagnosis);
+    }
+
+    #[test]
+    #[should_panic(expected = "No access to this patient's records!")]
+    fn pending_referral_grants_nothing() {
+        let (contract, _) = setup();
+
+        testing_env!(get_context("specialist.near"));
+        contract.read_shared_records("bob.near".parse().unwrap(), 0, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "No access to this record!")]
+    fn only_shared_records_can_be_referred() {
+        let (mut contract, _) = setup();
+
+        testing_env!(get_context("gp.near"));
+        contract.create_referral("bob.near".parse().unwrap(), "specialist.near".parse().unwrap(),
+            String::from("Flu follow-up"), vec![0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the patient can answer this referral")]
+    fn provider_cannot_accept_for_patient() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("specialist.near"));
+        contract.accept_referral(id);
+    }
+}
@@ -0,0 +1,319 @@
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{near_bindgen, env, AccountId, Promise};
+
+use crate::{now_ms, PatientRecord, PatientRecordExt};
+pub use med_block_types::research::{Consent, DeidentifiedRecord, Funder, FundingTerms, Institution, Program};
+
+// A new random pseudonym for a patient joining a program. The block's random seed is
+// shared by every call in the block, so the program and patient only keep it unique.
+fn new_participant(program_id: u64, patient_id: &AccountId) -> String {
+    let mut seed = env::random_seed();
+    seed.extend_from_slice(format!("{}:{}", program_id, patient_id).as_bytes());
+    env::sha256(&seed)[..16].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Approve an account to run research programs
+
+    #[payable]
+    pub fn approve_institution(&mut self, institution_id: AccountId, name: String) {
+        self.assert_owner();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        self.institutions.insert(&institution_id, &Institution { name, approved: true });
+
+        self.pay_for_storage(initial_storage, deposit);
+    }
+
+    // Withdraw an institution's approval, closing it off from exporting data
+
+    pub fn unapprove_institution(&mut self, institution_id: AccountId) -> Institution {
+        self.assert_owner();
+        let mut institution = self.institutions.get(&institution_id).expect("Institution not found!");
+        institution.approved = false;
+        self.institutions.insert(&institution_id, &institution);
+        institution
+    }
+
+    // Get an institution's details
+
+    pub fn get_institution(&self, institution_id: AccountId) -> Option<Institution> {
+        self.institutions.get(&institution_id)
+    }
+
+    // Register a research program, called by an approved institution
+
+    #[payable]
+    pub fn register_program(&mut self, name: String, purpose: String) -> u64 {
+        let signer = self.assert_approved_institution();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let id = self.next_program_id;
+        self.programs.insert(&id, &Program { id, institution_id: signer, name, purpose, open: true, funding_terms: None, funders: vec![], funding_rounds: 0 });
+        self.next_program_id += 1;
+
+        self.pay_for_storage(initial_storage, deposit);
+        id
+    }
+
+    // Stop a program taking new participants, called by its institution
+
+    pub fn close_program(&mut self, program_id: u64) -> Program {
+        let mut program = self.programs.get(&program_id).expect("Program not found!");
+        assert_eq!(env::predecessor_account_id(), program.institution_id, "Only the program's institution can close it");
+        program.open = false;
+        self.programs.insert(&program_id, &program);
+        program
+    }
+
+    // Set what insurers pay for access to a program's data, called by its institution.
+    // Access already paid for keeps its end date.
+
+    pub fn set_funding_terms(&mut self, program_id: u64, min_amount: U128, access_period: U64) -> Program {
+        let mut program = self.programs.get(&program_id).expect("Program not found!");
+        assert_eq!(env::predecessor_account_id(), program.institution_id, "Only the program's institution can set its terms");
+        assert!(min_amount.0 > 0 && access_period.0 > 0, "Funding terms would give access away!");
+        program.funding_terms = Some(FundingTerms { min_amount, access_period });
+        self.programs.insert(&program_id, &program);
+        program
+    }
+
+    // Get a research program
+
+    pub fn get_program(&self, program_id: u64) -> Option<Program> {
+        self.programs.get(&program_id)
+    }
+
+    // Consent to share the caller's records with a program until the given time.
+    // The purpose must match the program's so patients agree to what they were shown.
+
+    #[payable]
+    pub fn opt_in(&mut self, program_id: u64, purpose: String, expires_at: U64) -> Consent {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let program = self.programs.get(&program_id).expect("Program not found!");
+        assert!(program.open, "Program is closed!");
+        assert_eq!(program.purpose, purpose, "Purpose does not match the program!");
+        assert!(expires_at.0 > now_ms(), "Consent would already have expired!");
+
+        let mut consents = self.consents.get(&program_id).unwrap_or_default();
+        let consent = match consents.iter_mut().find(|consent| consent.patient_id == signer) {
+            // Renewing keeps the pseudonym so earlier exports still link up. What was earned
+            // is counted first, so rounds paid while the consent had lapsed stay uncounted.
+            Some(existing) => {
+                self.count_rounds(program_id, existing);
+                existing.purpose = purpose;
+                existing.expires_at = expires_at;
+                existing.clone()
+            }
+            None => {
+                let participant = new_participant(program_id, &signer);
+                let consent = Consent { patient_id: signer, purpose, expires_at, participant,
+                    earned: U128(0), rounds_counted: program.funding_rounds };
+                consents.push(consent.clone());
+                let mut program_ids = self.consents_by_patient.get(&consent.patient_id).unwrap_or_default();
+                program_ids.push(program_id);
+                self.consents_by_patient.insert(&consent.patient_id, &program_ids);
+                consent
+            }
+        };
+        self.consents.insert(&program_id, &consents);
+
+        self.settle_storage(initial_storage, deposit);
+        consent
+    }
+
+    // Withdraw the caller's consent to a program, paying out what it earned
+
+    pub fn opt_out(&mut self, program_id: u64) -> Option<Consent> {
+        let signer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+
+        let mut consents = self.consents.get(&program_id).unwrap_or_default();
+        let index = consents.iter().position(|consent| consent.patient_id == signer)?;
+        let mut removed = consents.remove(index);
+        self.count_rounds(program_id, &mut removed);
+        if consents.is_empty() {
+            self.consents.remove(&program_id);
+        } else {
+            self.consents.insert(&program_id, &consents);
+        }
+        let mut program_ids = self.consents_by_patient.get(&signer).unwrap_or_default();
+        program_ids.retain(|id| *id != program_id);
+        if program_ids.is_empty() {
+            self.consents_by_patient.remove(&signer);
+        } else {
+            self.consents_by_patient.insert(&signer, &program_ids);
+        }
+
+        self.refund_storage_cost(initial_storage);
+        if removed.earned.0 > 0 {
+            Promise::new(signer).transfer(removed.earned.0);
+        }
+        Some(removed)
+    }
+
+    // Get a patient's consent to a program, if it is still in force
+
+    pub fn get_consent(&self, program_id: u64, patient_id: AccountId) -> Option<Consent> {
+        self.active_consents(program_id).into_iter().find(|consent| consent.patient_id == patient_id)
+    }
+
+    // Export de-identified records from a paginated list of the patients currently
+    // consenting to a program, called by the program's institution or a funder.
+    // This has to be a call to check who is asking, so the records end up in the
+    // public transaction outcome. Pseudonyms keep them from being linked to
+    // account ids through the contract's methods, but the consents in contract
+    // state are public too, so this is no stronger than the chain's own privacy.
+
+    pub fn export_research_data(&self, program_id: u64, start: u32, limit: u32) -> Vec<DeidentifiedRecord> {
+        let signer = env::predecessor_account_id();
+        let program = self.programs.get(&program_id).expect("Program not found!");
+        let now = now_ms();
+        if !program.funders.iter().any(|funder| funder.account_id == signer && funder.access_until.0 > now) {
+            assert_eq!(self.assert_approved_institution(), program.institution_id, "Only the program's institution can export its data");
+        }
+
+        self.active_consents(program_id)
+            .into_iter()
+            .skip(start as usize)
+            .take(limit as usize)
+            .flat_map(|consent| {
+                let participant = consent.participant;
+                self.patients
+                    .get(&consent.patient_id)
+                    .map(|patient| patient.show(0, u32::MAX))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(move |record| DeidentifiedRecord::new(participant.clone(), record))
+            })
+            .collect()
+    }
+
+    // Consents to a program that haven't expired
+
+    pub(crate) fn active_consents(&self, program_id: u64) -> Vec<Consent> {
+        let now = now_ms();
+        self.consents
+            .get(&program_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|consent| consent.expires_at.0 > now)
+            .collect()
+    }
+
+    // Panics unless the caller is an approved institution
+
+    fn assert_approved_institution(&self) -> AccountId {
+        let signer = env::predecessor_account_id();
+        let approved = self.institutions.get(&signer).map(|institution| institution.approved).unwrap_or(false);
+        assert!(approved, "Only approved institutions can call this method");
+        signer
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    const PURPOSE: &str = "Malaria treatment outcomes";
+
+    fn get_context(predecessor: &str, timestamp_ms: u64) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build()
+    }
+
+    // A program at uni.near with bob opted in until 5000 and carol with records but no consent
+    fn setup() -> (PatientRecord, u64) {
+        testing_env!(get_context("alice.near", 1000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.approve_institution("uni.near".parse().unwrap(), String::from("University"));
+
+        testing_env!(get_context("uni.near", 1000));
+        let id = contract.register_program(String::from("Malaria study"), String::from(PURPOSE));
+
+        for patient in ["bob.near", "carol.near"] {
+            testing_env!(get_context(patient, 1000));
+            contract.add_record(String::from("Malaria"), String::from("CGH"), String::from("Coartem"),
+                String::from("28/02/2022"), String::from("03/03/2022"), String::from("None"), 1000);
+        }
+        testing_env!(get_context("bob.near", 1000));
+        contract.opt_in(id, String::from(PURPOSE), U64(5000));
+        (contract, id)
+    }
+
+    #[test]
+    fn export_only_has_consenting_patients_without_identifiers() {
+        let (contract, id) = setup();
+
+        testing_env!(get_context("uni.near", 2000));
+        let records = contract.export_research_data(id, 0, 10);
+        assert_eq!(1, records.len());
+        assert_eq!(32, records[0].participant.len());
+        assert!(!records[0].participant.contains("bob"));
+        assert_eq!(Some(2022), records[0].admission_year);
+        assert_eq!(Some(3), records[0].length_of_stay_days);
+        assert!(!near_sdk::serde_json::to_string(&records[0]).unwrap().contains("CGH"));
+    }
+
+    #[test]
+    fn pseudonym_is_random_and_kept_out_of_consents() {
+        let (mut contract, id) = setup();
+        testing_env!(get_context("uni.near", 2000));
+        let participant = contract.export_research_data(id, 0, 10)[0].participant.clone();
+        let hash = env::sha256(format!("{}:bob.near", id).as_bytes());
+        let guess: String = hash[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_ne!(guess, participant);
+
+        testing_env!(get_context("bob.near", 3000));
+        let renewed = contract.opt_in(id, String::from(PURPOSE), U64(9000));
+        assert!(!near_sdk::serde_json::to_string(&renewed).unwrap().contains(&participant));
+        testing_env!(get_context("uni.near", 3000));
+        assert_eq!(participant, contract.export_research_data(id, 0, 10)[0].participant);
+    }
+
+    #[test]
+    fn expired_or_withdrawn_consent_is_left_out() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("uni.near", 5000));
+        assert!(contract.export_research_data(id, 0, 10).is_empty());
+
+        testing_env!(get_context("bob.near", 2000));
+        assert!(contract.opt_out(id).is_some());
+        assert!(contract.get_consent(id, "bob.near".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Purpose does not match the program!")]
+    fn consent_must_name_the_purpose() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("carol.near", 2000));
+        contract.opt_in(id, String::from("Anything"), U64(5000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the program's institution can export its data")]
+    fn other_institutions_cannot_export() {
+        let (mut contract, id) = setup();
+
+        testing_env!(get_context("alice.near", 2000));
+        contract.approve_institution("lab.near".parse().unwrap(), String::from("Lab"));
+
+        testing_env!(get_context("lab.near", 2000));
+        contract.export_research_data(id, 0, 10);
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::{near_bindgen, env, AccountId};
+
+use crate::{fhir, PatientRecord, PatientRecordExt};
+use crate::med_record::MedRecord;
+use crate::patient::Patient;
+pub use med_block_types::stats::{HospitalTotal, HospitalTotalView, RecordStatsView};
+
+// Totals kept up to date as a patient's records change, so reading them never loads the records
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct RecordStats {
+    pub count: u64,
+    // Records per admission date as yyyy-mm-dd, so the first and last survive deletions
+    pub admissions: BTreeMap<String, u64>,
+    pub storage_used: u64,
+    pub hospitals: BTreeMap<String, HospitalTotal>,
+}
+
+impl RecordStats {
+    fn add(&mut self, record: &MedRecord) {
+        self.count += 1;
+        if let Some(date) = fhir::fhir_date(&record.date_of_admission) {
+            *self.admissions.entry(date).or_default() += 1;
+        }
+        let total = self.hospitals.entry(record.hospital_name.clone()).or_default();
+        total.records += 1;
+        total.billed = total.billed.saturating_add(record.price as u64);
+    }
+
+    fn remove(&mut self, record: &MedRecord) {
+        self.count = self.count.saturating_sub(1);
+        if let Some(date) = fhir::fhir_date(&record.date_of_admission) {
+            decrement(&mut self.admissions, &date, |count| {
+                *count = count.saturating_sub(1);
+                *count == 0
+            });
+        }
+        decrement(&mut self.hospitals, &record.hospital_name, |total| {
+            total.records = total.records.saturating_sub(1);
+            total.billed = total.billed.saturating_sub(record.price as u64);
+            total.records == 0
+        });
+    }
+}
+
+// Update an entry in place, dropping it once the update reports it is empty
+fn decrement<V>(map: &mut BTreeMap<String, V>, key: &str, update: impl FnOnce(&mut V) -> bool) {
+    if let Some(value) = map.get_mut(key) {
+        if update(value) {
+            map.remove(key);
+        }
+    }
+}
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Get how many records a patient has, for paginating through them
+
+    pub fn get_record_count(&self, patient_id: AccountId) -> u64 {
+        self.record_stats.get(&patient_id).map(|stats| stats.count).unwrap_or(0)
+    }
+
+    // View the totals for a patient's records once the patient has made a summary public,
+    // without the amounts billed
+
+    pub fn view_record_stats(&self, patient_id: AccountId) -> RecordStatsView {
+        assert!(self.has_public_summary(patient_id.clone()), "Patient has not published a summary!");
+        self.record_stats_view(&patient_id, false)
+    }
+
+    // Read the totals for a patient's records as the patient or an account they granted
+
+    pub fn read_record_stats(&self, patient_id: AccountId) -> RecordStatsView {
+        self.caller_grant(&patient_id);
+        self.record_stats_view(&patient_id, true)
+    }
+
+    fn record_stats_view(&self, patient_id: &AccountId, with_billed: bool) -> RecordStatsView {
+        let stats = self.record_stats.get(patient_id).unwrap_or_default();
+        RecordStatsView {
+            count: stats.count,
+            first_admission: stats.admissions.keys().next().cloned(),
+            last_admission: stats.admissions.keys().next_back().cloned(),
+            storage_used: stats.storage_used,
+            hospitals: stats.hospitals
+                .into_iter()
+                .map(|(name, total)| (name, HospitalTotalView {
+                    records: total.records,
+                    billed: with_billed.then_some(total.billed),
+                }))
+                .collect(),
+        }
+    }
+
+    // Write a patient back after records were added or removed, keeping their totals
+    // and the contract wide visit counts in step
+
+    pub(crate) fn save_patient(&mut self, patient_id: &AccountId, patient: &Patient, added: &[u64], removed: &[MedRecord]) {
+        let mut stats = self.record_stats.get(patient_id).unwrap_or_default();
+
+        let initial_storage = env::storage_usage();
+        self.patients.insert(patient_id, patient);
+        stats.storage_used = (stats.storage_used + env::storage_usage()).saturating_sub(initial_storage);
+
+        for id in added {
+            if let Some(record) = patient.get(*id) {
+                stats.add(record);
+            }
+        }
+        for record in removed {
+            stats.remove(record);
+        }
+        self.record_stats.insert(patient_id, &stats);
+        self.count_visits(patient, added, removed);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(predecessor: &str) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .build()
+    }
+
+    fn add(contract: &mut PatientRecord, hospital_name: &str, date_of_admission: &str, price: u64) {
+        contract.add_record(String::from("Malaria"), String::from(hospital_name), String::from("Coartem"),
+            String::from(date_of_admission), String::from("30/06/2022"), String::from("None"), price);
+    }
+
+    #[test]
+    fn stats_follow_adds_and_deletes() {
+        testing_env!(get_context("bob.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        add(&mut contract, "CGH", "03/05/2022", 1000);
+        add(&mut contract, "KNH", "01/05/2022", 2500);
+        add(&mut contract, "CGH", "20/06/2022", 500);
+        let bob: AccountId = "bob.near".parse().unwrap();
+        let full_size = contract.read_record_stats(bob.clone()).storage_used;
+
+        contract.delete_record(1);
+        let stats = contract.read_record_stats(bob.clone());
+        assert_eq!(2, contract.get_record_count(bob.clone()));
+        assert_eq!(Some(String::from("2022-05-03")), stats.first_admission);
+        assert_eq!(Some(String::from("2022-06-20")), stats.last_admission);
+        assert_eq!(HospitalTotalView { records: 2, billed: Some(1500) }, stats.hospitals["CGH"]);
+        assert!(!stats.hospitals.contains_key("KNH"));
+        assert!(stats.storage_used > 0 && stats.storage_used < full_size);
+
+        contract.delete_records(vec![0, 2]);
+        let stats = contract.read_record_stats(bob);
+        assert_eq!(0, stats.count);
+        assert!(stats.first_admission.is_none() && stats.hospitals.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "No access to this patient's records!")]
+    fn stats_need_access() {
+        testing_env!(get_context("bob.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        add(&mut contract, "CGH", "03/05/2022", 1000);
+
+        testing_env!(get_context("mallory.near"));
+        contract.read_record_stats("bob.near".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Patient has not published a summary!")]
+    fn free_stats_need_a_public_summary() {
+        testing_env!(get_context("bob.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        add(&mut contract, "CGH", "03/05/2022", 1000);
+        contract.set_public_summary(true);
+        let stats = contract.view_record_stats("bob.near".parse().unwrap());
+        assert_eq!(1, stats.count);
+        assert_eq!(HospitalTotalView { records: 1, billed: None }, stats.hospitals["CGH"]);
+
+        contract.set_public_summary(false);
+        contract.view_record_stats("bob.near".parse().unwrap());
+    }
+}
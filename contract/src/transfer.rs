@@ -0,0 +1,307 @@
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::{near_bindgen, env, AccountId};
+
+use crate::{PatientRecord, PatientRecordExt};
+
+// Move the given ids from one account's entry in an index to another's
+fn reindex(index: &mut LookupMap<AccountId, Vec<u64>>, old_account_id: &AccountId, new_account_id: &AccountId, moved: &[u64]) {
+    if moved.is_empty() {
+        return;
+    }
+    let mut old_ids = index.get(old_account_id).unwrap_or_default();
+    old_ids.retain(|id| !moved.contains(id));
+    if old_ids.is_empty() {
+        index.remove(old_account_id);
+    } else {
+        index.insert(old_account_id, &old_ids);
+    }
+
+    let mut new_ids = index.get(new_account_id).unwrap_or_default();
+    for id in moved {
+        if !new_ids.contains(id) {
+            new_ids.push(*id);
+        }
+    }
+    index.insert(new_account_id, &new_ids);
+}
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Offer the caller's records to a new account, which must accept to complete the move
+
+    #[payable]
+    pub fn initiate_transfer(&mut self, new_account_id: AccountId) {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        assert!(self.patients.contains_key(&signer), "Patient not found!");
+        assert_ne!(signer, new_account_id, "Cannot transfer to the same account!");
+
+        self.transfers.insert(&signer, &new_account_id);
+
+        self.settle_storage(initial_storage, deposit);
+    }
+
+    // Withdraw a transfer that hasn't been accepted yet
+
+    pub fn cancel_transfer(&mut self) -> Option<AccountId> {
+        let signer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+
+        let cancelled = self.transfers.remove(&signer);
+        if cancelled.is_some() {
+            self.refund_storage_cost(initial_storage);
+        }
+        cancelled
+    }
+
+    // Get the account a patient has offered their records to
+
+    pub fn get_pending_transfer(&self, patient_id: AccountId) -> Option<AccountId> {
+        self.transfers.get(&patient_id)
+    }
+
+    // Take over the records offered to the caller. The records, storage credit, grants,
+    // public summary, linked policy, bills, claims, referrals, appointments, certificates,
+    // research consents and unclaimed compensation all move to the caller in one step.
+
+    #[payable]
+    pub fn accept_transfer(&mut self, old_account_id: AccountId) {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let new_account_id = self.transfers.get(&old_account_id).expect("No pending transfer!");
+        assert_eq!(signer, new_account_id, "Transfer was offered to another account!");
+
+        self.transfers.remove(&old_account_id);
+        self.move_patient(&old_account_id, &new_account_id);
+
+        self.settle_storage(initial_storage, deposit);
+    }
+
+    // Re-key everything a patient owns from one account to another
+
+    pub(crate) fn move_patient(&mut self, old_account_id: &AccountId, new_account_id: &AccountId) {
+        assert!(!self.patients.contains_key(new_account_id), "Account already has records!");
+
+        let patient = self.patients.remove(old_account_id).expect("Patient not found!");
+
+        // Bills, storage payers, certificate approvals and claims are keyed by patient and record,
+        // so follow each record across
+        for record in patient.show(0, u32::MAX) {
+            if let Some(mut bill) = self.bills.remove(&(old_account_id.clone(), record.id)) {
+                bill.patient_id = new_account_id.clone();
+                self.bills.insert(&(new_account_id.clone(), record.id), &bill);
+            }
+            if let Some(payer) = self.record_payers.remove(&(old_account_id.clone(), record.id)) {
+                self.record_payers.insert(&(new_account_id.clone(), record.id), &payer);
+            }
+            if let Some(approval) = self.certificate_approvals.remove(&(old_account_id.clone(), record.id)) {
+                self.certificate_approvals.insert(&(new_account_id.clone(), record.id), &approval);
+            }
+            if let Some(claim_id) = self.claims_by_record.remove(&(old_account_id.clone(), record.id)) {
+                self.claims_by_record.insert(&(new_account_id.clone(), record.id), &claim_id);
+            }
+        }
+        let initial_storage = env::storage_usage();
+        self.patients.insert(new_account_id, &patient);
+        // The account id is part of the key so the size can change
+        let storage_used = env::storage_usage().saturating_sub(initial_storage);
+        if let Some(mut stats) = self.record_stats.remove(old_account_id) {
+            stats.storage_used = storage_used;
+            self.record_stats.insert(new_account_id, &stats);
+        }
+
+        if let Some(old_credit) = self.storage_credits.remove(old_account_id) {
+            let mut credit = self.storage_credits.get(new_account_id).unwrap_or_default();
+            // Credit is paid back at what it was bought for, so only like credit merges
+            if credit.purchase.is_none() {
+                credit.purchase = old_credit.purchase.clone();
+            }
+            assert!(credit.purchase == old_credit.purchase, "Account has storage credit on other terms!");
+            credit.available += old_credit.available;
+            credit.used += old_credit.used;
+            self.storage_credits.insert(new_account_id, &credit);
+        }
+
+        if let Some(grants) = self.grants.remove(old_account_id) {
+            let grants: Vec<_> = grants.into_iter().filter(|grant| grant.grantee_id != *new_account_id).collect();
+            if !grants.is_empty() {
+                self.grants.insert(new_account_id, &grants);
+            }
+        }
+
+        if self.public_summaries.remove(old_account_id).is_some() {
+            self.public_summaries.insert(new_account_id, &true);
+        }
+
+        if let Some(policy) = self.policies.remove(old_account_id) {
+            self.policies.insert(new_account_id, &policy);
+        }
+
+        self.move_certificates(old_account_id, new_account_id);
+
+        // Claims, referrals and appointments name the patient and are indexed under every
+        // party, so only those the old account is the patient on move
+        let claim_ids: Vec<u64> = self.claims_by_account.get(old_account_id).unwrap_or_default().into_iter()
+            .filter_map(|id| {
+                let mut claim = self.claims.get(&id).filter(|claim| claim.patient_id == *old_account_id)?;
+                claim.patient_id = new_account_id.clone();
+                self.claims.insert(&id, &claim);
+                Some(id)
+            })
+            .collect();
+        reindex(&mut self.claims_by_account, old_account_id, new_account_id, &claim_ids);
+
+        let referral_ids: Vec<u64> = self.referrals_by_account.get(old_account_id).unwrap_or_default().into_iter()
+            .filter_map(|id| {
+                let mut referral = self.referrals.get(&id).filter(|referral| referral.patient_id == *old_account_id)?;
+                referral.patient_id = new_account_id.clone();
+                self.referrals.insert(&id, &referral);
+                Some(id)
+            })
+            .collect();
+        reindex(&mut self.referrals_by_account, old_account_id, new_account_id, &referral_ids);
+
+        let appointment_ids: Vec<u64> = self.appointments_by_account.get(old_account_id).unwrap_or_default().into_iter()
+            .filter_map(|id| {
+                let mut appointment = self.appointments.get(&id)
+                    .filter(|appointment| appointment.patient_id.as_ref() == Some(old_account_id))?;
+                appointment.patient_id = Some(new_account_id.clone());
+                self.appointments.insert(&id, &appointment);
+                Some(id)
+            })
+            .collect();
+        reindex(&mut self.appointments_by_account, old_account_id, new_account_id, &appointment_ids);
+
+        // Consents keep their pseudonym so earlier exports still link up, and carry the
+        // compensation not yet claimed. Where the new account already consented, what the
+        // old consent earned is added to its consent.
+        let program_ids = self.consents_by_patient.get(old_account_id).unwrap_or_default();
+        for program_id in &program_ids {
+            let Some(mut consents) = self.consents.get(program_id) else { continue };
+            let Some(index) = consents.iter().position(|consent| consent.patient_id == *old_account_id) else { continue };
+            if let Some(existing) = consents.iter().position(|consent| consent.patient_id == *new_account_id) {
+                let mut old_consent = consents.remove(index);
+                self.count_rounds(*program_id, &mut old_consent);
+                let existing = if existing > index { existing - 1 } else { existing };
+                self.count_rounds(*program_id, &mut consents[existing]);
+                consents[existing].earned = U128(consents[existing].earned.0 + old_consent.earned.0);
+            } else {
+                consents[index].patient_id = new_account_id.clone();
+            }
+            self.consents.insert(program_id, &consents);
+        }
+        reindex(&mut self.consents_by_patient, old_account_id, new_account_id, &program_ids);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::json_types::U64;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(predecessor: &str) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .build()
+    }
+
+    // Bob has a record with an escrowed bill, a grant and some storage credit
+    fn setup() -> PatientRecord {
+        testing_env!(get_context("alice.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.whitelist_token("usdc.near".parse().unwrap(), U128(10));
+        contract.verify_provider("hospital.near".parse().unwrap(), String::from("CGH"));
+
+        testing_env!(get_context("bob.near"));
+        contract.add_record(String::from("Malaria"), String::from("CGH"), String::from("Coartem"),
+            String::from("01/05/2022"), String::from("03/05/2022"), String::from("None"), 1000);
+        testing_env!(get_context("hospital.near"));
+        contract.issue_bill("bob.near".parse().unwrap(), 0, U128(1000), None);
+        testing_env!(get_context("bob.near"));
+        contract.pay_bill(0);
+        contract.grant_access("doctor.near".parse().unwrap(), None);
+
+        testing_env!(get_context("usdc.near"));
+        contract.ft_on_transfer("bob.near".parse().unwrap(), U128(1000), String::from("\"buy_storage\""));
+
+        testing_env!(get_context("bob.near"));
+        contract.initiate_transfer("bob-new.near".parse().unwrap());
+        contract
+    }
+
+    #[test]
+    fn accepted_transfer_moves_everything() {
+        let mut contract = setup();
+
+        testing_env!(get_context("bob-new.near"));
+        contract.accept_transfer("bob.near".parse().unwrap());
+        assert_eq!(1, contract.read_record(0, 10).unwrap().len());
+        assert_eq!(1, contract.get_grants("bob-new.near".parse().unwrap()).len());
+        assert_eq!(1, contract.get_record_count("bob-new.near".parse().unwrap()));
+        let credit = contract.get_storage_credit("bob-new.near".parse().unwrap());
+        assert_eq!(100, credit.available + credit.used);
+        contract.confirm_bill(0);
+
+        testing_env!(get_context("bob.near"));
+        assert!(contract.read_record(0, 10).unwrap().is_empty());
+        assert!(contract.get_grants("bob.near".parse().unwrap()).is_empty());
+        assert!(contract.get_pending_transfer("bob.near".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn appointments_consents_and_compensation_follow_the_patient() {
+        let mut contract = setup();
+
+        testing_env!(get_context("alice.near"));
+        contract.approve_institution("lab.near".parse().unwrap(), String::from("Lab"));
+        testing_env!(get_context("lab.near"));
+        let program_id = contract.register_program(String::from("Malaria"), String::from("Outcomes"));
+        testing_env!(get_context("hospital.near"));
+        let appointment_id = contract.publish_slot(U64(1000), U64(2000), U128(0));
+
+        testing_env!(get_context("bob.near"));
+        contract.book_appointment(appointment_id);
+        contract.opt_in(program_id, String::from("Outcomes"), U64(5000));
+        testing_env!(get_context("lab.near"));
+        contract.fund_program(program_id, U128(1000));
+
+        testing_env!(get_context("bob-new.near"));
+        contract.accept_transfer("bob.near".parse().unwrap());
+        assert_eq!(U128(0), contract.get_claimable("bob.near".parse().unwrap()));
+        assert_eq!(U128(1000), contract.get_claimable("bob-new.near".parse().unwrap()));
+        assert_eq!(1, contract.read_appointments(0, 10).len());
+        assert_eq!(Some("bob-new.near".parse().unwrap()), contract.get_appointment(appointment_id).unwrap().patient_id);
+        assert!(contract.get_consent(program_id, "bob-new.near".parse().unwrap()).is_some());
+        assert!(contract.get_consent(program_id, "bob.near".parse().unwrap()).is_none());
+        contract.cancel_appointment(appointment_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Transfer was offered to another account!")]
+    fn only_named_account_can_accept() {
+        let mut contract = setup();
+
+        testing_env!(get_context("mallory.near"));
+        contract.accept_transfer("bob.near".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "No pending transfer!")]
+    fn cancelled_transfer_cannot_be_accepted() {
+        let mut contract = setup();
+        contract.cancel_transfer();
+
+        testing_env!(get_context("bob-new.near"));
+        contract.accept_transfer("bob.near".parse().unwrap());
+    }
+}
@@ -0,0 +1,463 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::json_types::{Base58CryptoHash, Base64VecU8, U128, U64};
+use near_sdk::{near_bindgen, env, AccountId, Gas, GasWeight, Promise, PromiseResult};
+
+use crate::patient::Patient;
+use crate::{now_ms, PatientRecord, PatientRecordExt};
+pub use med_block_types::upgrade::{UpgradeConfig, UpgradeProposal};
+
+// Gas kept for `migrate` on top of whatever the upgrade call leaves over
+const GAS_FOR_MIGRATE: Gas = Gas(20_000_000_000_000);
+// Gas for settling a proposal once its code is deployed
+const GAS_FOR_RESOLVE_UPGRADE: Gas = Gas(10_000_000_000_000);
+
+// Prefix the first release, and every one since, keeps patients under
+const PATIENTS_PREFIX: &[u8] = b"c";
+
+// A record as the first release laid it out, before ids, payments and authors
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+struct BaselineRecord {
+    diagnosis: String,
+    hospital_name: String,
+    medicine_administered: String,
+    date_of_admission: String,
+    date_of_release: String,
+    allergies_recorded: String,
+    price: f64,
+}
+
+// Deploy code to this account and migrate the state in the same receipt, so a
+// failed migration leaves the old code in place
+fn deploy_and_migrate(code: Vec<u8>) -> Promise {
+    Promise::new(env::current_account_id())
+        .deploy_contract(code)
+        .function_call_weight(String::from("migrate"), b"{}".to_vec(), 0, GAS_FOR_MIGRATE, GasWeight(1))
+}
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Replace the contract's code, called by the owner until approvers are set
+
+    pub fn upgrade(&mut self, code: Base64VecU8) -> Promise {
+        self.assert_owner();
+        assert!(self.upgrade_config.is_none(), "Upgrades need the approvers!");
+
+        deploy_and_migrate(code.into())
+    }
+
+    // Hand upgrades over to a set of approvers, called by the owner. Once set the
+    // owner can no longer upgrade alone, and only new code can change the approvers.
+
+    pub fn set_upgrade_approvers(&mut self, approvers: Vec<AccountId>, threshold: u32, delay: U64) {
+        self.assert_owner();
+        assert!(self.upgrade_config.is_none(), "Approvers can only be changed by an upgrade!");
+
+        assert!(threshold > 0 && threshold as usize <= approvers.len(), "Invalid upgrade threshold!");
+        let mut unique = approvers.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), approvers.len(), "Duplicate approver!");
+
+        self.upgrade_config = Some(UpgradeConfig { approvers, threshold, delay });
+    }
+
+    // Stage new code for the approvers to agree on, called by an approver who
+    // pays to store it until it is deployed or withdrawn
+
+    #[payable]
+    pub fn propose_upgrade(&mut self, code: Base64VecU8) -> UpgradeProposal {
+        let signer = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+
+        let config = self.assert_upgrade_approver(&signer);
+        assert!(self.upgrade_proposal.is_none(), "Another upgrade is in progress!");
+
+        let code: Vec<u8> = code.into();
+        let code_hash = Base58CryptoHash::from(env::sha256_array(&code));
+        self.upgrade_code.set(&code);
+        let code_bytes = env::storage_usage().saturating_sub(initial_storage);
+
+        let mut proposal = UpgradeProposal {
+            code_hash,
+            proposer_id: signer.clone(),
+            approvals: vec![signer.clone()],
+            ready_at: None,
+            storage_cost: U128(0),
+        };
+        if config.threshold == 1 {
+            proposal.ready_at = Some(U64(now_ms() + config.delay.0));
+        }
+        self.upgrade_proposal = Some(proposal.clone());
+
+        // The proposal lives in the contract's own state, which is only written once
+        // the call returns, so it is measured by its encoding in place of the None it
+        // replaces. The approvals and time lock still to come are charged for up front.
+        let proposal_bytes = proposal.try_to_vec().unwrap().len() as u64;
+        let pending_bytes: u64 = config.approvers
+            .iter()
+            .filter(|approver| **approver != signer)
+            .map(|approver| 4 + approver.as_str().len() as u64)
+            .sum::<u64>() + if proposal.ready_at.is_none() { 8 } else { 0 };
+        let storage_used = code_bytes + proposal_bytes + pending_bytes;
+        let storage_cost = env::storage_byte_cost() * storage_used as u128;
+        assert!(deposit >= storage_cost, "Insufficient funds!");
+        if deposit > storage_cost {
+            self.return_excess_tokens(deposit - storage_cost);
+        }
+
+        proposal.storage_cost = U128(storage_cost);
+        self.upgrade_proposal = Some(proposal.clone());
+        proposal
+    }
+
+    // Agree to the staged code, called by an approver. Naming the hash makes sure
+    // the code approved is the code that was reviewed.
+
+    pub fn approve_upgrade(&mut self, code_hash: Base58CryptoHash) -> UpgradeProposal {
+        let signer = env::predecessor_account_id();
+
+        let config = self.assert_upgrade_approver(&signer);
+        let mut proposal = self.upgrade_proposal.clone().expect("No upgrade proposed!");
+        assert_eq!(proposal.code_hash, code_hash, "Code does not match the proposal!");
+
+        if !proposal.approvals.contains(&signer) {
+            proposal.approvals.push(signer);
+        }
+        if proposal.ready_at.is_none() && proposal.approvals.len() >= config.threshold as usize {
+            proposal.ready_at = Some(U64(now_ms() + config.delay.0));
+        }
+        self.upgrade_proposal = Some(proposal.clone());
+        proposal
+    }
+
+    // Drop the staged code and pay the proposer back, called by any approver
+    // during the time lock or before
+
+    pub fn cancel_upgrade(&mut self) -> Option<UpgradeProposal> {
+        let signer = env::predecessor_account_id();
+
+        self.assert_upgrade_approver(&signer);
+        let cancelled = self.upgrade_proposal.take();
+        if let Some(proposal) = &cancelled {
+            self.upgrade_code.remove();
+            Promise::new(proposal.proposer_id.clone()).transfer(proposal.storage_cost.0);
+        }
+        cancelled
+    }
+
+    // Deploy the staged code once the time lock has passed, called by an approver.
+    // The proposal is kept until the migration went through, so a failed one can be
+    // applied again or cancelled.
+
+    pub fn apply_upgrade(&mut self) -> Promise {
+        let signer = env::predecessor_account_id();
+
+        self.assert_upgrade_approver(&signer);
+        let proposal = self.upgrade_proposal.clone().expect("No upgrade proposed!");
+        let ready_at = proposal.ready_at.expect("Not enough approvers have agreed!");
+        assert!(now_ms() >= ready_at.0, "Upgrade is still in its time lock!");
+
+        let code = self.upgrade_code.get().expect("No upgrade proposed!");
+        deploy_and_migrate(code).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_UPGRADE)
+                .on_upgrade_applied()
+        )
+    }
+
+    // Drop the deployed code and pay the proposer back once the migration went through
+
+    #[private]
+    pub fn on_upgrade_applied(&mut self) -> bool {
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            if let Some(proposal) = self.upgrade_proposal.take() {
+                self.upgrade_code.remove();
+                Promise::new(proposal.proposer_id).transfer(proposal.storage_cost.0);
+            }
+            return true;
+        }
+        false
+    }
+
+    // Rebuild the state after new code is deployed. Reads the state as this
+    // version lays it out, so change it along with any change to the layout.
+    // The first release kept nothing but the patients map and had no owner, so
+    // moving off it needs one, and its patients are then converted with
+    // `migrate_patients`.
+
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(owner_id: Option<AccountId>) -> Self {
+        let state = env::storage_read(b"STATE").expect("Contract is not initialized");
+        if let Ok(contract) = Self::try_from_slice(&state) {
+            return contract;
+        }
+
+        let prefix = Vec::<u8>::try_from_slice(&state).expect("Cannot read the contract state");
+        assert_eq!(prefix, PATIENTS_PREFIX, "Cannot read the contract state");
+        Self::new(owner_id.expect("Migrating the first release needs an owner_id!"))
+    }
+
+    // Convert patients still stored as the first release laid them out, called by
+    // the owner with ids read from contract state. Records get ids in their old
+    // order and count towards the patient's totals. The contract pays for the
+    // space the new layout takes. Returns how many patients were converted.
+
+    pub fn migrate_patients(&mut self, patient_ids: Vec<AccountId>) -> u32 {
+        self.assert_owner();
+
+        let mut migrated = 0;
+        for patient_id in patient_ids {
+            let key = [PATIENTS_PREFIX, &patient_id.try_to_vec().unwrap()].concat();
+            let Some(bytes) = env::storage_read(&key) else { continue };
+            if Patient::try_from_slice(&bytes).is_ok() {
+                continue;
+            }
+            let Ok(records) = Vec::<BaselineRecord>::try_from_slice(&bytes) else { continue };
+            // Inserting over the old entry would try to read it as the new layout
+            env::storage_remove(&key);
+
+            let mut patient = Patient::new_patient();
+            let ids: Vec<u64> = records
+                .into_iter()
+                .map(|record| patient.add(record.diagnosis, record.hospital_name, record.medicine_administered,
+                    record.date_of_admission, record.date_of_release, record.allergies_recorded, record.price, None))
+                .collect();
+            self.save_patient(&patient_id, &patient, &ids, &[]);
+            migrated += 1;
+        }
+        migrated
+    }
+
+    // Get the accounts that approve upgrades
+
+    pub fn get_upgrade_config(&self) -> Option<UpgradeConfig> {
+        self.upgrade_config.clone()
+    }
+
+    // Get the upgrade in progress
+
+    pub fn get_upgrade_proposal(&self) -> Option<UpgradeProposal> {
+        self.upgrade_proposal.clone()
+    }
+
+    // Panics unless the account is one of the approvers
+
+    fn assert_upgrade_approver(&self, account_id: &AccountId) -> UpgradeConfig {
+        let config = self.upgrade_config.clone().expect("Upgrades have no approvers!");
+        assert!(config.approvers.contains(account_id), "Only an approver can call this method");
+        config
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig, VMContext};
+
+    const DELAY: u64 = 86_400_000;
+    const CODE: &[u8] = b"\0asm new code";
+
+    fn get_context(predecessor: &str, timestamp_ms: u64) -> VMContext {
+        VMContextBuilder::new()
+            .current_account_id("medblock.near".parse().unwrap())
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build()
+    }
+
+    // The code deployed to the contract with a migrate call after it, if any
+    fn deployed() -> Option<Vec<u8>> {
+        let receipt = get_created_receipts().into_iter().find(|receipt| receipt.receiver_id.as_str() == "medblock.near")?;
+        match &receipt.actions[..] {
+            [VmAction::DeployContract { code }, VmAction::FunctionCall { function_name, .. }] if function_name == "migrate" => {
+                Some(code.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // Run the next call as the contract's own callback, after a call with the given result
+    fn resolve(result: PromiseResult) {
+        testing_env!(
+            get_context("medblock.near", 2000 + DELAY),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result]
+        );
+    }
+
+    // Whether the proposer has been paid back for the proposal's storage
+    fn refunded(proposal: &UpgradeProposal) -> bool {
+        get_created_receipts().iter().any(|receipt| receipt.receiver_id == proposal.proposer_id
+            && matches!(receipt.actions[..], [VmAction::Transfer { deposit }] if deposit == proposal.storage_cost.0))
+    }
+
+    // Three approvers, two of whom must agree, and mum has proposed new code
+    fn setup() -> PatientRecord {
+        testing_env!(get_context("alice.near", 1000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        let approvers = ["mum.near", "dad.near", "hospital.near"].iter().map(|id| id.parse().unwrap()).collect();
+        contract.set_upgrade_approvers(approvers, 2, U64(DELAY));
+
+        testing_env!(get_context("mum.near", 1000));
+        let proposal = contract.propose_upgrade(CODE.to_vec().into());
+        assert!(proposal.ready_at.is_none());
+        contract
+    }
+
+    #[test]
+    fn owner_upgrades_and_migrates() {
+        testing_env!(get_context("alice.near", 1000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.upgrade(CODE.to_vec().into());
+        assert_eq!(Some(CODE.to_vec()), deployed());
+    }
+
+    #[test]
+    fn migrate_keeps_records() {
+        testing_env!(get_context("bob.near", 1000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.add_record(String::from("Malaria"), String::from("CGH"), String::from("Coartem"),
+            String::from("01/05/2022"), String::from("03/05/2022"), String::from("None"), 1000);
+        env::state_write(&contract);
+
+        let contract = PatientRecord::migrate(None);
+        assert_eq!(1, contract.read_record(0, 10).unwrap().len());
+        assert_eq!("alice.near", contract.get_owner().as_str());
+    }
+
+    #[test]
+    fn migrate_from_first_release() {
+        testing_env!(get_context("alice.near", 1000));
+        let records = vec![
+            BaselineRecord {
+                diagnosis: String::from("Malaria"), hospital_name: String::from("CGH"),
+                medicine_administered: String::from("Coartem"), date_of_admission: String::from("01/05/2022"),
+                date_of_release: String::from("03/05/2022"), allergies_recorded: String::from("None"), price: 1000.0,
+            };
+            2
+        ];
+        let bob: AccountId = "bob.near".parse().unwrap();
+        env::storage_write(b"STATE", &PATIENTS_PREFIX.to_vec().try_to_vec().unwrap());
+        env::storage_write(&[PATIENTS_PREFIX, &bob.try_to_vec().unwrap()].concat(), &records.try_to_vec().unwrap());
+
+        let mut contract = PatientRecord::migrate(Some("alice.near".parse().unwrap()));
+        assert_eq!("alice.near", contract.get_owner().as_str());
+        assert_eq!(1, contract.migrate_patients(vec![bob.clone(), "carol.near".parse().unwrap()]));
+        assert_eq!(0, contract.migrate_patients(vec![bob.clone()]));
+        assert_eq!(2, contract.get_record_count(bob.clone()));
+
+        testing_env!(get_context("bob.near", 2000));
+        let records = contract.read_record(0, 10).unwrap();
+        assert_eq!(vec![0, 1], records.iter().map(|record| record.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn proposer_pays_for_the_proposal_and_its_approvals() {
+        testing_env!(get_context("alice.near", 1000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        let approvers = ["mum.near", "dad.near", "hospital.near"].iter().map(|id| id.parse().unwrap()).collect();
+        contract.set_upgrade_approvers(approvers, 3, U64(DELAY));
+        let initial_state = contract.try_to_vec().unwrap().len();
+
+        testing_env!(get_context("mum.near", 1000));
+        let proposal = contract.propose_upgrade(CODE.to_vec().into());
+        for approver in ["dad.near", "hospital.near"] {
+            testing_env!(get_context(approver, 2000));
+            contract.approve_upgrade(proposal.code_hash);
+        }
+        // The code's own entry, then whatever the proposal grew the state by once approved
+        let code_bytes = 40 + 1 + 4 + CODE.len();
+        let state_bytes = contract.try_to_vec().unwrap().len() - initial_state;
+        assert_eq!(env::storage_byte_cost() * (code_bytes + state_bytes) as u128, proposal.storage_cost.0);
+    }
+
+    #[test]
+    fn approvers_upgrade_after_time_lock() {
+        let mut contract = setup();
+        let code_hash = contract.get_upgrade_proposal().unwrap().code_hash;
+
+        testing_env!(get_context("dad.near", 2000));
+        let proposal = contract.approve_upgrade(code_hash);
+        assert_eq!(Some(U64(2000 + DELAY)), proposal.ready_at);
+
+        testing_env!(get_context("hospital.near", 2000 + DELAY));
+        contract.apply_upgrade();
+        assert_eq!(Some(CODE.to_vec()), deployed());
+        assert!(!refunded(&proposal));
+
+        // Mum is paid back once the migration has gone through
+        resolve(PromiseResult::Successful(vec![]));
+        assert!(contract.on_upgrade_applied());
+        assert!(refunded(&proposal));
+        assert!(contract.get_upgrade_proposal().is_none());
+    }
+
+    #[test]
+    fn failed_migration_keeps_the_proposal() {
+        let mut contract = setup();
+        let code_hash = contract.get_upgrade_proposal().unwrap().code_hash;
+
+        testing_env!(get_context("dad.near", 2000));
+        let proposal = contract.approve_upgrade(code_hash);
+        testing_env!(get_context("hospital.near", 2000 + DELAY));
+        contract.apply_upgrade();
+
+        resolve(PromiseResult::Failed);
+        assert!(!contract.on_upgrade_applied());
+        assert!(!refunded(&proposal));
+        assert!(contract.get_upgrade_proposal().is_some());
+
+        // It can then be cancelled, paying mum back
+        testing_env!(get_context("dad.near", 2000 + DELAY));
+        contract.cancel_upgrade();
+        assert!(refunded(&proposal));
+    }
+
+    #[test]
+    #[should_panic(expected = "Upgrade is still in its time lock!")]
+    fn upgrade_waits_for_time_lock() {
+        let mut contract = setup();
+        let code_hash = contract.get_upgrade_proposal().unwrap().code_hash;
+
+        testing_env!(get_context("dad.near", 2000));
+        contract.approve_upgrade(code_hash);
+        contract.apply_upgrade();
+    }
+
+    #[test]
+    #[should_panic(expected = "No upgrade proposed!")]
+    fn approver_can_cancel() {
+        let mut contract = setup();
+        let code_hash = contract.get_upgrade_proposal().unwrap().code_hash;
+
+        testing_env!(get_context("hospital.near", 2000));
+        assert!(contract.cancel_upgrade().is_some());
+        contract.approve_upgrade(code_hash);
+    }
+
+    #[test]
+    #[should_panic(expected = "Upgrades need the approvers!")]
+    fn owner_cannot_bypass_approvers() {
+        let mut contract = setup();
+
+        testing_env!(get_context("alice.near", 2000));
+        contract.upgrade(CODE.to_vec().into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn stranger_cannot_upgrade() {
+        testing_env!(get_context("mallory.near", 1000));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.upgrade(CODE.to_vec().into());
+    }
+}
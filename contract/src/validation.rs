@@ -0,0 +1,107 @@
+use near_sdk::{near_bindgen, AccountId};
+
+use crate::{PatientRecord, PatientRecordExt};
+use crate::med_record::NewRecord;
+pub use med_block_types::validation::{validate, RecordLimits};
+
+#[near_bindgen]
+impl PatientRecord {
+
+    // Set how much a single call and a patient's history may store, called by the owner
+
+    pub fn set_record_limits(&mut self, limits: RecordLimits) {
+        self.assert_owner();
+        assert!(
+            limits.max_records_per_call > 0
+                && limits.max_records_per_patient > 0
+                && limits.max_diagnosis_len > 0
+                && limits.max_hospital_name_len > 0
+                && limits.max_medicine_len > 0
+                && limits.max_allergies_len > 0,
+            "Limits would refuse every record!"
+        );
+        self.record_limits = limits;
+    }
+
+    // Get how much a single call and a patient's history may store
+
+    pub fn get_record_limits(&self) -> RecordLimits {
+        self.record_limits.clone()
+    }
+
+    // Panics with the first problem found so callers can read back which record and field failed
+
+    pub(crate) fn assert_valid_records(&self, patient_id: &AccountId, records: &[NewRecord]) {
+        if let Err(error) = validate(records, self.get_record_count(patient_id.clone()), &self.record_limits) {
+            panic!("{}", error.panic_message());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use med_block_types::validation::ValidationError;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, VMContext};
+
+    fn get_context(predecessor: &str) -> VMContext {
+        VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(1000000000000000000000000)
+            .build()
+    }
+
+    fn add(contract: &mut PatientRecord, diagnosis: &str, date_of_admission: &str) {
+        contract.add_record(String::from(diagnosis), String::from("CGH"), String::from("Coartem"),
+            String::from(date_of_admission), String::from("03/05/2022"), String::from("None"), 1000);
+    }
+
+    #[test]
+    fn owner_tightens_limits() {
+        testing_env!(get_context("alice.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.set_record_limits(RecordLimits { max_diagnosis_len: 7, ..RecordLimits::default() });
+
+        testing_env!(get_context("bob.near"));
+        add(&mut contract, "Malaria", "01/05/2022");
+        let error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| add(&mut contract, "Malaria!", "01/05/2022")))
+            .unwrap_err();
+        let message = error.downcast_ref::<String>().unwrap();
+        assert_eq!(
+            Some(ValidationError::FieldTooLong { record: 0, field: String::from("diagnosis"), max: 7, length: 8 }),
+            ValidationError::from_panic(message)
+        );
+        assert_eq!(1, contract.get_record_count("bob.near".parse().unwrap()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid record! {\"error\":\"invalid_date\",\"record\":0,\"field\":\"date_of_admission\"}")]
+    fn dates_must_be_readable() {
+        testing_env!(get_context("bob.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        add(&mut contract, "Malaria", "last tuesday");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid record! {\"error\":\"history_full\",\"max\":2,\"stored\":2,\"given\":1}")]
+    fn history_is_capped() {
+        testing_env!(get_context("alice.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.set_record_limits(RecordLimits { max_records_per_patient: 2, ..RecordLimits::default() });
+
+        testing_env!(get_context("bob.near"));
+        add(&mut contract, "Malaria", "01/05/2022");
+        add(&mut contract, "Typhoid", "01/06/2022");
+        add(&mut contract, "Flu", "01/07/2022");
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn only_owner_sets_limits() {
+        testing_env!(get_context("bob.near"));
+        let mut contract = PatientRecord::new("alice.near".parse().unwrap());
+        contract.set_record_limits(RecordLimits::default());
+    }
+}
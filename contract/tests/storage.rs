@@ -0,0 +1,424 @@
+//! Random sequences of record, sharing, appointment, billing, research funding and
+//! token storage calls across several accounts, checking after every call that the
+//! contract still holds enough to cover its state and that no account has been paid
+//! back more than it put in or was owed.
+
+use std::collections::HashMap;
+
+use med_block::fhir;
+use med_block::med_record::{MedRecord, NewRecord, PaymentStatus};
+use med_block::PatientRecord;
+use med_block_types::appointment::{AppointmentStatus, DEPOSIT_GRACE_PERIOD};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::mock::VmAction;
+use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+use near_sdk::{env, testing_env, AccountId, Balance, MockedBlockchain, RuntimeFeesConfig, VMConfig};
+use proptest::prelude::*;
+use proptest::sample::Index;
+
+const PATIENTS: [&str; 3] = ["bob.near", "carol.near", "dave.near"];
+const OWNER: &str = "alice.near";
+const HOSPITAL: &str = "hospital.near";
+const INSTITUTION: &str = "lab.near";
+const TOKEN: &str = "usdc.near";
+const PURPOSE: &str = "Malaria outcomes";
+// Token units per byte of storage bought with the token
+const TOKEN_BYTE_PRICE: u128 = 10;
+// When every published slot starts and ends, calls otherwise run at time 0
+const SLOT_START: u64 = 1000;
+const SLOT_END: u64 = 2000;
+// Enough to cover the storage any single call below takes, the rest is returned
+const STORAGE_DEPOSIT: Balance = 10u128.pow(23);
+
+#[derive(Debug, Clone)]
+enum Op {
+    Add { patient: usize, records: Vec<NewRecord>, extra: Balance },
+    Delete { patient: usize, pick: Index },
+    DeleteMany { patient: usize, picks: Vec<Index> },
+    Grant { patient: usize, grantee: usize, picks: Option<Vec<Index>> },
+    Revoke { patient: usize, grantee: usize },
+    PublicSummary { patient: usize, enabled: bool },
+    PublishSlot { no_show_deposit: Balance },
+    Book { patient: usize, pick: Index },
+    CancelBooking { patient: usize, pick: Index },
+    HospitalCancel { pick: Index },
+    Reclaim { patient: usize, pick: Index },
+    Complete { pick: Index, record: NewRecord },
+    IssueBill { patient: usize, pick: Index, amount: Balance },
+    PayBill { patient: usize, pick: Index, extra: Balance },
+    ConfirmBill { patient: usize, pick: Index },
+    RefundBill { patient: usize, pick: Index },
+    OptIn { patient: usize },
+    Fund { amount: Balance },
+    Claim { patient: usize },
+    BuyStorage { patient: usize, amount: u128 },
+    WithdrawCredit { patient: usize },
+}
+
+fn new_record() -> impl Strategy<Value = NewRecord> {
+    let text = "[A-Za-z ]{0,40}";
+    // Diagnosis and hospital can't be blank
+    let required = "[A-Za-z][A-Za-z ]{0,39}";
+    let date = prop_oneof![
+        (1u32..29, 1u32..13, 2000u32..2030).prop_map(|(d, m, y)| format!("{:02}/{:02}/{}", d, m, y)),
+        (1u32..29, 1u32..13, 2000u32..2030).prop_map(|(d, m, y)| format!("{}-{:02}-{:02}", y, m, d)),
+    ];
+    // Often the verified hospital's name so the record can be billed
+    let hospital_name = prop_oneof![Just(String::from("CGH")), required];
+    (required, hospital_name, text, date.clone(), date, text, any::<u64>()).prop_map(
+        |(diagnosis, hospital_name, medicine_administered, date_of_admission, date_of_release, allergies_recorded, price)| {
+            NewRecord { diagnosis, hospital_name, medicine_administered, date_of_admission, date_of_release, allergies_recorded, price }
+        },
+    )
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let patient = 0..PATIENTS.len();
+    prop_oneof![
+        3 => (patient.clone(), prop::collection::vec(new_record(), 1..4), 0..10u128.pow(24))
+            .prop_map(|(patient, records, extra)| Op::Add { patient, records, extra }),
+        1 => (patient.clone(), any::<Index>()).prop_map(|(patient, pick)| Op::Delete { patient, pick }),
+        1 => (patient.clone(), prop::collection::vec(any::<Index>(), 1..4))
+            .prop_map(|(patient, picks)| Op::DeleteMany { patient, picks }),
+        1 => (patient.clone(), patient.clone(), prop::option::of(prop::collection::vec(any::<Index>(), 1..4)))
+            .prop_map(|(patient, grantee, picks)| Op::Grant { patient, grantee, picks }),
+        1 => (patient.clone(), patient.clone()).prop_map(|(patient, grantee)| Op::Revoke { patient, grantee }),
+        1 => (patient.clone(), any::<bool>()).prop_map(|(patient, enabled)| Op::PublicSummary { patient, enabled }),
+        2 => (0..10u128.pow(23)).prop_map(|no_show_deposit| Op::PublishSlot { no_show_deposit }),
+        2 => (patient.clone(), any::<Index>()).prop_map(|(patient, pick)| Op::Book { patient, pick }),
+        1 => (patient.clone(), any::<Index>()).prop_map(|(patient, pick)| Op::CancelBooking { patient, pick }),
+        1 => any::<Index>().prop_map(|pick| Op::HospitalCancel { pick }),
+        1 => (patient.clone(), any::<Index>()).prop_map(|(patient, pick)| Op::Reclaim { patient, pick }),
+        2 => (any::<Index>(), new_record()).prop_map(|(pick, record)| Op::Complete { pick, record }),
+        2 => (patient.clone(), any::<Index>(), 1..10u128.pow(26))
+            .prop_map(|(patient, pick, amount)| Op::IssueBill { patient, pick, amount }),
+        2 => (patient.clone(), any::<Index>(), 0..10u128.pow(24))
+            .prop_map(|(patient, pick, extra)| Op::PayBill { patient, pick, extra }),
+        2 => (patient.clone(), any::<Index>()).prop_map(|(patient, pick)| Op::ConfirmBill { patient, pick }),
+        1 => (patient.clone(), any::<Index>()).prop_map(|(patient, pick)| Op::RefundBill { patient, pick }),
+        2 => patient.clone().prop_map(|patient| Op::OptIn { patient }),
+        2 => (1..10u128.pow(24)).prop_map(|amount| Op::Fund { amount }),
+        2 => patient.clone().prop_map(|patient| Op::Claim { patient }),
+        1 => (patient.clone(), 1_000..1_000_000u128).prop_map(|(patient, amount)| Op::BuyStorage { patient, amount }),
+        1 => patient.prop_map(|patient| Op::WithdrawCredit { patient }),
+    ]
+}
+
+fn account(index: usize) -> AccountId {
+    PATIENTS[index].parse().unwrap()
+}
+
+fn named(account_id: &str) -> AccountId {
+    account_id.parse().unwrap()
+}
+
+// The contract's balance, storage and block time carried from one call to the next,
+// what each account has paid in and been paid back, and what it was owed on top of its
+// own deposits as a hospital paid for a bill or a patient paid for their data
+struct Ledger {
+    now: u64,
+    balance: Balance,
+    storage: u64,
+    deposited: HashMap<AccountId, Balance>,
+    refunded: HashMap<AccountId, Balance>,
+    owed: HashMap<AccountId, Balance>,
+}
+
+impl Ledger {
+    fn context(&self, predecessor: &AccountId, deposit: Balance) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(predecessor.clone())
+            .attached_deposit(deposit)
+            .account_balance(self.balance + deposit)
+            .storage_usage(self.storage)
+            .block_timestamp(self.now * 1_000_000)
+            .build());
+    }
+
+    // Run one call at a later block time, in epoch milliseconds, then go back to the start
+    fn call_at<R>(&mut self, now: u64, caller: &AccountId, deposit: Balance, f: impl FnOnce() -> R) -> R {
+        self.now = now;
+        let result = self.call(caller, deposit, f);
+        self.now = 0;
+        result
+    }
+
+    // Run one call as an account, then book what it took in and paid out
+    fn call<R>(&mut self, caller: &AccountId, deposit: Balance, f: impl FnOnce() -> R) -> R {
+        self.context(caller, deposit);
+        let result = f();
+
+        let mut paid_out = 0;
+        for receipt in get_created_receipts() {
+            for action in receipt.actions {
+                if let VmAction::Transfer { deposit } = action {
+                    paid_out += deposit;
+                    *self.refunded.entry(receipt.receiver_id.clone()).or_default() += deposit;
+                }
+            }
+        }
+        *self.deposited.entry(caller.clone()).or_default() += deposit;
+        self.balance = self.balance + deposit - paid_out;
+        self.storage = env::storage_usage();
+        result
+    }
+
+    fn owe(&mut self, account_id: &AccountId, amount: Balance) {
+        *self.owed.entry(account_id.clone()).or_default() += amount;
+    }
+
+    // Storage bought with tokens is paid for by the contract's account, which the
+    // token payment compensates, so those bytes count as covered
+    fn check(&self, contract: &PatientRecord) -> Result<(), TestCaseError> {
+        let token_bytes: u64 = PATIENTS.iter().chain([HOSPITAL, INSTITUTION].iter())
+            .map(|account_id| contract.get_storage_credit(named(account_id)).used)
+            .sum();
+        let needed = self.storage.saturating_sub(token_bytes) as Balance * env::storage_byte_cost();
+        prop_assert!(self.balance >= needed, "balance {} can't cover {} bytes of state", self.balance, self.storage);
+        for (account_id, refunded) in &self.refunded {
+            let deposited = self.deposited.get(account_id).copied().unwrap_or(0);
+            let owed = self.owed.get(account_id).copied().unwrap_or(0);
+            prop_assert!(*refunded <= deposited + owed, "{} got back {} of {} deposited and {} owed",
+                account_id, refunded, deposited, owed);
+        }
+        Ok(())
+    }
+}
+
+// Reads as the patient, which leaves storage and the ledger as they were
+fn records(ledger: &Ledger, contract: &PatientRecord, patient: &AccountId) -> Vec<MedRecord> {
+    ledger.context(patient, 0);
+    contract.read_record(0, u32::MAX).unwrap_or_default()
+}
+
+// The records a patient can delete, which are those without funds held against them
+fn record_ids(ledger: &Ledger, contract: &PatientRecord, patient: &AccountId) -> Vec<u64> {
+    records(ledger, contract, patient)
+        .iter()
+        .filter(|record| record.payment_status != PaymentStatus::Escrowed && record.payment_status != PaymentStatus::Disputed)
+        .map(|record| record.id)
+        .collect()
+}
+
+// The records the hospital can bill whose payment is in one of the given states
+fn billed_ids(ledger: &Ledger, contract: &PatientRecord, patient: &AccountId, statuses: &[PaymentStatus]) -> Vec<u64> {
+    records(ledger, contract, patient)
+        .iter()
+        .filter(|record| statuses.contains(&record.payment_status))
+        .filter(|record| record.author_id.as_ref().map(|author| author.as_str() == HOSPITAL)
+            .unwrap_or_else(|| record.hospital_name.trim().eq_ignore_ascii_case("CGH")))
+        .map(|record| record.id)
+        .collect()
+}
+
+// Booked appointments, as their hospital or the patient who booked them
+fn booked_ids(ledger: &Ledger, contract: &PatientRecord, reader: &AccountId) -> Vec<u64> {
+    ledger.context(reader, 0);
+    contract.read_appointments(0, u32::MAX)
+        .into_iter()
+        .filter(|appointment| appointment.status == AppointmentStatus::Booked)
+        .map(|appointment| appointment.id)
+        .collect()
+}
+
+fn run(ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let mut ledger = Ledger { now: 0, balance: 0, storage: 0, deposited: HashMap::new(), refunded: HashMap::new(), owed: HashMap::new() };
+    let (owner, hospital, institution, token) = (named(OWNER), named(HOSPITAL), named(INSTITUTION), named(TOKEN));
+
+    // Start each sequence from empty storage, then have whoever deploys the
+    // contract pay for its initial state
+    let context = VMContextBuilder::new().predecessor_account_id(owner.clone()).build();
+    env::set_blockchain_interface(MockedBlockchain::new(context, VMConfig::test(), RuntimeFeesConfig::test(),
+        vec![], Default::default(), Default::default(), None));
+    let mut contract = PatientRecord::new(owner.clone());
+    ledger.storage = env::storage_usage();
+    ledger.balance = ledger.storage as Balance * env::storage_byte_cost();
+
+    // A verified hospital, a research program open to funding and a token that buys storage
+    ledger.call(&owner, STORAGE_DEPOSIT, || contract.verify_provider(hospital.clone(), String::from("CGH")));
+    ledger.call(&owner, STORAGE_DEPOSIT, || contract.approve_institution(institution.clone(), String::from("Lab")));
+    ledger.call(&owner, STORAGE_DEPOSIT, || contract.whitelist_token(token.clone(), U128(TOKEN_BYTE_PRICE)));
+    let program_id = ledger.call(&institution, STORAGE_DEPOSIT,
+        || contract.register_program(String::from("Malaria"), String::from(PURPOSE)));
+
+    for op in ops {
+        match op {
+            Op::Add { patient, records, extra } => {
+                let patient = account(patient);
+                let new_patient = contract.get_record_count(patient.clone()) == 0;
+                let deposit = fhir::required_deposit(&patient, &records, new_patient) + extra;
+                ledger.call(&patient, deposit, || contract.add_records(records));
+            }
+            Op::Delete { patient, pick } => {
+                let patient = account(patient);
+                let ids = record_ids(&ledger, &contract, &patient);
+                if ids.is_empty() {
+                    continue;
+                }
+                ledger.call(&patient, 0, || contract.delete_record(*pick.get(&ids)));
+            }
+            Op::DeleteMany { patient, picks } => {
+                let patient = account(patient);
+                let ids = record_ids(&ledger, &contract, &patient);
+                if ids.is_empty() {
+                    continue;
+                }
+                let mut picked: Vec<u64> = picks.iter().map(|pick| *pick.get(&ids)).collect();
+                picked.sort_unstable();
+                picked.dedup();
+                ledger.call(&patient, 0, || contract.delete_records(picked));
+            }
+            Op::Grant { patient, grantee, picks } => {
+                if patient == grantee {
+                    continue;
+                }
+                let (patient, grantee) = (account(patient), account(grantee));
+                let ids = record_ids(&ledger, &contract, &patient);
+                let record_ids = match picks {
+                    Some(_) if ids.is_empty() => continue,
+                    Some(picks) => Some(picks.iter().map(|pick| *pick.get(&ids)).collect()),
+                    None => None,
+                };
+                ledger.call(&patient, STORAGE_DEPOSIT, || contract.grant_access(grantee, record_ids));
+            }
+            Op::Revoke { patient, grantee } => {
+                let (patient, grantee) = (account(patient), account(grantee));
+                ledger.call(&patient, 0, || contract.revoke_access(grantee));
+            }
+            Op::PublicSummary { patient, enabled } => {
+                let patient = account(patient);
+                ledger.call(&patient, STORAGE_DEPOSIT, || contract.set_public_summary(enabled));
+            }
+            Op::PublishSlot { no_show_deposit } => {
+                ledger.call(&hospital, STORAGE_DEPOSIT,
+                    || contract.publish_slot(U64(SLOT_START), U64(SLOT_END), U128(no_show_deposit)));
+            }
+            Op::Book { patient, pick } => {
+                let patient = account(patient);
+                let slots = contract.get_open_slots(hospital.clone(), 0, u32::MAX);
+                if slots.is_empty() {
+                    continue;
+                }
+                let slot = pick.get(&slots);
+                let deposit = slot.no_show_deposit.0 + STORAGE_DEPOSIT;
+                ledger.call(&patient, deposit, || contract.book_appointment(slot.id));
+            }
+            Op::CancelBooking { patient, pick } => {
+                let patient = account(patient);
+                let ids = booked_ids(&ledger, &contract, &patient);
+                if ids.is_empty() {
+                    continue;
+                }
+                ledger.call(&patient, 0, || contract.cancel_appointment(*pick.get(&ids)));
+            }
+            Op::HospitalCancel { pick } => {
+                let ids = booked_ids(&ledger, &contract, &hospital);
+                if ids.is_empty() {
+                    continue;
+                }
+                ledger.call(&hospital, 0, || contract.cancel_booking(*pick.get(&ids)));
+            }
+            Op::Reclaim { patient, pick } => {
+                let patient = account(patient);
+                let ids = booked_ids(&ledger, &contract, &patient);
+                if ids.is_empty() {
+                    continue;
+                }
+                ledger.call_at(SLOT_END + DEPOSIT_GRACE_PERIOD, &patient, 0, || contract.reclaim_deposit(*pick.get(&ids)));
+            }
+            Op::Complete { pick, record } => {
+                let ids = booked_ids(&ledger, &contract, &hospital);
+                if ids.is_empty() {
+                    continue;
+                }
+                let id = *pick.get(&ids);
+                ledger.call_at(SLOT_START, &hospital, STORAGE_DEPOSIT, || contract.complete_appointment(id, record.diagnosis,
+                    record.medicine_administered, record.date_of_admission, record.date_of_release,
+                    record.allergies_recorded, record.price));
+            }
+            Op::IssueBill { patient, pick, amount } => {
+                let patient = account(patient);
+                let ids = billed_ids(&ledger, &contract, &patient, &[PaymentStatus::Unpaid, PaymentStatus::Refunded]);
+                if ids.is_empty() {
+                    continue;
+                }
+                let id = *pick.get(&ids);
+                ledger.call(&hospital, STORAGE_DEPOSIT, || contract.issue_bill(patient.clone(), id, U128(amount), None));
+            }
+            Op::PayBill { patient, pick, extra } => {
+                let patient = account(patient);
+                let ids: Vec<u64> = billed_ids(&ledger, &contract, &patient, &[PaymentStatus::Unpaid, PaymentStatus::Refunded])
+                    .into_iter()
+                    .filter(|id| contract.get_bill(patient.clone(), *id).is_some())
+                    .collect();
+                if ids.is_empty() {
+                    continue;
+                }
+                let id = *pick.get(&ids);
+                let amount = contract.get_bill(patient.clone(), id).unwrap().amount.0;
+                ledger.call(&patient, amount + extra, || contract.pay_bill(id));
+            }
+            Op::ConfirmBill { patient, pick } => {
+                let patient = account(patient);
+                let ids = billed_ids(&ledger, &contract, &patient, &[PaymentStatus::Escrowed]);
+                if ids.is_empty() {
+                    continue;
+                }
+                let bill = ledger.call(&patient, 0, || contract.confirm_bill(*pick.get(&ids)));
+                ledger.owe(&hospital, bill.amount.0);
+            }
+            Op::RefundBill { patient, pick } => {
+                let patient = account(patient);
+                let ids = billed_ids(&ledger, &contract, &patient, &[PaymentStatus::Escrowed]);
+                if ids.is_empty() {
+                    continue;
+                }
+                ledger.call(&hospital, 0, || contract.refund_bill(patient.clone(), *pick.get(&ids)));
+            }
+            Op::OptIn { patient } => {
+                let patient = account(patient);
+                ledger.call(&patient, STORAGE_DEPOSIT,
+                    || contract.opt_in(program_id, String::from(PURPOSE), U64(u64::MAX)));
+            }
+            Op::Fund { amount } => {
+                let patients = PATIENTS.iter().map(|patient| named(patient))
+                    .filter(|patient| contract.get_consent(program_id, patient.clone()).is_some())
+                    .count() as Balance;
+                if patients == 0 || amount < patients {
+                    continue;
+                }
+                ledger.call(&institution, amount + STORAGE_DEPOSIT, || contract.fund_program(program_id, U128(amount)));
+            }
+            Op::Claim { patient } => {
+                let patient = account(patient);
+                if contract.get_claimable(patient.clone()).0 == 0 {
+                    continue;
+                }
+                let claimed = ledger.call(&patient, 0, || contract.claim_compensation());
+                ledger.owe(&patient, claimed.0);
+            }
+            Op::BuyStorage { patient, amount } => {
+                let patient = account(patient);
+                ledger.call(&token, 0, || contract.ft_on_transfer(patient, U128(amount), String::from("\"buy_storage\"")));
+            }
+            Op::WithdrawCredit { patient } => {
+                let patient = account(patient);
+                if contract.get_storage_credit(patient.clone()).available == 0 {
+                    continue;
+                }
+                ledger.call(&patient, 0, || contract.withdraw_storage_credit());
+            }
+        }
+        ledger.check(&contract)?;
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn storage_stays_covered(ops in prop::collection::vec(op(), 1..40)) {
+        run(ops)?;
+    }
+}
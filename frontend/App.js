@@ -3,16 +3,27 @@ import React from 'react'
 
 import './assets/css/global.css'
 
-import {login, logout, get_greeting, set_greeting} from './assets/js/near/utils'
+import {login, logout, add_record, read_records} from './assets/js/near/utils'
 import getConfig from './assets/js/near/config'
 
+// Records shown at once
+const PAGE_SIZE = 20
+
+// Form fields, named after add_record's arguments
+const FIELDS = [
+  ['diagnosis', 'Diagnosis'],
+  ['hospital_name', 'Hospital'],
+  ['medicine_administered', 'Medicine administered'],
+  ['date_of_admission', 'Admitted (dd/mm/yyyy)'],
+  ['date_of_release', 'Released (dd/mm/yyyy)'],
+  ['allergies_recorded', 'Allergies'],
+  ['price', 'Price'],
+]
 
-export default function App() {
-  // use React Hooks to store greeting in component state
-  const [greeting, setGreeting] = React.useState()
 
-  // when the user has not yet interacted with the form, disable the button
-  const [buttonDisabled, setButtonDisabled] = React.useState(true)
+export default function App() {
+  // use React Hooks to store the signed in patient's records in component state
+  const [records, setRecords] = React.useState([])
 
   // after submitting the form, we want to show Notification
   const [showNotification, setShowNotification] = React.useState(false)
@@ -21,11 +32,10 @@ export default function App() {
   // Learn more: https://reactjs.org/docs/hooks-intro.html
   React.useEffect(
     () => {
-      // get_greeting is in near/utils.js
-      get_greeting()
-        .then(greetingFromContract => {
-          setGreeting(greetingFromContract)
-        })
+      // read_records is in near/utils.js
+      if (window.walletConnection.isSignedIn()) {
+        read_records(0, PAGE_SIZE).then(setRecords)
+      }
     },
 
     // The second argument to useEffect tells React when to re-run the effect
@@ -38,21 +48,11 @@ export default function App() {
   if (!window.walletConnection.isSignedIn()) {
     return (
       <main>
-        <h1>
-          <label
-            htmlFor="greeting"
-            style={{
-              color: 'var(--secondary)',
-              borderBottom: '2px solid var(--secondary)'
-            }}
-          >
-            {greeting}
-          </label>!
-          Welcome to NEAR!
-        </h1>
+        <h1>Welcome to MedBlock!</h1>
         <p>
-        Your contract is storing a greeting message in the NEAR blockchain. To
-        change it you need to sign in using the NEAR Wallet. It is very simple,
+        MedBlock keeps your medical records on the NEAR blockchain, where only
+        you and the accounts you share them with can read them. To see or add
+        records you need to sign in using the NEAR Wallet. It is very simple,
         just use the button below.
         </p>
         <p>
@@ -75,34 +75,41 @@ export default function App() {
       </button>
       <main>
         <h1>
-          <label
-            htmlFor="greeting"
-            style={{
-              color: 'var(--secondary)',
-              borderBottom: '2px solid var(--secondary)'
-            }}
-          >
-            {greeting}
-          </label>
+          Records for
           {' '/* React trims whitespace around tags; insert literal space character when needed */}
-          {window.accountId}!
+          <span style={{ color: 'var(--secondary)', borderBottom: '2px solid var(--secondary)' }}>
+            {window.accountId}
+          </span>
         </h1>
+        {records.length === 0
+          ? <p>No records yet.</p>
+          : (
+            <ol>
+              {records.map(record => (
+                <li key={record.id}>
+                  <strong>{record.diagnosis}</strong> at {record.hospital_name},
+                  {' '}{record.date_of_admission} to {record.date_of_release}.
+                  {' '}Given {record.medicine_administered}; allergies: {record.allergies_recorded}.
+                </li>
+              ))}
+            </ol>
+          )}
         <form onSubmit={async event => {
           event.preventDefault()
 
           // get elements from the form using their id attribute
-          const { fieldset, greeting } = event.target.elements
+          const { elements } = event.target
+          const record = Object.fromEntries(FIELDS.map(([name]) => [name, elements[name].value]))
+          record.price = Number(record.price) || 0
 
-          // hold onto new user-entered value from React's SynthenticEvent for use after `await` call
-          const newGreeting = greeting.value
-
-          // disable the form while the value gets updated on-chain
-          fieldset.disabled = true
+          // disable the form while the record gets added on-chain
+          elements.fieldset.disabled = true
 
           try {
             // make an update call to the smart contract
-            // pass the value that the user entered in the greeting field
-            await set_greeting(newGreeting)
+            await add_record(record)
+            setRecords(await read_records(0, PAGE_SIZE))
+            event.target.reset()
           } catch (e) {
             alert(
               'Something went wrong! ' +
@@ -112,12 +119,9 @@ export default function App() {
             throw e
           } finally {
             // re-enable the form, whether the call succeeded or failed
-            fieldset.disabled = false
+            elements.fieldset.disabled = false
           }
 
-          // update local `greeting` variable to match persisted value
-          setGreeting(newGreeting)
-
           // show Notification
           setShowNotification(true)
 
@@ -129,44 +133,29 @@ export default function App() {
         }}>
           <fieldset id="fieldset">
             <label
-              htmlFor="greeting"
               style={{
                 display: 'block',
                 color: 'var(--gray)',
                 marginBottom: '0.5em'
               }}
             >
-              Change greeting
+              Add a record
             </label>
-            <div style={{ display: 'flex' }}>
+            {FIELDS.map(([name, label]) => (
               <input
                 autoComplete="off"
-                defaultValue={greeting}
-                id="greeting"
-                onChange={e => setButtonDisabled(e.target.value === greeting)}
-                style={{ flex: 1 }}
+                id={name}
+                key={name}
+                placeholder={label}
+                required={name !== 'allergies_recorded' && name !== 'price'}
+                style={{ display: 'block', width: '100%', marginBottom: '0.5em' }}
               />
-              <button
-                disabled={buttonDisabled}
-                style={{ borderRadius: '0 5px 5px 0' }}
-              >
-                Save
-              </button>
-            </div>
+            ))}
+            <button style={{ borderRadius: '5px' }}>
+              Save
+            </button>
           </fieldset>
         </form>
-        <p>
-          Look at that! A Hello World app! This greeting is stored on the NEAR blockchain. Check it out:
-        </p>
-        <ol>
-          <li>
-            Look in <code>src/App.js</code> and <code>src/utils.js</code> – you'll see <code>get_greeting</code> and <code>set_greeting</code> being called on <code>contract</code>. What's this?
-          </li>
-          <li>
-            Ultimately, this <code>contract</code> code is defined in <code>assembly/main.ts</code> – this is the source code for your <a target="_blank" rel="noreferrer" href="https://docs.near.org/docs/develop/contracts/overview">smart contract</a>.</li>
-          <li>
-            When you run <code>yarn dev</code>, the code in <code>assembly/main.ts</code> gets deployed to the NEAR testnet. You can see how this happens by looking in <code>package.json</code> at the <code>scripts</code> section to find the <code>dev</code> command.</li>
-        </ol>
         <hr />
         <p>
           To keep learning, check out <a target="_blank" rel="noreferrer" href="https://docs.near.org">the NEAR docs</a> or look through some <a target="_blank" rel="noreferrer" href="https://examples.near.org">example apps</a>.
@@ -188,7 +177,7 @@ function Notification() {
         {window.accountId}
       </a>
       {' '/* React trims whitespace around tags; insert literal space character when needed */}
-      called method: 'set_greeting' in contract:
+      called method: 'add_record' in contract:
       {' '}
       <a target="_blank" rel="noreferrer" href={`${urlPrefix}/${window.contract.contractId}`}>
         {window.contract.contractId}
@@ -1,8 +1,11 @@
-import { connect, Contract, keyStores, WalletConnection } from 'near-api-js'
+import { connect, Contract, keyStores, utils, WalletConnection } from 'near-api-js'
 import getConfig from './config'
 
 const nearConfig = getConfig(process.env.NODE_ENV || 'development')
 
+// Covers the storage for a record, anything unused is refunded by the contract
+const RECORD_DEPOSIT = utils.format.parseNearAmount('0.1')
+
 // Initialize contract & set global variables
 export async function initContract() {
   // Initialize connection to the NEAR testnet
@@ -18,9 +21,9 @@ export async function initContract() {
   // Initializing our contract APIs by contract name and configuration
   window.contract = await new Contract(window.walletConnection.account(), nearConfig.contractName, {
     // View methods are read only. They don't modify the state, but usually return some value.
-    viewMethods: ['get_greeting'],
+    viewMethods: ['get_record_count'],
     // Change methods can modify the state. But you don't receive the returned value when called.
-    changeMethods: ['set_greeting'],
+    changeMethods: ['add_record', 'delete_record', 'read_record'],
   })
 }
 
@@ -38,14 +41,26 @@ export function login() {
   window.walletConnection.requestSignIn(nearConfig.contractName)
 }
 
-export async function set_greeting(message){
-  let response = await window.contract.set_greeting({
-    args:{message: message}
+export async function add_record(record){
+  let response = await window.contract.add_record({
+    args: record,
+    amount: RECORD_DEPOSIT,
   })
   return response
 }
 
-export async function get_greeting(){
-  let greeting = await window.contract.get_greeting()
-  return greeting
-}
\ No newline at end of file
+export async function delete_record(id){
+  let response = await window.contract.delete_record({
+    args: { id: id }
+  })
+  return response
+}
+
+// Views can't tell who is asking, so records are read with a call the contract
+// can check the caller of
+export async function read_records(start, limit){
+  let records = await window.contract.read_record({
+    args: { start: start, limit: limit }
+  })
+  return records || []
+}
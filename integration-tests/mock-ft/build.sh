@@ -0,0 +1,5 @@
+#!/bin/bash 
+
+cargo build --all --target wasm32-unknown-unknown --release
+mkdir -p ./res
+cp target/wasm32-unknown-unknown/release/*.wasm ./res/
@@ -0,0 +1,96 @@
+// Minimal NEP-141 token used by the sandbox tests to pay med_block in tokens.
+// Only what the tests need is implemented: no storage management or events.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::{env, ext_contract, near_bindgen, AccountId, Gas, PanicOnDefault, PromiseOrValue, PromiseResult};
+
+const GAS_FOR_ON_TRANSFER: Gas = Gas(50_000_000_000_000);
+const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas(10_000_000_000_000);
+
+#[allow(dead_code)]
+#[ext_contract(ext_receiver)]
+trait FungibleTokenReceiver {
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockFungibleToken {
+    balances: LookupMap<AccountId, u128>,
+}
+
+#[near_bindgen]
+impl MockFungibleToken {
+
+    // Mint the whole supply to the owner
+
+    #[init]
+    pub fn new(owner_id: AccountId, total_supply: U128) -> Self {
+        let mut balances = LookupMap::new(b"b");
+        balances.insert(&owner_id, &total_supply.0);
+        Self { balances }
+    }
+
+    // Accounts don't need registering with the mock, kept so callers can use the real flow
+
+    #[payable]
+    pub fn storage_deposit(&mut self, account_id: Option<AccountId>) {
+        let _ = account_id;
+    }
+
+    pub fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        U128(self.balances.get(&account_id).unwrap_or(0))
+    }
+
+    #[payable]
+    pub fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        let _ = memo;
+        assert_eq!(env::attached_deposit(), 1, "Requires attached deposit of exactly 1 yoctoNEAR");
+        self.internal_transfer(&env::predecessor_account_id(), &receiver_id, amount.0);
+    }
+
+    #[payable]
+    pub fn ft_transfer_call(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>, msg: String) -> PromiseOrValue<U128> {
+        let _ = memo;
+        assert_eq!(env::attached_deposit(), 1, "Requires attached deposit of exactly 1 yoctoNEAR");
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer(&sender_id, &receiver_id, amount.0);
+
+        ext_receiver::ext(receiver_id.clone())
+            .with_static_gas(GAS_FOR_ON_TRANSFER)
+            .ft_on_transfer(sender_id.clone(), amount, msg)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .ft_resolve_transfer(sender_id, receiver_id, amount)
+            )
+            .into()
+    }
+
+    // Give back whatever the receiver did not use, or everything if it failed
+
+    #[private]
+    pub fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128 {
+        let unused = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .map(|unused| unused.0.min(amount.0))
+                .unwrap_or(amount.0),
+            _ => amount.0,
+        };
+        let refund = unused.min(self.balances.get(&receiver_id).unwrap_or(0));
+        if refund > 0 {
+            self.internal_transfer(&receiver_id, &sender_id, refund);
+        }
+        U128(amount.0 - refund)
+    }
+
+    fn internal_transfer(&mut self, sender_id: &AccountId, receiver_id: &AccountId, amount: u128) {
+        let sender_balance = self.balances.get(sender_id).unwrap_or(0);
+        assert!(sender_balance >= amount, "Not enough balance");
+        self.balances.insert(sender_id, &(sender_balance - amount));
+        let receiver_balance = self.balances.get(receiver_id).unwrap_or(0);
+        self.balances.insert(receiver_id, &(receiver_balance + amount));
+    }
+}
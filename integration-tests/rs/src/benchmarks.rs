@@ -0,0 +1,234 @@
+//! Measures gas burnt and storage used by the record methods as a patient's
+//! history grows, and writes a report. Pass an earlier report's JSON as
+//! `BENCH_BASELINE` to fail when any number grows more than 10% past it.
+//!
+//!     cargo run --release --example benchmarks [report.md]
+
+use med_block_types::med_record::MedRecord;
+use near_units::parse_near;
+use serde_json::{json, Value};
+use workspaces::prelude::*;
+use workspaces::{network::Sandbox, Account, Contract, Worker};
+
+const MED_BLOCK_WASM_FILEPATH: &str = "../../contract/res/med_block.wasm";
+const DEFAULT_REPORT_FILEPATH: &str = "target/gas-report.md";
+
+// History lengths measured at
+const SIZES: [u64; 4] = [1, 100, 1_000, 10_000];
+// Records added per call while growing the history, the contract's default limit
+const BATCH: u64 = 50;
+// Growth over the baseline that counts as a regression
+const TOLERANCE: f64 = 1.1;
+
+const MAX_GAS: u64 = 300_000_000_000_000;
+
+// What one history length costs, None when the history couldn't be grown that far
+struct Row {
+    records: u64,
+    measured: Option<Measured>,
+}
+
+struct Measured {
+    add_gas: u64,
+    add_bytes: u64,
+    read_gas: u64,
+    delete_gas: u64,
+    delete_bytes: u64,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let report_path = std::env::args().nth(1).unwrap_or_else(|| DEFAULT_REPORT_FILEPATH.to_string());
+    let worker = workspaces::sandbox().await?;
+    let contract = worker.dev_deploy(&std::fs::read(MED_BLOCK_WASM_FILEPATH)?).await?;
+    contract
+        .call(&worker, "new")
+        .args_json(json!({ "owner_id": contract.id() }))?
+        .transact()
+        .await?;
+    // Let the history grow past the default per-patient cap to the largest size measured
+    let mut limits: Value = contract.view(&worker, "get_record_limits", vec![]).await?.json()?;
+    limits["max_records_per_patient"] = json!(SIZES[SIZES.len() - 1] + 1);
+    contract
+        .call(&worker, "set_record_limits")
+        .args_json(json!({ "limits": limits }))?
+        .transact()
+        .await?
+        .into_result()?;
+
+    let patient = worker
+        .root_account()
+        .create_subaccount(&worker, "patient")
+        .initial_balance(parse_near!("1000 N"))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let mut rows = vec![];
+    let mut history = 0;
+    for size in SIZES {
+        while history < size {
+            let count = BATCH.min(size - history);
+            if !add_records(&patient, &contract, &worker, count).await? {
+                break;
+            }
+            history += count;
+        }
+        let measured = if history == size { Some(measure(&patient, &contract, &worker, history).await?) } else { None };
+        println!("      Measured {} records", size);
+        rows.push(Row { records: size, measured });
+    }
+
+    std::fs::write(&report_path, markdown(&rows))?;
+    std::fs::write(format!("{}.json", report_path), serde_json::to_string_pretty(&to_json(&rows))?)?;
+    println!("      Report written to {}", report_path);
+
+    if let Ok(baseline_path) = std::env::var("BENCH_BASELINE") {
+        let baseline: Value = serde_json::from_str(&std::fs::read_to_string(baseline_path)?)?;
+        let regressions = compare(&baseline, &to_json(&rows));
+        for regression in &regressions {
+            println!("      Regressed ❌ {}", regression);
+        }
+        anyhow::ensure!(regressions.is_empty(), "{} measurements regressed", regressions.len());
+        println!("      Passed ✅ no measurement grew past the baseline");
+    }
+    Ok(())
+}
+
+fn record(n: u64) -> Value {
+    json!({
+        "diagnosis": format!("Malaria {}", n),
+        "hospital_name": "CGH",
+        "medicine_administered": "Coartem",
+        "date_of_admission": "01/05/2022",
+        "date_of_release": "03/05/2022",
+        "allergies_recorded": "None",
+        "price": 1000
+    })
+}
+
+// Grow the history, false once a batch no longer fits in a transaction
+async fn add_records(patient: &Account, contract: &Contract, worker: &Worker<Sandbox>, count: u64) -> anyhow::Result<bool> {
+    let records: Vec<Value> = (0..count).map(record).collect();
+    let outcome = patient
+        .call(&worker, contract.id(), "add_records")
+        .args_json(json!({ "records": records }))?
+        .deposit(parse_near!("10 N"))
+        .gas(MAX_GAS)
+        .transact()
+        .await;
+    Ok(outcome.map(|outcome| outcome.is_success()).unwrap_or(false))
+}
+
+async fn storage_usage(contract: &Contract, worker: &Worker<Sandbox>) -> anyhow::Result<u64> {
+    Ok(contract.view_account(&worker).await?.storage_usage)
+}
+
+// Add one more record, read the first page and delete the new record again,
+// leaving the history as it was
+async fn measure(patient: &Account, contract: &Contract, worker: &Worker<Sandbox>, history: u64)
+    -> anyhow::Result<Measured> {
+    let before = storage_usage(contract, worker).await?;
+    let add = patient
+        .call(&worker, contract.id(), "add_record")
+        .args_json(record(history))?
+        .deposit(parse_near!("1 N"))
+        .gas(MAX_GAS)
+        .transact()
+        .await?;
+    anyhow::ensure!(add.is_success(), "add_record failed at {} records", history);
+    let added = storage_usage(contract, worker).await?;
+    // Ids aren't reused, so look up the one just given out
+    let new_record: Option<Vec<MedRecord>> = patient
+        .call(&worker, contract.id(), "read_record")
+        .args_json(json!({ "start": history, "limit": 1 }))?
+        .transact()
+        .await?
+        .json()?;
+    let new_record = new_record.unwrap_or_default();
+    anyhow::ensure!(!new_record.is_empty(), "record added at {} records not found", history);
+
+    let read = patient
+        .call(&worker, contract.id(), "read_record")
+        .args_json(json!({ "start": 0, "limit": 10 }))?
+        .gas(MAX_GAS)
+        .transact()
+        .await?;
+
+    let delete = patient
+        .call(&worker, contract.id(), "delete_record")
+        .args_json(json!({ "id": new_record[0].id }))?
+        .gas(MAX_GAS)
+        .transact()
+        .await?;
+    anyhow::ensure!(delete.is_success(), "delete_record failed at {} records", history);
+    let deleted = storage_usage(contract, worker).await?;
+
+    // Other writes to the contract between readings could shrink it, which would otherwise underflow
+    let add_bytes = added.checked_sub(before)
+        .ok_or_else(|| anyhow::anyhow!("storage shrank while adding at {} records", history))?;
+    let delete_bytes = added.checked_sub(deleted)
+        .ok_or_else(|| anyhow::anyhow!("storage grew while deleting at {} records", history))?;
+
+    Ok(Measured {
+        add_gas: add.total_gas_burnt,
+        add_bytes,
+        read_gas: read.total_gas_burnt,
+        delete_gas: delete.total_gas_burnt,
+        delete_bytes,
+    })
+}
+
+fn tgas(gas: u64) -> String {
+    format!("{:.2}", gas as f64 / 1e12)
+}
+
+fn markdown(rows: &[Row]) -> String {
+    let mut report = String::from("# Record method costs by history length\n\n");
+    report.push_str("| Records | add_record TGas | add_record bytes | read_record TGas | delete_record TGas | delete_record bytes freed |\n");
+    report.push_str("|---:|---:|---:|---:|---:|---:|\n");
+    for row in rows {
+        match &row.measured {
+            Some(m) => report.push_str(&format!("| {} | {} | {} | {} | {} | {} |\n", row.records, tgas(m.add_gas),
+                m.add_bytes, tgas(m.read_gas), tgas(m.delete_gas), m.delete_bytes)),
+            None => report.push_str(&format!("| {} | history too long to build in {} TGas | | | | |\n", row.records, MAX_GAS / 1_000_000_000_000)),
+        }
+    }
+    report
+}
+
+fn to_json(rows: &[Row]) -> Value {
+    Value::Array(rows
+        .iter()
+        .map(|row| match &row.measured {
+            Some(m) => json!({
+                "records": row.records,
+                "add_gas": m.add_gas,
+                "add_bytes": m.add_bytes,
+                "read_gas": m.read_gas,
+                "delete_gas": m.delete_gas,
+                "delete_bytes": m.delete_bytes,
+            }),
+            None => json!({ "records": row.records }),
+        })
+        .collect())
+}
+
+// Every measurement that grew past the baseline, or that the baseline had and is now missing
+fn compare(baseline: &Value, current: &Value) -> Vec<String> {
+    let mut regressions = vec![];
+    for (old, new) in baseline.as_array().into_iter().flatten().zip(current.as_array().into_iter().flatten()) {
+        if old.get("add_gas").is_some() && new.get("add_gas").is_none() {
+            regressions.push(format!("{} records can no longer be reached", new["records"]));
+            continue;
+        }
+        for key in ["add_gas", "add_bytes", "read_gas", "delete_gas", "delete_bytes"] {
+            if let (Some(was), Some(now)) = (old[key].as_u64(), new[key].as_u64()) {
+                if now as f64 > was as f64 * TOLERANCE {
+                    regressions.push(format!("{} at {} records went from {} to {}", key, new["records"], was, now));
+                }
+            }
+        }
+    }
+    regressions
+}
@@ -0,0 +1,203 @@
+use near_units::parse_near;
+use serde_json::json;
+use workspaces::prelude::*;
+use workspaces::{network::Sandbox, Account, Contract, Worker};
+
+const MED_BLOCK_WASM_FILEPATH: &str = "../../contract/res/med_block.wasm";
+const MOCK_FT_WASM_FILEPATH: &str = "../mock-ft/res/mock_ft.wasm";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let worker = workspaces::sandbox().await?;
+    let contract = worker.dev_deploy(&std::fs::read(MED_BLOCK_WASM_FILEPATH)?).await?;
+    let token = worker.dev_deploy(&std::fs::read(MOCK_FT_WASM_FILEPATH)?).await?;
+
+    // create accounts
+    let owner = worker.root_account();
+    let alice = owner
+        .create_subaccount(&worker, "alice")
+        .initial_balance(parse_near!("30 N"))
+        .transact()
+        .await?
+        .into_result()?;
+    let hospital = owner
+        .create_subaccount(&worker, "hospital")
+        .initial_balance(parse_near!("30 N"))
+        .transact()
+        .await?
+        .into_result()?;
+
+    // the contract is owned by itself, accepts the mock token at 10 units per byte
+    // and knows the hospital as the provider behind CGH records
+    contract
+        .call(&worker, "new")
+        .args_json(json!({ "owner_id": contract.id() }))?
+        .transact()
+        .await?;
+    contract
+        .call(&worker, "verify_provider")
+        .args_json(json!({ "provider_id": hospital.id(), "name": "CGH" }))?
+        .deposit(parse_near!("1 N"))
+        .transact()
+        .await?;
+    contract
+        .call(&worker, "whitelist_token")
+        .args_json(json!({ "token_id": token.id(), "storage_byte_price": "10" }))?
+        .deposit(parse_near!("1 N"))
+        .transact()
+        .await?;
+    token
+        .call(&worker, "new")
+        .args_json(json!({ "owner_id": alice.id(), "total_supply": "1000000000" }))?
+        .transact()
+        .await?;
+
+    // begin tests
+    test_buy_storage(&alice, &contract, &token, &worker).await?;
+    test_pay_bill(&alice, &hospital, &contract, &token, &worker).await?;
+    test_withdraw_credit(&alice, &contract, &token, &worker).await?;
+    test_unlisted_token_refunded(&owner, &alice, &contract, &worker).await?;
+    Ok(())
+}
+
+async fn ft_balance(account: &Account, token: &Contract, worker: &Worker<Sandbox>) -> anyhow::Result<u128> {
+    let balance: String = token
+        .view(&worker, "ft_balance_of", json!({ "account_id": account.id() }).to_string().into_bytes())
+        .await?
+        .json()?;
+    Ok(balance.parse()?)
+}
+
+async fn test_buy_storage(
+    user: &Account,
+    contract: &Contract,
+    token: &Contract,
+    worker: &Worker<Sandbox>,
+) -> anyhow::Result<()> {
+    let before = ft_balance(user, token, worker).await?;
+
+    user.call(&worker, token.id(), "ft_transfer_call")
+        .args_json(json!({ "receiver_id": contract.id(), "amount": "10005", "msg": "\"buy_storage\"" }))?
+        .deposit(1)
+        .gas(100_000_000_000_000)
+        .transact()
+        .await?;
+
+    // 1000 bytes are bought and the 5 units that don't make up a byte come back
+    assert_eq!(before - 10_000, ft_balance(user, token, worker).await?);
+    let credit: serde_json::Value = contract
+        .view(&worker, "get_storage_credit", json!({ "account_id": user.id() }).to_string().into_bytes())
+        .await?
+        .json()?;
+    // the credit's own entry comes out of the bytes bought
+    assert_eq!(credit["available"].as_u64().unwrap() + credit["used"].as_u64().unwrap(), 1000);
+    println!("      Passed ✅ buys storage with tokens");
+    Ok(())
+}
+
+async fn test_pay_bill(
+    user: &Account,
+    hospital: &Account,
+    contract: &Contract,
+    token: &Contract,
+    worker: &Worker<Sandbox>,
+) -> anyhow::Result<()> {
+    user.call(&worker, contract.id(), "add_record")
+        .args_json(json!({
+            "diagnosis": "Malaria",
+            "hospital_name": "CGH",
+            "medicine_administered": "Coartem",
+            "date_of_admission": "01/05/2022",
+            "date_of_release": "03/05/2022",
+            "allergies_recorded": "None",
+            "price": 1000
+        }))?
+        .transact()
+        .await?;
+
+    hospital.call(&worker, contract.id(), "issue_bill")
+        .args_json(json!({ "patient_id": user.id(), "record_id": 0, "amount": "1000", "token_id": token.id() }))?
+        .deposit(parse_near!("1 N"))
+        .transact()
+        .await?;
+
+    let msg = json!({ "pay_bill": { "record_id": 0 } }).to_string();
+    user.call(&worker, token.id(), "ft_transfer_call")
+        .args_json(json!({ "receiver_id": contract.id(), "amount": "100000", "msg": msg }))?
+        .deposit(1)
+        .gas(100_000_000_000_000)
+        .transact()
+        .await?;
+
+    let bill: serde_json::Value = contract
+        .view(&worker, "get_bill", json!({ "patient_id": user.id(), "record_id": 0 }).to_string().into_bytes())
+        .await?
+        .json()?;
+    assert_eq!(bill["status"], "Escrowed");
+    let escrowed: u128 = bill["amount"].as_str().unwrap().parse()?;
+
+    // confirming releases the escrowed tokens to the hospital
+    user.call(&worker, contract.id(), "confirm_bill")
+        .args_json(json!({ "record_id": 0 }))?
+        .gas(100_000_000_000_000)
+        .transact()
+        .await?;
+    assert_eq!(escrowed, ft_balance(hospital, token, worker).await?);
+    println!("      Passed ✅ pays a bill with tokens");
+    Ok(())
+}
+
+async fn test_withdraw_credit(
+    user: &Account,
+    contract: &Contract,
+    token: &Contract,
+    worker: &Worker<Sandbox>,
+) -> anyhow::Result<()> {
+    let before = ft_balance(user, token, worker).await?;
+    let credit: serde_json::Value = contract
+        .view(&worker, "get_storage_credit", json!({ "account_id": user.id() }).to_string().into_bytes())
+        .await?
+        .json()?;
+
+    // the bytes the record doesn't use come back at the price they were bought at
+    user.call(&worker, contract.id(), "withdraw_storage_credit")
+        .gas(100_000_000_000_000)
+        .transact()
+        .await?;
+    let available = credit["available"].as_u64().unwrap() as u128;
+    assert_eq!(before + available * 10, ft_balance(user, token, worker).await?);
+    println!("      Passed ✅ withdraws unused storage credit");
+    Ok(())
+}
+
+async fn test_unlisted_token_refunded(
+    owner: &Account,
+    user: &Account,
+    contract: &Contract,
+    worker: &Worker<Sandbox>,
+) -> anyhow::Result<()> {
+    // a second token that was never whitelisted gets the whole transfer back
+    let other = owner
+        .create_subaccount(&worker, "other-token")
+        .initial_balance(parse_near!("10 N"))
+        .transact()
+        .await?
+        .into_result()?;
+    let other = other.deploy(&worker, &std::fs::read(MOCK_FT_WASM_FILEPATH)?).await?.into_result()?;
+    other
+        .call(&worker, "new")
+        .args_json(json!({ "owner_id": user.id(), "total_supply": "1000" }))?
+        .transact()
+        .await?;
+
+    user.call(&worker, other.id(), "ft_transfer_call")
+        .args_json(json!({ "receiver_id": contract.id(), "amount": "1000", "msg": "\"buy_storage\"" }))?
+        .deposit(1)
+        .gas(100_000_000_000_000)
+        .transact()
+        .await?;
+
+    assert_eq!(1000, ft_balance(user, &other, worker).await?);
+    println!("      Passed ✅ refunds tokens that are not accepted");
+    Ok(())
+}
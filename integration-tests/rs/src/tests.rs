@@ -1,15 +1,20 @@
+use med_block_types::med_record::MedRecord;
 use near_units::parse_near;
 use serde_json::json;
 use workspaces::prelude::*;
-use workspaces::{network::Sandbox, Account, Contract, Worker};
+use workspaces::result::CallExecutionDetails;
+use workspaces::{network::Sandbox, Account, AccountId, Contract, Worker};
 
-const WASM_FILEPATH: &str = "../../out/main.wasm";
+const MED_BLOCK_WASM_FILEPATH: &str = "../../contract/res/med_block.wasm";
+
+// The sandbox never raises gas above the genesis minimum
+const GAS_PRICE: u128 = 100_000_000;
+const STORAGE_BYTE_COST: u128 = 10_000_000_000_000_000_000;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let worker = workspaces::sandbox().await?;
-    let wasm = std::fs::read(WASM_FILEPATH)?;
-    let contract = worker.dev_deploy(&wasm).await?;
+    let contract = worker.dev_deploy(&std::fs::read(MED_BLOCK_WASM_FILEPATH)?).await?;
 
     // create accounts
     let owner = worker.root_account();
@@ -19,48 +24,179 @@ async fn main() -> anyhow::Result<()> {
         .transact()
         .await?
         .into_result()?;
+    let bob = owner
+        .create_subaccount(&worker, "bob")
+        .initial_balance(parse_near!("30 N"))
+        .transact()
+        .await?
+        .into_result()?;
+
+    // the contract is owned by itself
+    contract
+        .call(&worker, "new")
+        .args_json(json!({ "owner_id": contract.id() }))?
+        .transact()
+        .await?;
 
     // begin tests
-    test_default_message(&alice, &contract, &worker).await?;
-    test_changes_message(&alice, &contract, &worker).await?;
+    test_add_pays_for_storage(&alice, &contract, &worker).await?;
+    test_reads_are_per_account(&alice, &bob, &contract, &worker).await?;
+    test_reads_follow_grants(&alice, &bob, &contract, &worker).await?;
+    test_delete_refunds_storage(&alice, &contract, &worker).await?;
+    test_delete_is_all_or_nothing(&bob, &contract, &worker).await?;
     Ok(())
 }
 
-async fn test_default_message(
+fn record(diagnosis: &str) -> serde_json::Value {
+    json!({
+        "diagnosis": diagnosis,
+        "hospital_name": "CGH",
+        "medicine_administered": "Coartem",
+        "date_of_admission": "01/05/2022",
+        "date_of_release": "03/05/2022",
+        "allergies_recorded": "None",
+        "price": 1000
+    })
+}
+
+async fn add_record(user: &Account, diagnosis: &str, contract: &Contract, worker: &Worker<Sandbox>)
+    -> anyhow::Result<CallExecutionDetails> {
+    Ok(user
+        .call(&worker, contract.id(), "add_record")
+        .args_json(record(diagnosis))?
+        .deposit(parse_near!("1 N"))
+        .transact()
+        .await?)
+}
+
+async fn read_records(user: &Account, contract: &Contract, worker: &Worker<Sandbox>) -> anyhow::Result<Vec<MedRecord>> {
+    let records: Option<Vec<MedRecord>> = user
+        .call(&worker, contract.id(), "read_record")
+        .args_json(json!({ "start": 0, "limit": 10 }))?
+        .transact()
+        .await?
+        .json()?;
+    Ok(records.unwrap_or_default())
+}
+
+async fn read_shared_records(reader: &Account, patient: &AccountId, contract: &Contract, worker: &Worker<Sandbox>)
+    -> anyhow::Result<Vec<MedRecord>> {
+    let outcome = reader
+        .call(&worker, contract.id(), "read_shared_records")
+        .args_json(json!({ "patient_id": patient, "start": 0, "limit": 10 }))?
+        .transact()
+        .await?;
+    anyhow::ensure!(outcome.is_success(), "read_shared_records failed");
+    Ok(outcome.json()?)
+}
+
+// What a call cost its signer in gas, all of it bought at the same price
+fn gas_cost(outcome: &CallExecutionDetails) -> u128 {
+    outcome.total_gas_burnt as u128 * GAS_PRICE
+}
+
+async fn test_add_pays_for_storage(
     user: &Account,
     contract: &Contract,
     worker: &Worker<Sandbox>,
 ) -> anyhow::Result<()> {
-    let message: String = user
-        .call(&worker, contract.id(), "get_greeting")
-        .args_json(json!({}))?
+    let balance = user.view_account(&worker).await?.balance;
+    let storage = contract.view_account(&worker).await?.storage_usage;
+
+    let outcome = add_record(user, "Malaria", contract, worker).await?;
+    assert!(outcome.is_success());
+
+    // of the 1 N attached only the new storage is kept, the rest comes back
+    let stored = (contract.view_account(&worker).await?.storage_usage - storage) as u128;
+    assert!(stored > 0);
+    let spent = balance - user.view_account(&worker).await?.balance;
+    assert_eq!(stored * STORAGE_BYTE_COST + gas_cost(&outcome), spent);
+    println!("      Passed ✅ pays exactly for the storage a record takes");
+    Ok(())
+}
+
+async fn test_reads_are_per_account(
+    alice: &Account,
+    bob: &Account,
+    contract: &Contract,
+    worker: &Worker<Sandbox>,
+) -> anyhow::Result<()> {
+    add_record(bob, "Typhoid", contract, worker).await?;
+    add_record(bob, "Flu", contract, worker).await?;
+
+    let alices = read_records(alice, contract, worker).await?;
+    let bobs = read_records(bob, contract, worker).await?;
+    assert_eq!(vec!["Malaria"], alices.iter().map(|record| record.diagnosis.as_str()).collect::<Vec<_>>());
+    assert_eq!(vec!["Typhoid", "Flu"], bobs.iter().map(|record| record.diagnosis.as_str()).collect::<Vec<_>>());
+    println!("      Passed ✅ each account reads only its own records");
+    Ok(())
+}
+
+async fn test_reads_follow_grants(
+    alice: &Account,
+    bob: &Account,
+    contract: &Contract,
+    worker: &Worker<Sandbox>,
+) -> anyhow::Result<()> {
+    assert!(read_shared_records(bob, alice.id(), contract, worker).await.is_err());
+
+    alice
+        .call(&worker, contract.id(), "grant_access")
+        .args_json(json!({ "grantee_id": bob.id(), "record_ids": null }))?
+        .deposit(parse_near!("1 N"))
         .transact()
-        .await?
-        .json()?;
+        .await?;
 
-    assert_eq!(message, "Hello".to_string());
-    println!("      Passed ✅ gets default message");
+    let shared = read_shared_records(bob, alice.id(), contract, worker).await?;
+    assert_eq!(1, shared.len());
+    assert_eq!("Malaria", shared[0].diagnosis);
+    println!("      Passed ✅ another account reads records once granted");
     Ok(())
 }
 
-async fn test_changes_message(
+async fn test_delete_refunds_storage(
     user: &Account,
     contract: &Contract,
     worker: &Worker<Sandbox>,
 ) -> anyhow::Result<()> {
-    user.call(&worker, contract.id(), "set_greeting")
-        .args_json(json!({"message": "Howdy"}))?
+    let balance = user.view_account(&worker).await?.balance;
+    let storage = contract.view_account(&worker).await?.storage_usage;
+
+    let outcome = user
+        .call(&worker, contract.id(), "delete_record")
+        .args_json(json!({ "id": 0 }))?
         .transact()
         .await?;
+    let deleted: Option<MedRecord> = outcome.json()?;
+    assert_eq!("Malaria", deleted.unwrap().diagnosis);
 
-    let message: String = user
-        .call(&worker, contract.id(), "get_greeting")
-        .args_json(json!({}))?
+    // every byte freed is paid back to the patient
+    let freed = (storage - contract.view_account(&worker).await?.storage_usage) as u128;
+    assert!(freed > 0);
+    let balance_after = user.view_account(&worker).await?.balance;
+    assert_eq!(balance + freed * STORAGE_BYTE_COST - gas_cost(&outcome), balance_after);
+    assert!(read_records(user, contract, worker).await?.is_empty());
+    println!("      Passed ✅ refunds exactly the storage a deleted record freed");
+    Ok(())
+}
+
+async fn test_delete_is_all_or_nothing(
+    user: &Account,
+    contract: &Contract,
+    worker: &Worker<Sandbox>,
+) -> anyhow::Result<()> {
+    let storage = contract.view_account(&worker).await?.storage_usage;
+
+    // record 7 doesn't exist, so record 0 has to survive too
+    let outcome = user
+        .call(&worker, contract.id(), "delete_records")
+        .args_json(json!({ "ids": [0, 7] }))?
         .transact()
-        .await?
-        .json()?;
+        .await;
+    assert!(outcome.map(|outcome| !outcome.is_success()).unwrap_or(true));
 
-    assert_eq!(message, "Howdy".to_string());
-    println!("      Passed ✅ changes message");
+    assert_eq!(storage, contract.view_account(&worker).await?.storage_usage);
+    assert_eq!(2, read_records(user, contract, worker).await?.len());
+    println!("      Passed ✅ deleting a missing record leaves the rest in place");
     Ok(())
-}
\ No newline at end of file
+}
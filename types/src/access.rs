@@ -0,0 +1,55 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::AccountId;
+
+use crate::med_record::MedRecord;
+
+// Read access a patient has given another account
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Grant {
+    pub grantee_id: AccountId,
+    // Records the grantee may read, None for all of them
+    pub record_ids: Option<Vec<u64>>,
+}
+
+// The parts of a record a patient can choose to make public, leaving out anything clinical
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RecordSummary {
+    pub id: u64,
+    pub hospital_name: String,
+    pub date_of_admission: String,
+    pub date_of_release: String,
+}
+
+impl From<MedRecord> for RecordSummary {
+    fn from(record: MedRecord) -> Self {
+        Self {
+            id: record.id,
+            hospital_name: record.hospital_name,
+            date_of_admission: record.date_of_admission,
+            date_of_release: record.date_of_release,
+        }
+    }
+}
+
+impl Grant {
+    pub fn covers(&self, record_id: u64) -> bool {
+        self.record_ids.as_ref().map(|ids| ids.contains(&record_id)).unwrap_or(true)
+    }
+
+    // Widen the grant to also cover the given records
+    pub fn extend(&mut self, record_ids: Option<Vec<u64>>) {
+        match (&mut self.record_ids, record_ids) {
+            (Some(current), Some(extra)) => {
+                for id in extra {
+                    if !current.contains(&id) {
+                        current.push(id);
+                    }
+                }
+            }
+            (current, _) => *current = None,
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+
+use crate::fhir;
+use crate::med_record::MedRecord;
+
+// What visits are counted by across the whole contract
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum Dimension {
+    Diagnosis,
+    Hospital,
+    // Month of admission as yyyy-mm
+    Month,
+}
+
+pub const DIMENSIONS: [Dimension; 3] = [Dimension::Diagnosis, Dimension::Hospital, Dimension::Month];
+
+impl Dimension {
+    // The group a record falls in, None when it can't be placed
+    pub fn group(&self, record: &MedRecord) -> Option<String> {
+        let group = match self {
+            Dimension::Diagnosis => record.diagnosis.trim().to_lowercase(),
+            Dimension::Hospital => record.hospital_name.trim().to_string(),
+            Dimension::Month => fhir::fhir_date(&record.date_of_admission)?[..7].to_string(),
+        };
+        (!group.is_empty()).then_some(group)
+    }
+}
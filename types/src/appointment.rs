@@ -0,0 +1,38 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::AccountId;
+
+// How long after a slot ends the hospital has to close the booking before the patient
+// can take their deposit back, in milliseconds
+pub const DEPOSIT_GRACE_PERIOD: u64 = 7 * 24 * 60 * 60 * 1000;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AppointmentStatus {
+    Open,
+    Booked,
+    Completed,
+    NoShow,
+    // The hospital called off the booking and refunded the patient
+    Cancelled,
+    // The hospital never closed the booking, so the patient took their deposit back
+    Lapsed,
+}
+
+// A slot a hospital has opened, and the patient booked into it if any
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Appointment {
+    pub id: u64,
+    pub hospital_id: AccountId,
+    // Unix epoch milliseconds
+    pub starts_at: U64,
+    pub ends_at: U64,
+    // Held from the patient on booking, kept by the hospital if they don't turn up
+    pub no_show_deposit: U128,
+    pub patient_id: Option<AccountId>,
+    pub status: AppointmentStatus,
+    // Record written when the appointment was completed
+    pub record_id: Option<u64>,
+}
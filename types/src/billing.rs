@@ -0,0 +1,20 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::json_types::U128;
+use near_sdk::AccountId;
+
+use crate::med_record::PaymentStatus;
+
+// A hospital's bill for one of a patient's records and the funds put up for it
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Bill {
+    pub patient_id: AccountId,
+    pub record_id: u64,
+    pub hospital_id: AccountId,
+    pub amount: U128,
+    // Token the amount is in and the bill is paid in, None for yoctoNEAR
+    pub token_id: Option<AccountId>,
+    pub status: PaymentStatus,
+    pub dispute_reason: Option<String>,
+}
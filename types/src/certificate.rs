@@ -0,0 +1,123 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::serde_json::json;
+use near_sdk::AccountId;
+
+pub type TokenId = String;
+
+// What public token metadata calls every certificate, so listing a patient's tokens
+// says nothing about their health
+pub const CERTIFICATE_TITLE: &str = "Health certificate";
+
+// A patient's go-ahead for a provider to issue a certificate from one of their records
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CertificateApproval {
+    pub issuer_id: AccountId,
+    // Whether verifiers may see the record's diagnosis as the certificate's title
+    pub disclose_diagnosis: bool,
+}
+
+// A vaccination or health certificate a provider issued from one of a patient's records
+// with the patient's approval. Only the title and validity are shown to verifiers,
+// never the record itself.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Certificate {
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub issuer_id: AccountId,
+    pub record_id: u64,
+    // The record's diagnosis when the patient chose to disclose it to verifiers
+    pub title: Option<String>,
+    pub description: Option<String>,
+    // Unix epoch milliseconds
+    pub issued_at: u64,
+    pub starts_at: Option<u64>,
+    pub expires_at: Option<u64>,
+    pub revoked: bool,
+}
+
+// NEP-177 contract metadata
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NFTContractMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub base_uri: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<String>,
+}
+
+// NEP-177 token metadata, timestamps are epoch milliseconds as strings
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub media: Option<String>,
+    pub media_hash: Option<String>,
+    pub copies: Option<u64>,
+    pub issued_at: Option<String>,
+    pub expires_at: Option<String>,
+    pub starts_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub extra: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<String>,
+}
+
+// NEP-171 token
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Token {
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub metadata: Option<TokenMetadata>,
+}
+
+// What a verifier learns about a certificate
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CertificateVerification {
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub issuer_id: AccountId,
+    // The record's diagnosis if the patient disclosed it, otherwise the neutral title
+    pub title: String,
+    pub issuer_verified: bool,
+    pub revoked: bool,
+    pub started: bool,
+    pub expired: bool,
+    pub valid: bool,
+}
+
+impl From<Certificate> for Token {
+    fn from(certificate: Certificate) -> Self {
+        let extra = json!({
+            "issuer_id": certificate.issuer_id,
+            "record_id": certificate.record_id,
+            "revoked": certificate.revoked,
+        });
+        Token {
+            token_id: certificate.token_id,
+            owner_id: certificate.owner_id,
+            metadata: Some(TokenMetadata {
+                title: Some(String::from(CERTIFICATE_TITLE)),
+                description: certificate.description,
+                media: None,
+                media_hash: None,
+                copies: Some(1),
+                issued_at: Some(certificate.issued_at.to_string()),
+                expires_at: certificate.expires_at.map(|at| at.to_string()),
+                starts_at: certificate.starts_at.map(|at| at.to_string()),
+                updated_at: None,
+                extra: Some(extra.to_string()),
+                reference: None,
+                reference_hash: None,
+            }),
+        }
+    }
+}
@@ -0,0 +1,503 @@
+//! Export of a patient's records as an HL7 FHIR R4 Bundle, and import back.
+//!
+//! Each `MedRecord` becomes an Encounter for the visit, a Condition for the
+//! diagnosis, a MedicationAdministration for the medicine given and, when
+//! anything was recorded, an AllergyIntolerance, all pointing back at a single
+//! Patient resource for the account. A public `RecordSummary` only has the
+//! visit, so it becomes just the Encounter. The functions are pure so EHR
+//! integrations can run them off-chain on records fetched from the contract.
+//!
+//! `import` goes the other way for onboarding a hospital's history: each
+//! Encounter in a Bundle becomes a `NewRecord` ready for `add_records`, with
+//! the Condition, MedicationAdministration and AllergyIntolerance resources
+//! that reference it filling in the rest. Entries that can't be turned into a
+//! record are reported rather than dropped, and `required_deposit` gives the
+//! storage deposit to attach before anything is submitted.
+
+use near_sdk::borsh::BorshSerialize;
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::serde_json::{json, Value};
+use near_sdk::AccountId;
+
+use crate::access::RecordSummary;
+use crate::med_record::{MedRecord, NewRecord};
+
+/// Identifier system used for the patient's NEAR account.
+pub const ACCOUNT_SYSTEM: &str = "https://near.org/account-id";
+
+/// Cost of a byte of contract storage in yoctoNEAR.
+pub const STORAGE_BYTE_COST: u128 = 10_000_000_000_000_000_000;
+
+// Code system for the Encounter class
+const ACT_CODE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v3-ActCode";
+
+// Bytes NEAR charges for every storage entry on top of its key and value
+const STORAGE_ENTRY_OVERHEAD: u64 = 40;
+
+/// Records parsed from a Bundle along with the entries that couldn't be used.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Import {
+    pub records: Vec<NewRecord>,
+    pub errors: Vec<ImportError>,
+}
+
+/// Why a Bundle entry, identified by its index, was not imported.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ImportError {
+    pub entry: usize,
+    pub message: String,
+}
+
+/// Build a `collection` Bundle for a patient's records.
+pub fn bundle(patient_id: &AccountId, records: &[MedRecord]) -> Value {
+    let patient_ref = format!("Patient/{}", resource_id(patient_id.as_str()));
+
+    let mut entries = vec![entry(patient(patient_id))];
+    for record in records {
+        let encounter_ref = format!("Encounter/encounter-{}", record.id);
+        entries.push(entry(encounter(record.id, &record.hospital_name, &record.date_of_admission,
+            &record.date_of_release, record.author_id.as_ref(), &patient_ref)));
+        entries.push(entry(condition(record, &patient_ref, &encounter_ref)));
+        entries.push(entry(medication_administration(record, &patient_ref, &encounter_ref)));
+        if has_allergies(record) {
+            entries.push(entry(allergy_intolerance(record, &patient_ref, &encounter_ref)));
+        }
+    }
+
+    collection(entries)
+}
+
+/// Build a `collection` Bundle for the public summary of a patient's records,
+/// with an Encounter for each visit and nothing of what was treated.
+pub fn summary_bundle(patient_id: &AccountId, summaries: &[RecordSummary]) -> Value {
+    let patient_ref = format!("Patient/{}", resource_id(patient_id.as_str()));
+
+    let mut entries = vec![entry(patient(patient_id))];
+    for summary in summaries {
+        entries.push(entry(encounter(summary.id, &summary.hospital_name, &summary.date_of_admission,
+            &summary.date_of_release, None, &patient_ref)));
+    }
+    collection(entries)
+}
+
+/// Convert a record date to a FHIR `date`, accepting `dd/mm/yyyy` and `yyyy-mm-dd`.
+pub fn fhir_date(date: &str) -> Option<String> {
+    let date = date.trim();
+    let parts: Vec<&str> = date.split(['/', '-']).collect();
+    if parts.len() != 3 || !parts.iter().all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit())) {
+        return None;
+    }
+    let (year, month, day) = if date.contains('/') {
+        (parts[2], parts[1], parts[0])
+    } else {
+        (parts[0], parts[1], parts[2])
+    };
+    let (month_number, day_number): (u32, u32) = (month.parse().ok()?, day.parse().ok()?);
+    if year.len() != 4 || !(1..=12).contains(&month_number) || !(1..=31).contains(&day_number) {
+        return None;
+    }
+    Some(format!("{}-{:02}-{:02}", year, month_number, day_number))
+}
+
+/// Parse a Bundle into records, one per Encounter. Prices aren't part of the
+/// clinical resources so imported records start at zero.
+pub fn import(bundle: &Value) -> Result<Import, String> {
+    if bundle["resourceType"] != "Bundle" {
+        return Err(String::from("Not a FHIR Bundle"));
+    }
+    let entries = bundle["entry"].as_array().map(Vec::as_slice).unwrap_or_default();
+
+    let mut drafts: Vec<Draft> = vec![];
+    let mut errors = vec![];
+    let mut error = |entry: usize, message: String| errors.push(ImportError { entry, message });
+
+    for (index, entry) in entries.iter().enumerate() {
+        if entry["resource"]["resourceType"] == "Encounter" {
+            drafts.push(Draft::from_encounter(index, &entry["resource"]));
+        }
+    }
+
+    for (index, entry) in entries.iter().enumerate() {
+        let resource = &entry["resource"];
+        let (encounter_ref, field) = match resource["resourceType"].as_str() {
+            Some("Encounter") | Some("Patient") => continue,
+            Some("Condition") => (&resource["encounter"], Field::Diagnosis(concept_text(&resource["code"]))),
+            Some("MedicationAdministration") => (
+                &resource["context"],
+                Field::Medicine(concept_text(&resource["medicationCodeableConcept"])),
+            ),
+            Some("AllergyIntolerance") => (&resource["encounter"], Field::Allergy(concept_text(&resource["code"]))),
+            Some(other) => {
+                error(index, format!("Unsupported resource type {}", other));
+                continue;
+            }
+            None => {
+                error(index, String::from("Entry has no resource"));
+                continue;
+            }
+        };
+
+        let reference = encounter_ref["reference"].as_str().unwrap_or_default();
+        let Some(draft) = drafts.iter_mut().find(|draft| draft.reference == reference) else {
+            error(index, format!("References unknown encounter {:?}", reference));
+            continue;
+        };
+        match field {
+            Field::Diagnosis(Some(text)) => draft.diagnoses.push(text),
+            Field::Medicine(Some(text)) => draft.medicines.push(text),
+            Field::Allergy(Some(text)) => draft.allergies.push(text),
+            _ => error(index, String::from("Resource has no text or coding display")),
+        }
+    }
+
+    let mut records = vec![];
+    for draft in drafts {
+        match draft.into_record() {
+            Ok(record) => records.push(record),
+            Err((entry, message)) => error(entry, message),
+        }
+    }
+    Ok(Import { records, errors })
+}
+
+/// Bytes of contract storage the records will take up once added, at most.
+/// Each record is assumed to bring a new hospital, diagnosis and admission date
+/// to the patient's totals and the contract wide visit counts, and adding a
+/// patient's first records creates their entries.
+pub fn storage_bytes(patient_id: &AccountId, records: &[NewRecord], new_patient: bool) -> u64 {
+    let record_bytes: u64 = records
+        .iter()
+        .map(|record| {
+            let record = MedRecord::new(u64::MAX, record.diagnosis.clone(), record.hospital_name.clone(),
+                record.medicine_administered.clone(), record.date_of_admission.clone(),
+                record.date_of_release.clone(), record.allergies_recorded.clone(), record.price as f64, None);
+            let hospital = 4 + record.hospital_name.len() as u64;
+            // The patient's totals keep a date and count, and a hospital, count and amount billed
+            let totals_bytes = (4 + 10 + 8) + (hospital + 8 + 8);
+            // Visit counts keep an entry per diagnosis, hospital and month, each with a
+            // count and two index entries naming the group
+            let visit_bytes: u64 = [4 + record.diagnosis.len() as u64, hospital, 4 + 7]
+                .iter()
+                .map(|group| 3 * (STORAGE_ENTRY_OVERHEAD + group + 16))
+                .sum();
+            // The record is kept along with its hash
+            record.try_to_vec().unwrap().len() as u64 + 32 + totals_bytes + visit_bytes
+        })
+        .sum();
+
+    if new_patient {
+        // Map prefix and account id key for both the records and their totals,
+        // then empty record and hash lists, next id and root, and empty totals
+        let key_bytes = 1 + patient_id.try_to_vec().unwrap().len() as u64;
+        let records_entry = STORAGE_ENTRY_OVERHEAD + key_bytes + 4 + 8 + 32 + 4;
+        let totals_entry = STORAGE_ENTRY_OVERHEAD + key_bytes + 8 + 4 + 8 + 4;
+        // In case these are the contract's first records, a group index per visit count dimension
+        let visit_entries = 3 * (STORAGE_ENTRY_OVERHEAD + 2 + 32);
+        record_bytes + records_entry + totals_entry + visit_entries
+    } else {
+        record_bytes
+    }
+}
+
+/// Deposit in yoctoNEAR to attach to `add_records`, anything unused is refunded.
+pub fn required_deposit(patient_id: &AccountId, records: &[NewRecord], new_patient: bool) -> u128 {
+    storage_bytes(patient_id, records, new_patient) as u128 * STORAGE_BYTE_COST
+}
+
+/// Convert a FHIR `date` or `dateTime` to the `dd/mm/yyyy` form records use.
+pub fn record_date(date: &str) -> Option<String> {
+    let date = fhir_date(date.get(..10)?)?;
+    Some(format!("{}/{}/{}", &date[8..10], &date[5..7], &date[..4]))
+}
+
+// FHIR ids only allow letters, digits, '-' and '.'
+fn resource_id(value: &str) -> String {
+    value.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '-' }).take(64).collect()
+}
+
+fn collection(entries: Vec<Value>) -> Value {
+    json!({
+        "resourceType": "Bundle",
+        "type": "collection",
+        "entry": entries,
+    })
+}
+
+fn entry(resource: Value) -> Value {
+    json!({ "resource": resource })
+}
+
+fn has_allergies(record: &MedRecord) -> bool {
+    let allergies = record.allergies_recorded.trim();
+    !allergies.is_empty() && !allergies.eq_ignore_ascii_case("none")
+}
+
+// Admission to release, leaving out dates that can't be read and the period
+// itself when neither can
+fn period(date_of_admission: &str, date_of_release: &str) -> Option<Value> {
+    let (start, end) = (fhir_date(date_of_admission), fhir_date(date_of_release));
+    if start.is_none() && end.is_none() {
+        return None;
+    }
+    let mut period = json!({});
+    if let Some(start) = start {
+        period["start"] = json!(start);
+    }
+    if let Some(end) = end {
+        period["end"] = json!(end);
+    }
+    Some(period)
+}
+
+// Records don't say how the patient was seen, so a stay past the day of admission
+// is inpatient, a same day visit ambulatory and anything else unknown
+fn encounter_class(date_of_admission: &str, date_of_release: &str) -> Value {
+    let (system, code, display) = match (fhir_date(date_of_admission), fhir_date(date_of_release)) {
+        (Some(start), Some(end)) if start == end => (ACT_CODE_SYSTEM, "AMB", "ambulatory"),
+        (Some(start), Some(end)) if start < end => (ACT_CODE_SYSTEM, "IMP", "inpatient encounter"),
+        _ => ("http://terminology.hl7.org/CodeSystem/v3-NullFlavor", "UNK", "unknown"),
+    };
+    json!({ "system": system, "code": code, "display": display })
+}
+
+fn patient(patient_id: &AccountId) -> Value {
+    json!({
+        "resourceType": "Patient",
+        "id": resource_id(patient_id.as_str()),
+        "identifier": [{ "system": ACCOUNT_SYSTEM, "value": patient_id }],
+    })
+}
+
+fn encounter(id: u64, hospital_name: &str, date_of_admission: &str, date_of_release: &str,
+    author_id: Option<&AccountId>, patient_ref: &str) -> Value {
+    let mut encounter = json!({
+        "resourceType": "Encounter",
+        "id": format!("encounter-{}", id),
+        "status": "finished",
+        "class": encounter_class(date_of_admission, date_of_release),
+        "subject": { "reference": patient_ref },
+        "serviceProvider": { "display": hospital_name },
+    });
+    if let Some(period) = period(date_of_admission, date_of_release) {
+        encounter["period"] = period;
+    }
+    if let Some(author_id) = author_id {
+        encounter["serviceProvider"]["identifier"] = json!({ "system": ACCOUNT_SYSTEM, "value": author_id });
+    }
+    encounter
+}
+
+fn condition(record: &MedRecord, patient_ref: &str, encounter_ref: &str) -> Value {
+    json!({
+        "resourceType": "Condition",
+        "id": format!("condition-{}", record.id),
+        "code": { "text": record.diagnosis },
+        "subject": { "reference": patient_ref },
+        "encounter": { "reference": encounter_ref },
+    })
+}
+
+fn medication_administration(record: &MedRecord, patient_ref: &str, encounter_ref: &str) -> Value {
+    let mut administration = json!({
+        "resourceType": "MedicationAdministration",
+        "id": format!("medication-administration-{}", record.id),
+        "status": "completed",
+        "medicationCodeableConcept": { "text": record.medicine_administered },
+        "subject": { "reference": patient_ref },
+        "context": { "reference": encounter_ref },
+    });
+    if let Some(period) = period(&record.date_of_admission, &record.date_of_release) {
+        administration["effectivePeriod"] = period;
+    }
+    administration
+}
+
+fn allergy_intolerance(record: &MedRecord, patient_ref: &str, encounter_ref: &str) -> Value {
+    json!({
+        "resourceType": "AllergyIntolerance",
+        "id": format!("allergy-intolerance-{}", record.id),
+        "code": { "text": record.allergies_recorded },
+        "patient": { "reference": patient_ref },
+        "encounter": { "reference": encounter_ref },
+    })
+}
+
+// What a resource pointing at an encounter contributes to its record
+enum Field {
+    Diagnosis(Option<String>),
+    Medicine(Option<String>),
+    Allergy(Option<String>),
+}
+
+// A record being assembled from an Encounter and the resources that reference it
+struct Draft {
+    entry: usize,
+    reference: String,
+    hospital_name: Option<String>,
+    date_of_admission: Option<String>,
+    date_of_release: Option<String>,
+    diagnoses: Vec<String>,
+    medicines: Vec<String>,
+    allergies: Vec<String>,
+}
+
+impl Draft {
+    fn from_encounter(entry: usize, encounter: &Value) -> Self {
+        let text = |value: &Value| value.as_str().map(String::from);
+        Self {
+            entry,
+            reference: format!("Encounter/{}", encounter["id"].as_str().unwrap_or_default()),
+            hospital_name: text(&encounter["serviceProvider"]["display"]),
+            date_of_admission: encounter["period"]["start"].as_str().and_then(record_date),
+            date_of_release: encounter["period"]["end"].as_str().and_then(record_date),
+            diagnoses: vec![],
+            medicines: vec![],
+            allergies: vec![],
+        }
+    }
+
+    fn into_record(self) -> Result<NewRecord, (usize, String)> {
+        let missing = |what: &str| (self.entry, format!("Encounter has no {}", what));
+        if self.diagnoses.is_empty() {
+            return Err(missing("Condition"));
+        }
+        Ok(NewRecord {
+            hospital_name: self.hospital_name.clone().ok_or_else(|| missing("serviceProvider display"))?,
+            date_of_admission: self.date_of_admission.clone().ok_or_else(|| missing("readable period start"))?,
+            date_of_release: self.date_of_release.clone().ok_or_else(|| missing("readable period end"))?,
+            diagnosis: self.diagnoses.join("; "),
+            medicine_administered: none_if_empty(&self.medicines),
+            allergies_recorded: none_if_empty(&self.allergies),
+            price: 0,
+        })
+    }
+}
+
+fn none_if_empty(values: &[String]) -> String {
+    if values.is_empty() {
+        String::from("None")
+    } else {
+        values.join("; ")
+    }
+}
+
+// The human readable text of a CodeableConcept
+fn concept_text(concept: &Value) -> Option<String> {
+    concept["text"]
+        .as_str()
+        .or_else(|| concept["coding"][0]["display"].as_str())
+        .map(String::from)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::serde_json;
+
+    const GOLDEN_BUNDLE: &str = include_str!("../tests/golden/fhir_bundle.json");
+
+    fn records() -> Vec<MedRecord> {
+        vec![
+            MedRecord::new(0, String::from("Diarrhea"), String::from("CGH"), String::from("Flagyl"),
+                String::from("21/04/2022"), String::from("22/04/2022"), String::from("Protein Allergies"), 1000.0, None),
+            MedRecord::new(3, String::from("Malaria"), String::from("KNH"), String::from("Coartem"),
+                String::from("2022-05-01"), String::from("21 May 2022"), String::from("None"), 2500.0,
+                Some("knh.near".parse().unwrap())),
+        ]
+    }
+
+    #[test]
+    fn bundle_matches_golden_file() {
+        let bundle = bundle(&"bob_near.testnet".parse().unwrap(), &records());
+        let rendered = serde_json::to_string_pretty(&bundle).unwrap() + "\n";
+
+        // Regenerate with UPDATE_GOLDEN=1 cargo test after an intended change
+        if std::env::var("UPDATE_GOLDEN").is_ok() {
+            std::fs::write(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/fhir_bundle.json"), &rendered).unwrap();
+            return;
+        }
+        assert_eq!(GOLDEN_BUNDLE, rendered);
+    }
+
+    #[test]
+    fn import_reads_back_exported_bundle() {
+        let golden: Value = serde_json::from_str(GOLDEN_BUNDLE).unwrap();
+        let import = import(&golden).unwrap();
+
+        assert_eq!(vec![NewRecord {
+            diagnosis: String::from("Diarrhea"),
+            hospital_name: String::from("CGH"),
+            medicine_administered: String::from("Flagyl"),
+            date_of_admission: String::from("21/04/2022"),
+            date_of_release: String::from("22/04/2022"),
+            allergies_recorded: String::from("Protein Allergies"),
+            price: 0,
+        }], import.records);
+        // The second record's release date couldn't be exported
+        assert_eq!(vec![ImportError { entry: 5, message: String::from("Encounter has no readable period end") }],
+            import.errors);
+    }
+
+    #[test]
+    fn import_reports_unusable_entries() {
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [
+                { "resource": { "resourceType": "Encounter", "id": "a", "serviceProvider": { "display": "KNH" },
+                    "period": { "start": "2022-06-01T08:00:00Z", "end": "2022-06-02" } } },
+                { "resource": { "resourceType": "Condition", "code": { "coding": [{ "display": "Typhoid" }] },
+                    "encounter": { "reference": "Encounter/a" } } },
+                { "resource": { "resourceType": "Condition", "code": { "text": "Dehydration" },
+                    "encounter": { "reference": "Encounter/b" } } },
+                { "resource": { "resourceType": "Observation" } },
+                { "resource": { "resourceType": "Encounter", "id": "c", "serviceProvider": { "display": "KNH" },
+                    "period": { "start": "2022-06-01", "end": "2022-06-02" } } },
+            ],
+        });
+        let import = import(&bundle).unwrap();
+
+        assert_eq!(1, import.records.len());
+        assert_eq!("Typhoid", import.records[0].diagnosis);
+        assert_eq!("01/06/2022", import.records[0].date_of_admission);
+        assert_eq!("None", import.records[0].medicine_administered);
+        let failed: Vec<usize> = import.errors.iter().map(|error| error.entry).collect();
+        assert_eq!(vec![2, 3, 4], failed);
+        assert!(super::import(&json!({ "resourceType": "Patient" })).is_err());
+    }
+
+    #[test]
+    fn unreadable_dates_leave_out_the_period() {
+        let record = MedRecord::new(7, String::from("Flu"), String::from("CGH"), String::from("Panadol"),
+            String::from("last week"), String::from("today"), String::from("None"), 0.0, None);
+        let bundle = bundle(&"bob.near".parse().unwrap(), &[record]);
+
+        let encounter = &bundle["entry"][1]["resource"];
+        assert!(encounter.get("period").is_none());
+        assert_eq!("UNK", encounter["class"]["code"]);
+        assert!(bundle["entry"][3]["resource"].get("effectivePeriod").is_none());
+    }
+
+    #[test]
+    fn summary_bundle_has_only_the_visits() {
+        let summaries: Vec<RecordSummary> = records().into_iter().map(RecordSummary::from).collect();
+        let bundle = summary_bundle(&"bob.near".parse().unwrap(), &summaries);
+
+        let types: Vec<&str> = bundle["entry"].as_array().unwrap().iter()
+            .map(|entry| entry["resource"]["resourceType"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["Patient", "Encounter", "Encounter"], types);
+        assert_eq!("IMP", bundle["entry"][1]["resource"]["class"]["code"]);
+    }
+
+    #[test]
+    fn dates_convert_or_are_left_out() {
+        assert_eq!(Some(String::from("2022-04-21")), fhir_date("21/04/2022"));
+        assert_eq!(Some(String::from("2022-05-01")), fhir_date("2022-5-1"));
+        assert_eq!(None, fhir_date("21 April 2022"));
+        assert_eq!(None, fhir_date("31/13/2022"));
+    }
+}
@@ -0,0 +1,42 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::json_types::U128;
+use near_sdk::AccountId;
+
+// A NEP-141 token the contract accepts payments in
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AcceptedToken {
+    // Token units charged per byte of storage
+    pub storage_byte_price: U128,
+}
+
+// Storage bytes an account has bought with tokens
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageCredit {
+    // Bytes not yet taken up by the account's data
+    pub available: u64,
+    // Bytes currently covered by the credit, returned to it when released
+    pub used: u64,
+    // What the credit was bought with, None until the first purchase
+    pub purchase: Option<CreditPurchase>,
+}
+
+// The token a storage credit was bought with and its price per byte at the time,
+// which is what unused bytes are paid back at
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CreditPurchase {
+    pub token_id: AccountId,
+    pub byte_price: U128,
+}
+
+// What a token transfer to the contract pays for, passed as the `msg` of `ft_transfer_call`
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPayment {
+    PayBill { record_id: u64 },
+    BuyStorage,
+}
@@ -0,0 +1,43 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::AccountId;
+
+// An insurance company allowed to receive claims
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Insurer {
+    pub name: String,
+}
+
+// A patient's cover with a registered insurer
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Policy {
+    pub insurer_id: AccountId,
+    pub policy_number: String,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ClaimStatus {
+    Pending,
+    Approved,
+    PartiallyApproved,
+    Rejected,
+}
+
+// A hospital's request to an insurer to cover the price of a patient's record
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Claim {
+    pub id: u64,
+    pub patient_id: AccountId,
+    pub hospital_id: AccountId,
+    pub insurer_id: AccountId,
+    pub policy_number: String,
+    pub record_id: u64,
+    pub amount: f64,
+    pub approved_amount: f64,
+    pub status: ClaimStatus,
+    pub reason: Option<String>,
+}
@@ -0,0 +1,27 @@
+//! The types the MedBlock contract takes and returns, shared by the contract,
+//! its clients and anything else that reads or writes its JSON.
+//!
+//! Modules mirror the contract's, so `med_block_types::billing::Bill` is what
+//! the contract's billing methods return. `merkle` and `fhir` are pure helpers
+//! for checking disclosure proofs and converting records off-chain.
+
+pub mod access;
+pub mod aggregate;
+pub mod appointment;
+pub mod billing;
+pub mod certificate;
+pub mod fhir;
+pub mod fungible_token;
+pub mod insurance;
+pub mod med_record;
+pub mod merkle;
+pub mod provider;
+pub mod recovery;
+pub mod referral;
+pub mod research;
+pub mod stats;
+pub mod upgrade;
+pub mod validation;
+
+pub use near_sdk::AccountId;
+pub use near_sdk::json_types::{Base58CryptoHash, Base64VecU8, U128, U64};
@@ -0,0 +1,69 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::{AccountId, CryptoHash};
+
+
+//This is a declaration of the medical record object i.e MedRecord
+#[derive(Debug, BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MedRecord {
+ pub id: u64,
+ pub diagnosis: String,
+ pub hospital_name: String,
+ pub medicine_administered: String,
+ pub date_of_admission: String,
+ pub date_of_release: String,
+ pub allergies_recorded: String,
+ pub price: f64,
+ pub payment_status: PaymentStatus,
+ // Hospital account that wrote the record, None when the patient added it
+ pub author_id: Option<AccountId>,
+ // Random value mixed into every field's commitment, see `merkle`. Revealing it
+ // lets anyone check guesses at the record's fields against the patient's root.
+ pub salt: CryptoHash,
+}
+
+// Where the bill for a record stands
+#[derive(Debug, BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PaymentStatus {
+ Unpaid,
+ Escrowed,
+ Paid,
+ Refunded,
+ Disputed,
+}
+
+impl MedRecord {
+ #[allow(clippy::too_many_arguments)]
+ pub fn new(id: u64, diagnosis: String, hospital_name: String, medicine_administered: String,
+  date_of_admission: String, date_of_release: String,
+  allergies_recorded: String, price: f64, author_id: Option<AccountId>) -> Self {
+      Self {
+        id,
+        diagnosis,
+        hospital_name,
+        medicine_administered,
+        date_of_admission,
+        date_of_release,
+        allergies_recorded,
+        price,
+        payment_status: PaymentStatus::Unpaid,
+        author_id,
+        salt: [0; 32],
+      }
+ }
+}
+
+// The fields a caller supplies for a new record, used to add several at once
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NewRecord {
+ pub diagnosis: String,
+ pub hospital_name: String,
+ pub medicine_administered: String,
+ pub date_of_admission: String,
+ pub date_of_release: String,
+ pub allergies_recorded: String,
+ pub price: u64,
+}
@@ -0,0 +1,277 @@
+//! Merkle commitments over a patient's records for selective disclosure.
+//!
+//! Every record is hashed as a small tree of its fields, and the patient's root
+//! is a tree over those record hashes in record order. A verifier holding only
+//! the root published by the contract can then check that one record, or one
+//! field of one record, belongs to the patient without seeing the others.
+//!
+//! Each field is hashed with its own salt, derived from a random per-record salt
+//! the contract keeps with the record and returns only to the patient and the
+//! accounts they share it with. A field proof carries the salt for the field it
+//! discloses, so a verifier can't test guesses at the other fields' values
+//! against the sibling hashes it is given. The salt is stored in contract state
+//! next to the record itself, so it protects proofs handed to third parties,
+//! not data anyone can already read from the chain.
+//!
+//! The functions here are pure so they can be used off-chain to build and
+//! check proofs against the root returned by `get_records_root`.
+
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::CryptoHash;
+use sha2::{Digest, Sha256};
+
+use crate::med_record::MedRecord;
+
+// Domain separation so a leaf can never be passed off as an inner node
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Fields committed for each record, in tree order. Payment status is left out
+/// because it changes after the visit and is not part of the clinical record.
+pub const FIELDS: [&str; 9] = [
+    "id",
+    "diagnosis",
+    "hospital_name",
+    "medicine_administered",
+    "date_of_admission",
+    "date_of_release",
+    "allergies_recorded",
+    "price",
+    "author_id",
+];
+
+/// One step up the tree: the sibling hash and which side it sits on.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ProofStep {
+    pub sibling: CryptoHash,
+    pub sibling_on_left: bool,
+}
+
+/// Proof that a whole record is in a patient's record set.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RecordProof {
+    pub path: Vec<ProofStep>,
+}
+
+/// Proof that one field of one record is in a patient's record set.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FieldProof {
+    pub field: String,
+    pub value: String,
+    // The disclosed field's salt, which says nothing about the record's other fields
+    pub salt: CryptoHash,
+    pub field_path: Vec<ProofStep>,
+    pub record_path: Vec<ProofStep>,
+}
+
+/// The committed value of a field, None if the field name is unknown.
+pub fn field_value(record: &MedRecord, field: &str) -> Option<String> {
+    let value = match field {
+        "id" => record.id.to_string(),
+        "diagnosis" => record.diagnosis.clone(),
+        "hospital_name" => record.hospital_name.clone(),
+        "medicine_administered" => record.medicine_administered.clone(),
+        "date_of_admission" => record.date_of_admission.clone(),
+        "date_of_release" => record.date_of_release.clone(),
+        "allergies_recorded" => record.allergies_recorded.clone(),
+        "price" => record.price.to_string(),
+        "author_id" => record.author_id.as_ref().map(|id| id.to_string()).unwrap_or_default(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Salt for one field of a record, derived from the record's salt.
+pub fn field_salt(record_salt: &CryptoHash, field: &str) -> CryptoHash {
+    let mut hasher = Sha256::new();
+    hasher.update(record_salt);
+    hasher.update(field.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Hash of a single field name and value with the field's salt.
+pub fn field_leaf(field: &str, salt: &CryptoHash, value: &str) -> CryptoHash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(salt);
+    hasher.update((field.len() as u32).to_le_bytes());
+    hasher.update(field.as_bytes());
+    hasher.update(value.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Hash of a whole record, the root of its field tree.
+pub fn record_leaf(record: &MedRecord) -> CryptoHash {
+    root(&field_leaves(record))
+}
+
+/// Root over a patient's records, all zeroes when there are none.
+pub fn records_root(records: &[MedRecord]) -> CryptoHash {
+    let leaves: Vec<CryptoHash> = records.iter().map(record_leaf).collect();
+    root(&leaves)
+}
+
+/// Root over record hashes already computed with `record_leaf`, so a change to
+/// one record only needs that record hashed again.
+pub fn root_of_leaves(leaves: &[CryptoHash]) -> CryptoHash {
+    root(leaves)
+}
+
+/// Build a proof for the record with the given id.
+pub fn prove_record(records: &[MedRecord], record_id: u64) -> Option<RecordProof> {
+    let index = records.iter().position(|record| record.id == record_id)?;
+    let leaves: Vec<CryptoHash> = records.iter().map(record_leaf).collect();
+    Some(RecordProof { path: path(&leaves, index) })
+}
+
+/// Build a proof for one field of the record with the given id.
+pub fn prove_field(records: &[MedRecord], record_id: u64, field: &str) -> Option<FieldProof> {
+    let record = records.iter().find(|record| record.id == record_id)?;
+    let value = field_value(record, field)?;
+    let field_index = FIELDS.iter().position(|name| *name == field)?;
+
+    Some(FieldProof {
+        field: field.to_string(),
+        value,
+        salt: field_salt(&record.salt, field),
+        field_path: path(&field_leaves(record), field_index),
+        record_path: prove_record(records, record_id)?.path,
+    })
+}
+
+/// Check a record against a patient's root.
+pub fn verify_record(root: &CryptoHash, record: &MedRecord, proof: &RecordProof) -> bool {
+    fold(record_leaf(record), &proof.path) == *root
+}
+
+/// Check a single disclosed field against a patient's root.
+pub fn verify_field(root: &CryptoHash, proof: &FieldProof) -> bool {
+    let record_hash = fold(field_leaf(&proof.field, &proof.salt, &proof.value), &proof.field_path);
+    fold(record_hash, &proof.record_path) == *root
+}
+
+fn field_leaves(record: &MedRecord) -> Vec<CryptoHash> {
+    FIELDS
+        .iter()
+        .map(|field| field_leaf(field, &field_salt(&record.salt, field), &field_value(record, field).unwrap_or_default()))
+        .collect()
+}
+
+fn node(left: &CryptoHash, right: &CryptoHash) -> CryptoHash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Pair up a level, carrying an odd last node up unchanged
+fn parent_level(level: &[CryptoHash]) -> Vec<CryptoHash> {
+    level
+        .chunks(2)
+        .map(|pair| if pair.len() == 2 { node(&pair[0], &pair[1]) } else { pair[0] })
+        .collect()
+}
+
+fn root(leaves: &[CryptoHash]) -> CryptoHash {
+    if leaves.is_empty() {
+        return [0; 32];
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = parent_level(&level);
+    }
+    level[0]
+}
+
+fn path(leaves: &[CryptoHash], mut index: usize) -> Vec<ProofStep> {
+    let mut steps = vec![];
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            steps.push(ProofStep { sibling: level[sibling], sibling_on_left: sibling < index });
+        }
+        level = parent_level(&level);
+        index /= 2;
+    }
+    steps
+}
+
+fn fold(leaf: CryptoHash, path: &[ProofStep]) -> CryptoHash {
+    path.iter().fold(leaf, |hash, step| {
+        if step.sibling_on_left { node(&step.sibling, &hash) } else { node(&hash, &step.sibling) }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(count: u64) -> Vec<MedRecord> {
+        (0..count)
+            .map(|id| {
+                let mut record = MedRecord::new(id, format!("Diagnosis {}", id), String::from("CGH"), String::from("Flagyl"),
+                    String::from("21/04/2022"), String::from("22/04/2022"), String::from("None"), 1000.0, None);
+                record.salt = [id as u8 + 1; 32];
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_record_proves_at_every_size() {
+        for count in 1..8 {
+            let records = records(count);
+            let root = records_root(&records);
+            for record in &records {
+                let proof = prove_record(&records, record.id).unwrap();
+                assert!(verify_record(&root, record, &proof));
+            }
+        }
+    }
+
+    #[test]
+    fn field_proof_discloses_only_that_field() {
+        let records = records(5);
+        let root = records_root(&records);
+
+        let proof = prove_field(&records, 3, "diagnosis").unwrap();
+        assert_eq!("Diagnosis 3", proof.value);
+        assert!(verify_field(&root, &proof));
+
+        let mut forged = proof;
+        forged.value = String::from("Diagnosis 4");
+        assert!(!verify_field(&root, &forged));
+    }
+
+    #[test]
+    fn hidden_fields_need_their_salt() {
+        let records = records(1);
+        let root = records_root(&records);
+        let proof = prove_field(&records, 0, "allergies_recorded").unwrap();
+
+        // The price sits next to the allergies, and knowing its value is only enough
+        // to rebuild its hash with the record's salt, which the proof doesn't carry
+        let price = &proof.field_path[0].sibling;
+        assert_ne!(*price, field_leaf("price", &proof.salt, "1000"));
+        assert_ne!(*price, field_leaf("price", &[0; 32], "1000"));
+        assert_eq!(*price, field_leaf("price", &field_salt(&records[0].salt, "price"), "1000"));
+        assert!(verify_field(&root, &proof));
+    }
+
+    #[test]
+    fn tampered_record_fails() {
+        let mut records = records(3);
+        let root = records_root(&records);
+        let proof = prove_record(&records, 1).unwrap();
+
+        records[1].hospital_name = String::from("KNH");
+        assert!(!verify_record(&root, &records[1], &proof));
+        assert!(prove_field(&records, 1, "unknown").is_none());
+    }
+}
@@ -0,0 +1,11 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+
+// A hospital or clinic whose account the owner has checked
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Provider {
+    pub name: String,
+    // False once the owner withdraws verification, kept so past actions can be traced
+    pub verified: bool,
+}
@@ -0,0 +1,25 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::json_types::U64;
+use near_sdk::AccountId;
+
+// Accounts a patient trusts to move their records if they lose their keys
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RecoveryConfig {
+    pub guardians: Vec<AccountId>,
+    // Guardians that must agree before a recovery can go ahead
+    pub threshold: u32,
+    // Milliseconds the patient has to veto once the threshold is reached
+    pub delay: U64,
+}
+
+// A recovery the guardians have started
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RecoveryRequest {
+    pub new_account_id: AccountId,
+    pub approvals: Vec<AccountId>,
+    // Epoch milliseconds after which the recovery can be finalized, set once the threshold is met
+    pub ready_at: Option<U64>,
+}
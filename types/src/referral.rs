@@ -0,0 +1,24 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::AccountId;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ReferralStatus {
+    Pending,
+    Accepted,
+    Declined,
+}
+
+// One provider sending a patient on to another along with the records that matter
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Referral {
+    pub id: u64,
+    pub patient_id: AccountId,
+    pub from_provider_id: AccountId,
+    pub to_provider_id: AccountId,
+    pub reason: String,
+    pub record_ids: Vec<u64>,
+    pub status: ReferralStatus,
+}
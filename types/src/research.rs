@@ -0,0 +1,108 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::AccountId;
+
+use crate::fhir;
+use crate::med_record::MedRecord;
+
+// A university or lab the owner has approved to run research programs
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Institution {
+    pub name: String,
+    // False once the owner withdraws approval, kept so past programs can be traced
+    pub approved: bool,
+}
+
+// A study patients can contribute their records to
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Program {
+    pub id: u64,
+    pub institution_id: AccountId,
+    pub name: String,
+    // What the data will be used for, patients consent to exactly this
+    pub purpose: String,
+    pub open: bool,
+    // What an insurer pays for access to the program's data, None until the institution sets it
+    pub funding_terms: Option<FundingTerms>,
+    // Insurers that have paid for access to the program's data
+    pub funders: Vec<Funder>,
+    // How many payments the program has been funded with
+    pub funding_rounds: u32,
+}
+
+// The institution's price for access to a program's data
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FundingTerms {
+    // Smallest payment that buys access, in yoctoNEAR
+    pub min_amount: U128,
+    // Milliseconds of access each payment of at least the minimum buys
+    pub access_period: U64,
+}
+
+// An insurer that has paid for access to a program's data
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Funder {
+    pub account_id: AccountId,
+    // Epoch milliseconds after which the insurer can no longer export the data
+    pub access_until: U64,
+}
+
+// A patient's agreement to share their records with a program
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Consent {
+    pub patient_id: AccountId,
+    pub purpose: String,
+    // Epoch milliseconds after which the consent no longer applies
+    pub expires_at: U64,
+    // The patient's random pseudonym in exports, never returned next to the patient id
+    #[serde(skip)]
+    pub participant: String,
+    // Compensation counted for the patient under this consent and not yet claimed, in yoctoNEAR
+    pub earned: U128,
+    // How many of the program's funding rounds are counted in `earned`
+    pub rounds_counted: u32,
+}
+
+// A record with anything that could identify the patient taken out. Dates are cut
+// down to the year and a length of stay, and the hospital and author are dropped.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DeidentifiedRecord {
+    // Random per consent, so a patient's records link up within a program but the
+    // pseudonym can't be worked out from their account id
+    pub participant: String,
+    pub diagnosis: String,
+    pub medicine_administered: String,
+    pub allergies_recorded: String,
+    pub admission_year: Option<u32>,
+    pub length_of_stay_days: Option<u64>,
+}
+
+impl DeidentifiedRecord {
+    pub fn new(participant: String, record: MedRecord) -> Self {
+        let admission_date = fhir::fhir_date(&record.date_of_admission);
+        let admission = admission_date.as_deref().and_then(day_number);
+        let release = fhir::fhir_date(&record.date_of_release).as_deref().and_then(day_number);
+        Self {
+            participant,
+            diagnosis: record.diagnosis,
+            medicine_administered: record.medicine_administered,
+            allergies_recorded: record.allergies_recorded,
+            admission_year: admission_date.and_then(|date| date[..4].parse().ok()),
+            length_of_stay_days: admission.zip(release).and_then(|(start, end)| end.checked_sub(start)),
+        }
+    }
+}
+
+// Days since 0000-03-01 for a yyyy-mm-dd date, only ever compared with each other
+fn day_number(date: &str) -> Option<u64> {
+    let (year, month, day): (u64, u64, u64) = (date[..4].parse().ok()?, date[5..7].parse().ok()?, date[8..].parse().ok()?);
+    let (year, month) = if month <= 2 { (year.checked_sub(1)?, month + 9) } else { (year, month - 3) };
+    Some(365 * year + year / 4 - year / 100 + year / 400 + (153 * month + 2) / 5 + day - 1)
+}
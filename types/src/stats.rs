@@ -0,0 +1,34 @@
+use std::collections::BTreeMap;
+
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+
+// Records and amount billed at one hospital
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HospitalTotal {
+    pub records: u64,
+    pub billed: u64,
+}
+
+// One hospital's totals as the stats views return them. The amount billed is only
+// shown to the patient and the accounts they granted, the public view leaves it out.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HospitalTotalView {
+    pub records: u64,
+    pub billed: Option<u64>,
+}
+
+// What the record stats view returns
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RecordStatsView {
+    pub count: u64,
+    // Earliest and latest admission dates as yyyy-mm-dd, leaving out dates that can't be read
+    pub first_admission: Option<String>,
+    pub last_admission: Option<String>,
+    // Bytes of contract storage the patient's records take up
+    pub storage_used: u64,
+    pub hospitals: BTreeMap<String, HospitalTotalView>,
+}
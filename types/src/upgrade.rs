@@ -0,0 +1,28 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::json_types::{Base58CryptoHash, U128, U64};
+use near_sdk::AccountId;
+
+// Accounts that must agree on new contract code once the owner hands upgrades over to them
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UpgradeConfig {
+    pub approvers: Vec<AccountId>,
+    // Approvers that must agree before an upgrade can go ahead
+    pub threshold: u32,
+    // Milliseconds between the threshold being reached and the code being deployed
+    pub delay: U64,
+}
+
+// Contract code an approver has staged
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UpgradeProposal {
+    pub code_hash: Base58CryptoHash,
+    pub proposer_id: AccountId,
+    pub approvals: Vec<AccountId>,
+    // Epoch milliseconds after which the code can be deployed, set once the threshold is met
+    pub ready_at: Option<U64>,
+    // What the proposer paid to store the code, returned when it is deployed or withdrawn
+    pub storage_cost: U128,
+}
@@ -0,0 +1,188 @@
+use near_sdk::borsh::{self, BorshSerialize, BorshDeserialize};
+use near_sdk::serde::{Serialize, Deserialize};
+use near_sdk::serde_json;
+
+use crate::fhir;
+use crate::med_record::NewRecord;
+
+/// Starts every panic message for a rejected record, followed by the
+/// [`ValidationError`] as JSON.
+pub const INVALID_RECORD: &str = "Invalid record! ";
+
+/// Fields that must have something in them besides whitespace.
+pub const REQUIRED_FIELDS: [&str; 2] = ["diagnosis", "hospital_name"];
+
+/// Fields holding a code rather than free text. The hospital name is matched
+/// against verified providers and counted in the visit statistics, so it is
+/// limited to [`is_code_character`].
+pub const CODE_FIELDS: [&str; 1] = ["hospital_name"];
+
+/// Characters a code field may hold: ASCII letters and digits, spaces and
+/// the punctuation found in institution names, `-.,&'()/`.
+pub fn is_code_character(c: char) -> bool {
+    c.is_ascii_alphanumeric() || " -.,&'()/".contains(c)
+}
+
+// How much a single call and a patient's whole history may store, set by the
+// owner. Lengths are in bytes.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RecordLimits {
+    pub max_diagnosis_len: u32,
+    pub max_hospital_name_len: u32,
+    pub max_medicine_len: u32,
+    pub max_allergies_len: u32,
+    pub max_records_per_call: u32,
+    // Every call loads a patient's records in full, so this keeps them readable
+    pub max_records_per_patient: u32,
+}
+
+impl Default for RecordLimits {
+    fn default() -> Self {
+        Self {
+            max_diagnosis_len: 256,
+            max_hospital_name_len: 128,
+            max_medicine_len: 512,
+            max_allergies_len: 512,
+            max_records_per_call: 50,
+            max_records_per_patient: 500,
+        }
+    }
+}
+
+// Why a record was turned away. `record` is its position in the call.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde", tag = "error", rename_all = "snake_case")]
+pub enum ValidationError {
+    TooManyRecords { max: u32, given: u32 },
+    // The records would take the patient past the most they may keep
+    HistoryFull { max: u32, stored: u32, given: u32 },
+    FieldTooLong { record: u32, field: String, max: u32, length: u32 },
+    EmptyField { record: u32, field: String },
+    // Text fields can't hold control characters, and code fields only code characters
+    InvalidCharacters { record: u32, field: String },
+    // Dates must be dd/mm/yyyy or yyyy-mm-dd
+    InvalidDate { record: u32, field: String },
+}
+
+impl ValidationError {
+    /// The message the contract panics with.
+    pub fn panic_message(&self) -> String {
+        format!("{}{}", INVALID_RECORD, serde_json::to_string(self).unwrap())
+    }
+
+    /// Read the error back out of a failed call's message, which may wrap the panic message.
+    pub fn from_panic(message: &str) -> Option<Self> {
+        let start = message.find(INVALID_RECORD)? + INVALID_RECORD.len();
+        serde_json::Deserializer::from_str(&message[start..]).into_iter().next()?.ok()
+    }
+}
+
+/// Check records against the limits before they are added to a patient who
+/// already has `stored` records.
+pub fn validate(records: &[NewRecord], stored: u64, limits: &RecordLimits) -> Result<(), ValidationError> {
+    if records.len() > limits.max_records_per_call as usize {
+        return Err(ValidationError::TooManyRecords { max: limits.max_records_per_call, given: records.len() as u32 });
+    }
+    if stored + records.len() as u64 > limits.max_records_per_patient as u64 {
+        return Err(ValidationError::HistoryFull {
+            max: limits.max_records_per_patient,
+            stored: stored.min(u32::MAX as u64) as u32,
+            given: records.len() as u32,
+        });
+    }
+
+    for (index, record) in records.iter().enumerate() {
+        let index = index as u32;
+        let texts = [
+            ("diagnosis", &record.diagnosis, limits.max_diagnosis_len),
+            ("hospital_name", &record.hospital_name, limits.max_hospital_name_len),
+            ("medicine_administered", &record.medicine_administered, limits.max_medicine_len),
+            ("allergies_recorded", &record.allergies_recorded, limits.max_allergies_len),
+        ];
+        for (field, value, max) in texts {
+            if value.len() > max as usize {
+                return Err(ValidationError::FieldTooLong { record: index, field: field.to_string(), max, length: value.len() as u32 });
+            }
+            if REQUIRED_FIELDS.contains(&field) && value.trim().is_empty() {
+                return Err(ValidationError::EmptyField { record: index, field: field.to_string() });
+            }
+            let allowed = |c: char| if CODE_FIELDS.contains(&field) { is_code_character(c) } else { !c.is_control() };
+            if !value.chars().all(allowed) {
+                return Err(ValidationError::InvalidCharacters { record: index, field: field.to_string() });
+            }
+        }
+
+        for (field, value) in [("date_of_admission", &record.date_of_admission), ("date_of_release", &record.date_of_release)] {
+            if fhir::fhir_date(value).is_none() {
+                return Err(ValidationError::InvalidDate { record: index, field: field.to_string() });
+            }
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> NewRecord {
+        NewRecord {
+            diagnosis: String::from("Malaria"),
+            hospital_name: String::from("CGH"),
+            medicine_administered: String::from("Coartem"),
+            date_of_admission: String::from("01/05/2022"),
+            date_of_release: String::from("2022-05-03"),
+            allergies_recorded: String::from("None"),
+            price: 1000,
+        }
+    }
+
+    #[test]
+    fn each_rule_names_the_record_and_field() {
+        let limits = RecordLimits::default();
+        assert_eq!(Ok(()), validate(&[record()], 0, &limits));
+
+        let mut long = record();
+        long.hospital_name = "H".repeat(129);
+        let mut control = record();
+        control.allergies_recorded = String::from("None\u{0}");
+        let mut date = record();
+        date.date_of_release = String::from("yesterday");
+
+        assert_eq!(
+            Err(ValidationError::FieldTooLong { record: 1, field: String::from("hospital_name"), max: 128, length: 129 }),
+            validate(&[record(), long], 0, &limits)
+        );
+        assert_eq!(Err(ValidationError::InvalidCharacters { record: 0, field: String::from("allergies_recorded") }), validate(&[control], 0, &limits));
+        assert_eq!(Err(ValidationError::InvalidDate { record: 0, field: String::from("date_of_release") }), validate(&[date], 0, &limits));
+        assert_eq!(Err(ValidationError::TooManyRecords { max: 50, given: 51 }), validate(&vec![record(); 51], 0, &limits));
+        assert_eq!(Err(ValidationError::HistoryFull { max: 500, stored: 499, given: 2 }), validate(&[record(), record()], 499, &limits));
+    }
+
+    #[test]
+    fn required_and_code_fields() {
+        let limits = RecordLimits::default();
+        let mut blank = record();
+        blank.diagnosis = String::from("  ");
+        let mut symbols = record();
+        symbols.hospital_name = String::from("CGH <script>");
+        let mut named = record();
+        named.hospital_name = String::from("St. Mary's (Nairobi) - A&E");
+        let mut free_text = record();
+        free_text.medicine_administered = String::from("Coartem <twice daily>");
+
+        assert_eq!(Err(ValidationError::EmptyField { record: 0, field: String::from("diagnosis") }), validate(&[blank], 0, &limits));
+        assert_eq!(Err(ValidationError::InvalidCharacters { record: 0, field: String::from("hospital_name") }), validate(&[symbols], 0, &limits));
+        assert_eq!(Ok(()), validate(&[named, free_text], 0, &limits));
+    }
+
+    #[test]
+    fn error_survives_the_panic_message() {
+        let error = ValidationError::InvalidDate { record: 2, field: String::from("date_of_admission") };
+        let message = format!("Smart contract panicked: {}", error.panic_message());
+        assert_eq!(Some(error), ValidationError::from_panic(&message));
+        assert_eq!(None, ValidationError::from_panic("Patient not found!"));
+    }
+}